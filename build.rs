@@ -0,0 +1,87 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! With the `codegen` feature, generate wrappers for the manager's
+//! methods, properties and signals, and for the methods and properties
+//! of unit and job objects, from introspection/, into
+//! $OUT_DIR/systemd1.rs, which src/lib.rs includes.
+
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[path = "src/introspect.rs"]
+#[allow(dead_code)]
+mod introspect;
+
+/// The interfaces of unit and job objects, as (interface, proxy type,
+/// source of the proxy type, prefix of the generated names).
+static OBJECTS: &[(&str, &str, &str, &str)] = &[
+    ("org.freedesktop.systemd1.Unit", "unit::Unit", "src/unit.rs", ""),
+    ("org.freedesktop.systemd1.Service", "unit::Unit", "src/unit.rs", "service_"),
+    ("org.freedesktop.systemd1.Socket", "unit::Unit", "src/unit.rs", "socket_"),
+    ("org.freedesktop.systemd1.Timer", "unit::Unit", "src/unit.rs", "timer_"),
+    ("org.freedesktop.systemd1.Job", "job::JobObject", "src/job.rs", "")];
+
+fn read(path: &str) -> String {
+    println!("cargo:rerun-if-changed={}", path);
+    let mut s = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut s))
+        .unwrap_or_else(|e| panic!("reading {}: {}", path, e));
+    s
+}
+
+/// The methods wrapped by hand with `systemd_dbus!` in src/lib.rs.
+fn wrapped(lib: &str) -> Vec<String> {
+    lib.split("systemd_dbus!(")
+        .skip(1)
+        .filter_map(|s| s.split('"').nth(1))
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// The methods defined by hand in `source`.
+fn defined(source: &str) -> Vec<String> {
+    source.split("pub fn ")
+        .skip(1)
+        .filter_map(|s| s.split('(').next())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Parse introspection/`name`.xml, and return `name`'s interface.
+fn interface(name: &str) -> introspect::Interface {
+    let path = format!("introspection/{}.xml", name);
+    let node = introspect::parse(&read(&path))
+        .unwrap_or_else(|e| panic!("parsing {}: {}", path, e));
+    node.interface(name).cloned()
+        .unwrap_or_else(|| panic!("{} has no {} interface", path, name))
+}
+
+fn main() {
+    if env::var("CARGO_FEATURE_CODEGEN").is_err() {
+        return
+    }
+    let manager = interface("org.freedesktop.systemd1.Manager");
+    let mut code = introspect::generate(&manager, "bus",
+                                        &wrapped(&read("src/lib.rs")));
+    for &(name, proxy, source, prefix) in OBJECTS.iter() {
+        code.push_str(&introspect::generate_object(
+            &interface(name), proxy, prefix, &defined(&read(source))));
+    }
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("systemd1.rs");
+    File::create(&out).and_then(|mut f| f.write_all(code.as_bytes()))
+        .unwrap_or_else(|e| panic!("writing {}: {}", out.display(), e));
+}
@@ -0,0 +1,1109 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Non-blocking access to systemd.
+//!
+//! A dispatcher thread owns a single DBus connection.  Method calls
+//! are queued to it and immediately return a `Pending` reply; the
+//! dispatcher sends them, and routes each reply back to its caller by
+//! serial number as it arrives.  Signals received on the same
+//! connection are delivered to `Signals` streams.  One dispatcher
+//! thread serves any number of calls in flight.
+//!
+//! The dispatcher sleeps in `poll(2)` on the connection's file
+//! descriptors, and on a pipe that wakes it when a request is queued,
+//! so it only runs when there is work to do.  A `Pending` reply can be
+//! waited for, polled, or awaited as a `std::future::Future`; the
+//! future's waker is woken from the dispatcher thread when the reply
+//! arrives.
+//!
+//! `AsyncConnection` and `SharedConnection` handles are `Send` and
+//! `Sync`, and clones share the same dispatcher thread, so calls can be
+//! issued concurrently from many threads over one DBus connection.
+//!
+//! A dispatcher started with a `Backoff` is resilient: when the bus
+//! connection is lost, e.g. because dbus-daemon restarted, it
+//! reconnects, re-adds its match rules, re-subscribes to systemd
+//! signals, and reports `ConnectionEvent::Reconnected`.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dbus;
+use dbus::arg::messageitem::MessageItem;
+use libc;
+use rustc_serialize::Decodable;
+
+use match_rule::MatchRule;
+use serialize::{decode, DecoderError};
+use trace::{CallSpan, Tracer};
+use {Backoff, Bus, DEFAULT_TIMEOUT_MS, Object, Signal, SystemdError,
+     SystemdResult, DBUS, SYSTEMD, SYSTEMD_PROPERTIES};
+use {connect_with_backoff, restore_subscriptions, subscription_change};
+
+type Reply = SystemdResult<Vec<MessageItem>>;
+
+/// A call queued on the dispatcher.
+struct Call {
+    object: &'static Object,
+    method: String,
+    args: Vec<MessageItem>,
+    timeout: Duration,
+    reply: Completion
+}
+
+/// The progress of a reply, shared between the dispatcher and the
+/// `Pending` waiting for it.
+enum ReplyState {
+    Waiting(Option<Waker>),
+    Ready(Reply),
+    Taken
+}
+
+struct ReplySlot {
+    state: Mutex<ReplyState>,
+    ready: Condvar
+}
+
+impl ReplySlot {
+    /// Take the reply if it has arrived, or remember `waker` to be
+    /// woken when it does.
+    fn take(&self, waker: Option<&Waker>) -> Option<Reply> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            ReplyState::Waiting(ref mut w) => {
+                if let Some(waker) = waker {
+                    *w = Some(waker.clone());
+                }
+                return None
+            },
+            ReplyState::Taken => return Some(Err(dispatcher_gone())),
+            ReplyState::Ready(_) => ()
+        }
+        match ::std::mem::replace(&mut *state, ReplyState::Taken) {
+            ReplyState::Ready(reply) => Some(reply),
+            _ => unreachable!()
+        }
+    }
+}
+
+/// The dispatcher's end of a reply.  Dropping it without completing
+/// the reply fails the call.
+struct Completion {
+    slot: Arc<ReplySlot>
+}
+
+impl Completion {
+    fn new() -> (Completion, Arc<ReplySlot>) {
+        let slot = Arc::new(ReplySlot{
+            state: Mutex::new(ReplyState::Waiting(None)),
+            ready: Condvar::new()
+        });
+        (Completion{ slot: slot.clone() }, slot)
+    }
+
+    fn complete(&self, reply: Reply) {
+        let mut state = self.slot.state.lock().unwrap();
+        if let ReplyState::Waiting(ref mut waker) = *state {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        } else {
+            return
+        }
+        *state = ReplyState::Ready(reply);
+        self.slot.ready.notify_all();
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.complete(Err(dispatcher_gone()));
+    }
+}
+
+/// A pipe end, closed when dropped.
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+/// Return the read and write ends of a new non-blocking pipe.
+fn wake_pipe() -> SystemdResult<(Fd, Fd)> {
+    let mut fds = [0 as RawFd; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(SystemdError::UnspecifiedError(
+            "failed to create the dispatcher's wake pipe".to_string()))
+    }
+    let (rx, tx) = (Fd(fds[0]), Fd(fds[1]));
+    for fd in fds.iter() {
+        unsafe {
+            libc::fcntl(*fd, libc::F_SETFL, libc::O_NONBLOCK);
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    Ok((rx, tx))
+}
+
+/// Requests handled by the dispatcher thread.
+enum Request {
+    Call(Call),
+    Signals(SignalFeed),
+    Events(Sender<ConnectionEvent>),
+    SetTracer(Option<Box<dyn Tracer>>)
+}
+
+/// Changes in the state of a dispatcher's bus connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// The connection was lost.  Calls in flight fail with
+    /// `SystemdError::Disconnected`.
+    Disconnected,
+    /// A resilient dispatcher has reconnected, and restored its match
+    /// rules and subscription.
+    Reconnected,
+    /// A resilient dispatcher has given up reconnecting.
+    ReconnectFailed
+}
+
+/// A stream of connection events from a dispatcher.
+pub struct Events {
+    rx: Receiver<ConnectionEvent>
+}
+
+impl Events {
+    /// Return the next event if one has occurred, without blocking.
+    pub fn poll(&self) -> Option<ConnectionEvent> {
+        self.rx.try_recv().ok()
+    }
+}
+
+impl Iterator for Events {
+    type Item = ConnectionEvent;
+
+    fn next(&mut self) -> Option<ConnectionEvent> {
+        self.rx.recv().ok()
+    }
+}
+
+/// A call that has been sent, and is waiting for its reply.
+struct InFlight {
+    call: Call,
+    sent: Instant
+}
+
+/// A reply that will arrive at some point in the future.
+///
+/// Block for it with `wait`, check for it with `poll`, or `.await` it:
+/// `Pending` is a `Future`, woken by the dispatcher thread.
+pub struct Pending<T> {
+    slot: Arc<ReplySlot>,
+    decode: fn(Vec<MessageItem>) -> SystemdResult<T>
+}
+
+impl<T> Pending<T> {
+    /// Block until the reply arrives, and return it.
+    pub fn wait(self) -> SystemdResult<T> {
+        {
+            let mut state = self.slot.state.lock().unwrap();
+            while let ReplyState::Waiting(_) = *state {
+                state = self.slot.ready.wait(state).unwrap();
+            }
+        }
+        self.poll().unwrap()
+    }
+
+    /// Return the reply if it has arrived, without blocking.
+    pub fn poll(&self) -> Option<SystemdResult<T>> {
+        self.slot.take(None).map(|reply| reply.and_then(self.decode))
+    }
+}
+
+impl<T> Future for Pending<T> {
+    type Output = SystemdResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<SystemdResult<T>> {
+        match self.slot.take(Some(cx.waker())) {
+            Some(reply) => Poll::Ready(reply.and_then(self.decode)),
+            None => Poll::Pending
+        }
+    }
+}
+
+/// Decode a reply as `T`.
+#[doc(hidden)]
+pub fn decode_reply<T: Decodable>(items: Vec<MessageItem>)
+                                  -> SystemdResult<T> {
+    Ok(decode::<T>(items)?)
+}
+
+/// Decode the variant in a Properties.Get reply as `T`.
+#[doc(hidden)]
+pub fn decode_property<T: Decodable>(mut items: Vec<MessageItem>)
+                                     -> SystemdResult<T> {
+    match items.pop() {
+        Some(MessageItem::Variant(v)) => Ok(decode::<T>(vec![*v])?),
+        other => Err(SystemdError::DecoderError(
+            DecoderError::ExpectedError("Variant".to_string(),
+                                        format!("{:?}", other))))
+    }
+}
+
+/// Discard a reply.
+#[doc(hidden)]
+pub fn ignore_reply(_: Vec<MessageItem>) -> SystemdResult<()> {
+    Ok(())
+}
+
+/// Signals queued for a `Signals` stream, shared between the
+/// dispatcher and the stream.
+struct SignalQueue {
+    state: Mutex<SignalState>,
+    ready: Condvar
+}
+
+struct SignalState {
+    signals: VecDeque<Signal>,
+    waker: Option<Waker>,
+    closed: bool
+}
+
+impl SignalQueue {
+    /// Take the next signal if one has arrived, or remember `waker` to
+    /// be woken when one does.  Returns `Some(None)` once the
+    /// dispatcher has gone and the queue is empty.
+    fn take(&self, waker: Option<&Waker>) -> Option<Option<Signal>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(signal) = state.signals.pop_front() {
+            return Some(Some(signal))
+        }
+        if state.closed {
+            return Some(None)
+        }
+        if let Some(waker) = waker {
+            state.waker = Some(waker.clone());
+        }
+        None
+    }
+}
+
+/// The dispatcher's end of a `Signals` stream.  Dropping it ends the
+/// stream.
+struct SignalFeed {
+    queue: Arc<SignalQueue>
+}
+
+impl SignalFeed {
+    fn new() -> (SignalFeed, Arc<SignalQueue>) {
+        let queue = Arc::new(SignalQueue{
+            state: Mutex::new(SignalState{
+                signals: VecDeque::new(),
+                waker: None,
+                closed: false
+            }),
+            ready: Condvar::new()
+        });
+        (SignalFeed{ queue: queue.clone() }, queue)
+    }
+
+    /// Queue `signal`, and wake the stream.  Returns false once the
+    /// stream has been dropped.
+    fn send(&self, signal: &Signal) -> bool {
+        if Arc::strong_count(&self.queue) == 1 {
+            return false
+        }
+        let mut state = self.queue.state.lock().unwrap();
+        state.signals.push_back(signal.clone());
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.queue.ready.notify_all();
+        true
+    }
+}
+
+impl Drop for SignalFeed {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        self.queue.ready.notify_all();
+    }
+}
+
+/// A stream of signals received by a dispatcher.
+///
+/// Block for the next signal by iterating, check for one with `poll`,
+/// or drive it from a task with `poll_next`, whose waker is woken by
+/// the dispatcher thread when a signal arrives.
+pub struct Signals {
+    queue: Arc<SignalQueue>
+}
+
+impl Signals {
+    /// Return the next signal if one has arrived, without blocking.
+    pub fn poll(&self) -> Option<Signal> {
+        self.queue.take(None).and_then(|signal| signal)
+    }
+
+    /// Return the next signal if one has arrived, or arrange for the
+    /// task in `cx` to be woken when one does.  Returns
+    /// `Poll::Ready(None)` once the connection has gone away.
+    pub fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Signal>> {
+        match self.queue.take(Some(cx.waker())) {
+            Some(signal) => Poll::Ready(signal),
+            None => Poll::Pending
+        }
+    }
+}
+
+impl Iterator for Signals {
+    type Item = Signal;
+
+    /// Block until the next signal arrives.  Returns None once the
+    /// connection has gone away.
+    fn next(&mut self) -> Option<Signal> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(signal) = state.signals.pop_front() {
+                return Some(signal)
+            }
+            if state.closed {
+                return None
+            }
+            state = self.queue.ready.wait(state).unwrap();
+        }
+    }
+}
+
+fn dispatcher_gone() -> SystemdError {
+    SystemdError::UnspecifiedError("dispatcher thread has exited".to_string())
+}
+
+/// Handle to a dispatcher thread.  The thread exits when the handle
+/// is dropped and no calls are in flight.
+struct Dispatcher {
+    tx: Mutex<Sender<Request>>,
+    wake: Fd
+}
+
+impl Dispatcher {
+    /// Start a dispatcher thread on a new private connection to `bus`.
+    /// If `backoff` is given, the dispatcher reconnects when the
+    /// connection is lost.
+    pub fn start(bus: Bus, backoff: Option<Backoff>)
+                 -> SystemdResult<Dispatcher> {
+        let (tx, rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        let (wake_rx, wake_tx) = wake_pipe()?;
+        thread::spawn(move || {
+            let conn = match bus.connect() {
+                Ok(conn) => {
+                    let _ = ready_tx.send(Ok(()));
+                    conn
+                },
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return
+                }
+            };
+            DispatchLoop::new(conn, bus, backoff, rx, wake_rx).run()
+        });
+        match ready_rx.recv() {
+            Ok(Ok(())) => Ok(Dispatcher{ tx: Mutex::new(tx), wake: wake_tx }),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(dispatcher_gone())
+        }
+    }
+
+    /// Queue a request for the dispatcher thread, and wake it.
+    fn request(&self, request: Request) -> Result<(), Request> {
+        self.tx.lock().unwrap().send(request).map_err(|e| e.0)?;
+        // A full pipe already wakes the dispatcher, so a failed write
+        // can be ignored.
+        let byte = [0u8; 1];
+        unsafe {
+            libc::write(self.wake.0, byte.as_ptr() as *const libc::c_void, 1);
+        }
+        Ok(())
+    }
+
+    /// Queue a call of `method` on `object`.
+    pub fn call<T>(&self, object: &'static Object, method: &str,
+                   args: Vec<MessageItem>, timeout: Duration,
+                   decode: fn(Vec<MessageItem>) -> SystemdResult<T>)
+                   -> Pending<T> {
+        let (reply, slot) = Completion::new();
+        let call = Call{
+            object,
+            method: method.to_string(),
+            args,
+            timeout,
+            reply
+        };
+        // If the dispatcher has gone, dropping the call fails it.
+        let _ = self.request(Request::Call(call));
+        Pending{ slot, decode }
+    }
+
+    /// Return a new stream of the signals received by the dispatcher.
+    pub fn signals(&self) -> SystemdResult<Signals> {
+        let (feed, queue) = SignalFeed::new();
+        self.request(Request::Signals(feed)).map_err(|_| dispatcher_gone())?;
+        Ok(Signals{ queue })
+    }
+
+    /// Return a new stream of connection events.
+    pub fn events(&self) -> SystemdResult<Events> {
+        let (tx, rx) = channel();
+        self.request(Request::Events(tx)).map_err(|_| dispatcher_gone())?;
+        Ok(Events{ rx })
+    }
+
+    /// Install, or remove, the tracer used for calls.
+    pub fn set_tracer(&self, tracer: Option<Box<dyn Tracer>>) {
+        let _ = self.request(Request::SetTracer(tracer));
+    }
+}
+
+/// State owned by the dispatcher thread.
+struct DispatchLoop {
+    conn: dbus::ffidisp::Connection,
+    bus: Bus,
+    backoff: Option<Backoff>,
+    requests: Receiver<Request>,
+    wake: Fd,
+    in_flight: HashMap<u32, InFlight>,
+    signals: Vec<SignalFeed>,
+    events: Vec<Sender<ConnectionEvent>>,
+    rules: Vec<String>,
+    subscribed: bool,
+    connected: bool,
+    tracer: Option<Box<dyn Tracer>>
+}
+
+impl DispatchLoop {
+    fn new(conn: dbus::ffidisp::Connection, bus: Bus,
+           backoff: Option<Backoff>, requests: Receiver<Request>, wake: Fd)
+           -> DispatchLoop {
+        DispatchLoop{
+            conn,
+            bus,
+            backoff,
+            requests,
+            wake,
+            in_flight: HashMap::new(),
+            signals: vec![],
+            events: vec![],
+            rules: vec![],
+            subscribed: false,
+            connected: true,
+            tracer: None
+        }
+    }
+
+    fn run(mut self) {
+        let mut handles_gone = false;
+        while self.connected {
+            while !handles_gone {
+                match self.requests.try_recv() {
+                    Ok(request) => self.handle(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => handles_gone = true
+                }
+            }
+            if handles_gone && self.in_flight.is_empty() {
+                return
+            }
+            // Messages libdbus has already read do not make its fds
+            // ready, so handle them before sleeping.
+            self.receive();
+            self.wait_ready(!handles_gone);
+            self.receive();
+            self.expire();
+        }
+    }
+
+    /// Sleep until the bus connection or, if `wakeable`, the wake pipe
+    /// is ready, or the next call in flight times out.  Once every
+    /// handle has gone the pipe is closed and always ready, so it is
+    /// no longer polled.
+    fn wait_ready(&mut self, wakeable: bool) {
+        let watches = self.conn.watch_fds();
+        let mut fds: Vec<libc::pollfd> =
+            watches.iter().map(|w| w.to_pollfd()).collect();
+        if wakeable {
+            fds.push(libc::pollfd{ fd: self.wake.0, events: libc::POLLIN,
+                                   revents: 0 });
+        }
+        let timeout = self.in_flight.values()
+            .map(|f| f.call.timeout.checked_sub(f.sent.elapsed())
+                 .unwrap_or_else(|| Duration::from_secs(0)))
+            .min()
+            .map(|t| ::timeout_ms(t + Duration::from_millis(1)))
+            .unwrap_or(-1);
+        let n = unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout)
+        };
+        if wakeable && n > 0 && fds[fds.len() - 1].revents != 0 {
+            let mut buf = [0u8; 64];
+            while unsafe {
+                libc::read(self.wake.0, buf.as_mut_ptr() as *mut libc::c_void,
+                           buf.len())
+            } > 0 {}
+        }
+    }
+
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Call(call) => self.send(call),
+            Request::Signals(feed) => self.signals.push(feed),
+            Request::Events(tx) => self.events.push(tx),
+            Request::SetTracer(tracer) => self.tracer = tracer
+        }
+    }
+
+    fn send(&mut self, call: Call) {
+        let mut m = call.object.method(&call.method).unwrap();
+        if !call.args.is_empty() {
+            m.append_items(&call.args);
+        }
+        match self.conn.send(m) {
+            Ok(serial) => {
+                self.in_flight.insert(serial, InFlight{
+                    call,
+                    sent: Instant::now()
+                });
+            },
+            Err(e) => call.reply.complete(Err(SystemdError::from(e)))
+        }
+    }
+
+    /// Let libdbus read and write whatever it can without blocking,
+    /// then process every message it has read.  This is used rather
+    /// than `watch_handle`, which deadlocks when libdbus removes its
+    /// watch on hangup.
+    fn receive(&mut self) {
+        let mut items = vec![];
+        for item in self.conn.iter(0) {
+            match item {
+                dbus::ffidisp::ConnectionItem::Nothing => break,
+                item => items.push(item)
+            }
+        }
+        for item in items.into_iter() {
+            match item {
+                dbus::ffidisp::ConnectionItem::MethodReturn(mut m) => {
+                    let serial = match m.get_reply_serial() {
+                        Some(serial) => serial,
+                        None => continue
+                    };
+                    if let Some(in_flight) = self.in_flight.remove(&serial) {
+                        let reply = m.as_result()
+                            .map(|m| m.get_items())
+                            .map_err(SystemdError::from);
+                        self.complete(in_flight, reply);
+                    }
+                },
+                dbus::ffidisp::ConnectionItem::Signal(mut m) => {
+                    let signal = Signal::from_message(&mut m);
+                    if signal.is_disconnected() {
+                        self.disconnected();
+                        return
+                    }
+                    if signal.is_name_bookkeeping() {
+                        continue
+                    }
+                    self.signals.retain(|feed| feed.send(&signal));
+                },
+                _ => ()
+            }
+        }
+    }
+
+    /// Fail calls whose timeout has passed.
+    fn expire(&mut self) {
+        let expired: Vec<u32> = self.in_flight.iter()
+            .filter(|&(_, f)| f.sent.elapsed() >= f.call.timeout)
+            .map(|(serial, _)| *serial)
+            .collect();
+        for serial in expired.into_iter() {
+            let in_flight = self.in_flight.remove(&serial).unwrap();
+            self.complete(in_flight, Err(SystemdError::Timeout));
+        }
+    }
+
+    /// Fail all calls in flight, and reconnect if resilient.
+    fn disconnected(&mut self) {
+        let in_flight: Vec<u32> = self.in_flight.keys().cloned().collect();
+        for serial in in_flight.into_iter() {
+            let in_flight = self.in_flight.remove(&serial).unwrap();
+            self.complete(in_flight, Err(SystemdError::Disconnected));
+        }
+        self.notify(ConnectionEvent::Disconnected);
+        let backoff = match self.backoff {
+            Some(ref backoff) => backoff.clone(),
+            None => {
+                self.connected = false;
+                return
+            }
+        };
+        let conn = connect_with_backoff(&self.bus, &backoff)
+            .and_then(|conn| {
+                restore_subscriptions(&conn, &self.rules,
+                                      self.subscribed)?;
+                Ok(conn)
+            });
+        match conn {
+            Ok(conn) => {
+                self.conn = conn;
+                self.notify(ConnectionEvent::Reconnected);
+            },
+            Err(_) => {
+                self.connected = false;
+                self.notify(ConnectionEvent::ReconnectFailed);
+            }
+        }
+    }
+
+    /// Record the rule added or removed by an accepted AddMatch or
+    /// RemoveMatch `call`, so it can be restored on reconnection.
+    fn rule_change(&mut self, call: &Call) {
+        let rule = match call.args.first() {
+            Some(MessageItem::Str(rule)) => rule,
+            _ => return
+        };
+        match call.method.as_str() {
+            "AddMatch" => self.rules.push(rule.clone()),
+            "RemoveMatch" => {
+                if let Some(i) = self.rules.iter().position(|r| r == rule) {
+                    self.rules.remove(i);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn notify(&mut self, event: ConnectionEvent) {
+        self.events.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    /// Deliver `reply`, noting a change of subscription or match
+    /// rules once the bus has accepted it.
+    fn complete(&mut self, in_flight: InFlight, reply: Reply) {
+        if reply.is_ok() {
+            if ::std::ptr::eq(in_flight.call.object, DBUS) {
+                self.rule_change(&in_flight.call);
+            } else if let Some(subscribed) =
+                subscription_change(&in_flight.call.method) {
+                self.subscribed = subscribed;
+            }
+        }
+        if let Some(ref tracer) = self.tracer {
+            let call = &in_flight.call;
+            tracer.call(&CallSpan{
+                service: call.object.service,
+                path: call.object.path,
+                interface: call.object.interface,
+                method: &call.method,
+                args: &call.args,
+                elapsed: in_flight.sent.elapsed(),
+                result: match reply {
+                    Ok(ref items) => Ok(&items[..]),
+                    Err(ref e) => Err(e)
+                }
+            });
+        }
+        in_flight.call.reply.complete(reply);
+    }
+}
+
+/// Non-blocking connection to systemd.
+///
+/// Methods return a `Pending` reply instead of blocking the calling
+/// thread.  All calls share one dispatcher thread and one DBus
+/// connection, on which signals are also received.  Clones share the
+/// dispatcher, but each has its own timeout.
+///
+/// ```no_run
+/// use systemd_dbus::AsyncConnection;
+///
+/// let conn = AsyncConnection::new().unwrap();
+/// let units = conn.list_units().unwrap();
+/// let jobs = conn.list_jobs().unwrap();
+/// println!("{:?} {:?}", units.wait(), jobs.wait());
+/// ```
+#[derive(Clone)]
+pub struct AsyncConnection {
+    dispatcher: Arc<Dispatcher>,
+    object: &'static Object,
+    timeout: Duration
+}
+
+impl AsyncConnection {
+    /// Create a new non-blocking connection to systemd.
+    pub fn new() -> SystemdResult<AsyncConnection> {
+        AsyncConnection::open(Bus::System, None)
+    }
+
+    /// Create a new non-blocking connection to systemd, which
+    /// reconnects with `backoff` when the bus connection is lost.
+    pub fn resilient(backoff: Backoff) -> SystemdResult<AsyncConnection> {
+        AsyncConnection::open(Bus::System, Some(backoff))
+    }
+
+    /// Create a new non-blocking connection to the systemd manager on
+    /// `bus`, reconnecting with `backoff` if given.
+    pub fn open(bus: Bus, backoff: Option<Backoff>)
+                -> SystemdResult<AsyncConnection> {
+        let dispatcher = Dispatcher::start(bus, backoff)?;
+        Ok(AsyncConnection{
+            dispatcher: Arc::new(dispatcher),
+            object: SYSTEMD,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS as u64)
+        })
+    }
+
+    /// Return a stream of events about the state of the connection.
+    pub fn events(&self) -> SystemdResult<Events> {
+        self.dispatcher.events()
+    }
+
+    /// Set the timeout for calls on this connection.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Install a tracer, which receives a span for every DBus call
+    /// made on this connection.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.dispatcher.set_tracer(Some(tracer));
+    }
+
+    /// Add a match rule for signals.
+    pub fn add_match(&self, rule_string: &str) -> SystemdResult<()> {
+        self.call_bus("AddMatch", rule_string)
+    }
+
+    /// Validate and add a match rule for signals.
+    pub fn add_match_rule(&self, rule: &MatchRule) -> SystemdResult<()> {
+        rule.validate()?;
+        self.add_match(rule.to_string().as_str())
+    }
+
+    /// Remove a match rule.
+    pub fn remove_match(&self, rule_string: &str) -> SystemdResult<()> {
+        self.call_bus("RemoveMatch", rule_string)
+    }
+
+    /// Call `method` of the bus itself with a match rule as its
+    /// argument, and wait for the bus to accept it.
+    fn call_bus(&self, method: &str, rule_string: &str) -> SystemdResult<()> {
+        self.dispatcher.call(DBUS, method,
+                             vec![MessageItem::Str(rule_string.to_string())],
+                             self.timeout, ignore_reply).wait()
+    }
+
+    /// Remove a match rule added with `add_match_rule`.
+    pub fn remove_match_rule(&self, rule: &MatchRule) -> SystemdResult<()> {
+        self.remove_match(rule.to_string().as_str())
+    }
+
+    /// Subscribe to systemd signals, and return a stream of the
+    /// signals received on this connection.
+    pub fn systemd_signals(&self) -> SystemdResult<Signals> {
+        let signals = self.dispatcher.signals()?;
+        for rule in ::systemd_signal_rules().iter() {
+            self.add_match_rule(rule)?;
+        }
+        self.subscribe()?.wait()?;
+        Ok(signals)
+    }
+
+    #[doc(hidden)]
+    pub fn call<T>(&self, method: &str, args: Vec<MessageItem>,
+                   decode: fn(Vec<MessageItem>) -> SystemdResult<T>)
+                   -> Pending<T> {
+        self.dispatcher.call(self.object, method, args, self.timeout, decode)
+    }
+
+    #[doc(hidden)]
+    pub fn get_property<T>(&self, interface: &str, name: &str,
+                           decode: fn(Vec<MessageItem>) -> SystemdResult<T>)
+                           -> Pending<T> {
+        let args = vec![MessageItem::Str(interface.to_string()),
+                        MessageItem::Str(name.to_string())];
+        self.dispatcher.call(SYSTEMD_PROPERTIES, "Get", args, self.timeout,
+                             decode)
+    }
+}
+
+/// Blocking connection to systemd that can be shared between threads.
+///
+/// Methods block the calling thread until the reply arrives, but many
+/// threads can have calls in flight at once; replies are routed back
+/// to the right caller by the dispatcher.  Clone the handle to hand it
+/// to another thread.
+///
+/// ```no_run
+/// use std::thread;
+/// use systemd_dbus::SharedConnection;
+///
+/// let conn = SharedConnection::new().unwrap();
+/// let c = conn.clone();
+/// let t = thread::spawn(move || c.list_jobs());
+/// println!("{:?} {:?}", conn.list_units(), t.join());
+/// ```
+#[derive(Clone)]
+pub struct SharedConnection {
+    inner: AsyncConnection
+}
+
+impl SharedConnection {
+    /// Create a new shareable connection to systemd.
+    pub fn new() -> SystemdResult<SharedConnection> {
+        Ok(SharedConnection{ inner: AsyncConnection::new()? })
+    }
+
+    /// Create a new shareable connection to systemd, which reconnects
+    /// with `backoff` when the bus connection is lost.
+    pub fn resilient(backoff: Backoff) -> SystemdResult<SharedConnection> {
+        Ok(SharedConnection{ inner: AsyncConnection::resilient(backoff)? })
+    }
+
+    /// Create a new shareable connection to the systemd manager on
+    /// `bus`, reconnecting with `backoff` if given.
+    pub fn open(bus: Bus, backoff: Option<Backoff>)
+                -> SystemdResult<SharedConnection> {
+        Ok(SharedConnection{ inner: AsyncConnection::open(bus, backoff)? })
+    }
+
+    /// Set the timeout for calls made through this handle.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.set_timeout(timeout);
+    }
+
+    /// Return the non-blocking connection this handle calls through.
+    pub fn as_async(&self) -> &AsyncConnection {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread::{self, Thread};
+    use std::time::{Duration, Instant};
+    use mock::MockSystemd;
+    use {ObjectPath, SystemdError};
+    use super::*;
+
+    /// Wakes a parked thread, counting the wake-ups.
+    struct ThreadWaker {
+        thread: Thread,
+        wakes: AtomicUsize
+    }
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.wakes.fetch_add(1, Ordering::SeqCst);
+            self.thread.unpark();
+        }
+    }
+
+    /// Drive `future` to completion on this thread, returning its
+    /// output and how often it was woken.
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let waker = Arc::new(ThreadWaker{ thread: thread::current(),
+                                          wakes: AtomicUsize::new(0) });
+        let task_waker = Waker::from(waker.clone());
+        let mut cx = Context::from_waker(&task_waker);
+        let start = Instant::now();
+        loop {
+            if let Poll::Ready(output) = Pin::new(&mut future).poll(&mut cx) {
+                return (output, waker.wakes.load(Ordering::SeqCst))
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "never woken");
+            thread::park_timeout(Duration::from_secs(5));
+        }
+    }
+
+    fn mock_connection() -> (MockSystemd, AsyncConnection) {
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("a.service", "A", "active", "running");
+        mock.add_unit("b.service", "B", "inactive", "dead");
+        let conn = AsyncConnection::open(mock.bus(), None).unwrap();
+        (mock, conn)
+    }
+
+    #[test]
+    fn replies_are_routed_to_their_calls() {
+        let (mock, conn) = mock_connection();
+        let b = conn.get_unit("b.service").unwrap();
+        let units = conn.list_units().unwrap();
+        let a = conn.get_unit("a.service").unwrap();
+        let missing = conn.get_unit("c.service").unwrap();
+        assert!(missing.wait().is_err());
+        assert_eq!(ObjectPath::new(
+            "/org/freedesktop/systemd1/unit/a_2eservice").unwrap(),
+                   a.wait().unwrap());
+        assert_eq!(2, units.wait().unwrap().len());
+        assert_eq!(ObjectPath::new(
+            "/org/freedesktop/systemd1/unit/b_2eservice").unwrap(),
+                   b.wait().unwrap());
+        assert_eq!(4, mock.calls().len());
+    }
+
+    #[test]
+    fn calls_from_many_threads() {
+        let (_mock, conn) = mock_connection();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let conn = conn.clone();
+            thread::spawn(move || conn.list_units().unwrap().wait().unwrap())
+        }).collect();
+        for t in threads.into_iter() {
+            assert_eq!(2, t.join().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn calls_time_out() {
+        let (mock, mut conn) = mock_connection();
+        mock.withhold_replies("Reload");
+        conn.set_timeout(Duration::from_millis(100));
+        let start = Instant::now();
+        let reload = conn.reload().unwrap();
+        let units = conn.list_units().unwrap();
+        match reload.wait() {
+            Err(SystemdError::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other)
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(2, units.wait().unwrap().len());
+    }
+
+    #[test]
+    fn pending_is_a_future() {
+        let (mock, conn) = mock_connection();
+        let (units, _) = block_on(conn.list_units().unwrap());
+        assert_eq!(2, units.unwrap().len());
+
+        // The dispatcher wakes a future that was polled before the
+        // reply arrived.
+        mock.withhold_replies("Reload");
+        let mut conn = conn.clone();
+        conn.set_timeout(Duration::from_millis(200));
+        let (reload, wakes) = block_on(conn.reload().unwrap());
+        assert!(matches!(reload, Err(SystemdError::Timeout)));
+        assert!(wakes >= 1);
+    }
+
+    /// The next signal of a stream, as a future.
+    struct NextSignal<'a>(&'a mut Signals);
+
+    impl<'a> Future for NextSignal<'a> {
+        type Output = Option<::Signal>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context)
+                -> Poll<Option<::Signal>> {
+            self.0.poll_next(cx)
+        }
+    }
+
+    #[test]
+    fn signals_wake_their_task() {
+        let (mock, conn) = mock_connection();
+        let mut signals = conn.systemd_signals().unwrap();
+        let emitter = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            mock.emit_unit_new("a.service");
+            mock
+        });
+        let (signal, wakes) = block_on(NextSignal(&mut signals));
+        assert_eq!(Some("UnitNew".to_string()), signal.unwrap().member);
+        assert!(wakes >= 1);
+        emitter.join().unwrap();
+    }
+
+    #[test]
+    fn rejected_match_rules_fail() {
+        let (_mock, conn) = mock_connection();
+        assert!(conn.add_match("type='nonsense'").is_err());
+        let rule = "type='signal',interface='org.example.Test'";
+        conn.add_match(rule).unwrap();
+        conn.remove_match(rule).unwrap();
+        assert!(conn.remove_match(rule).is_err());
+    }
+
+    #[test]
+    fn resilient_dispatcher_restores_subscriptions() {
+        let mut mock = MockSystemd::start().unwrap();
+        let backoff = ::Backoff{ initial: Duration::from_millis(10),
+                                 max: Duration::from_millis(100),
+                                 attempts: Some(20) };
+        let conn = AsyncConnection::open(mock.bus(), Some(backoff)).unwrap();
+        let mut events = conn.events().unwrap();
+        let signals = conn.systemd_signals().unwrap();
+        // Clear first: the dispatcher may resubscribe before restart
+        // returns.
+        mock.clear_calls();
+        mock.restart().unwrap();
+        assert_eq!(Some(ConnectionEvent::Disconnected), events.next());
+        assert_eq!(Some(ConnectionEvent::Reconnected), events.next());
+        // The match rules were re-added before Subscribe was sent
+        let start = Instant::now();
+        while !mock.calls().iter().any(|c| c.method == "Subscribe") {
+            assert!(start.elapsed() < Duration::from_secs(5), "not resubscribed");
+            thread::sleep(Duration::from_millis(10));
+        }
+        mock.emit_unit_new("a.service");
+        let start = Instant::now();
+        loop {
+            if let Some(signal) = signals.poll() {
+                assert_eq!(Some("UnitNew".to_string()), signal.member);
+                break
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "no signal");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn poll_does_not_block() {
+        let (mock, mut conn) = mock_connection();
+        mock.withhold_replies("Reload");
+        conn.set_timeout(Duration::from_millis(100));
+        let reload = conn.reload().unwrap();
+        assert!(reload.poll().is_none());
+        thread::sleep(Duration::from_millis(300));
+        assert!(matches!(reload.poll(), Some(Err(SystemdError::Timeout))));
+    }
+}
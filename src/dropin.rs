@@ -0,0 +1,330 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Drop-in overrides for units, as made by `systemctl edit`.
+//!
+//! A drop-in is a `.conf` file in the `<unit>.d/` directory next to
+//! where the unit could be installed.  Its settings are applied after
+//! the unit file's, so a drop-in can change a vendor unit without
+//! editing it.  Changes reload systemd, so they apply the next time
+//! the unit is started.
+//!
+//! ```no_run
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::dropin::DropInLocation;
+//! use systemd_dbus::unitfile::Document;
+//!
+//! let conn = Connection::new().unwrap();
+//! let limits: Document = "[Service]\nLimitNOFILE=65536\n".parse().unwrap();
+//! conn.create_drop_in("nginx.service", "limits", &limits,
+//!                     DropInLocation::Persistent).unwrap();
+//! let config = conn.effective_config("nginx.service").unwrap();
+//! assert_eq!(Some("65536"), config.document.get("Service", "LimitNOFILE"));
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use dbus::arg::messageitem::MessageItem;
+
+use serialize::decode;
+use unitfile::Document;
+use unit_name::UnitName;
+use units::{write_atomic, write_new};
+use {Connection, SystemdError, SystemdResult};
+
+/// Where drop-ins are read and written.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DropInLocation {
+    /// /etc/systemd/system, kept across reboots
+    Persistent,
+    /// /run/systemd/system, lost on reboot
+    Runtime,
+    /// Any other unit directory
+    Directory(PathBuf)
+}
+
+impl DropInLocation {
+    /// The drop-in directory for `unit`.
+    pub fn directory(&self, unit: &str) -> PathBuf {
+        let base = match self {
+            &DropInLocation::Persistent => Path::new("/etc/systemd/system"),
+            &DropInLocation::Runtime => Path::new("/run/systemd/system"),
+            DropInLocation::Directory(dir) => dir.as_path()
+        };
+        base.join(format!("{}.d", unit))
+    }
+}
+
+/// A unit's configuration, with its drop-ins applied.
+#[derive(Clone, Debug)]
+pub struct EffectiveConfig {
+    /// The unit file, or empty if the unit has none
+    pub fragment_path: String,
+    /// The drop-ins, in the order they are applied
+    pub drop_in_paths: Vec<String>,
+    /// The unit file followed by its drop-ins, each preceded by a
+    /// comment naming the file, as `systemctl cat` shows them
+    pub document: Document
+}
+
+fn check_unit(unit: &str) -> SystemdResult<()> {
+    UnitName::new(unit)?;
+    Ok(())
+}
+
+/// The file name for drop-in `name`, with a .conf suffix.
+fn file_name(unit: &str, name: &str) -> SystemdResult<String> {
+    check_unit(unit)?;
+    let name = if name.ends_with(".conf") {
+        name.to_string()
+    } else {
+        format!("{}.conf", name)
+    };
+    if name == ".conf" || name.contains('/') || name.starts_with(".") {
+        return Err(SystemdError::InvalidArg(format!("invalid drop-in {}", name)))
+    }
+    Ok(name)
+}
+
+/// Read and concatenate the unit file and drop-ins at `paths`.
+fn merge(paths: &[&str]) -> SystemdResult<Document> {
+    let mut doc = Document::new();
+    for path in paths.iter() {
+        let header: Document = format!("# {}\n", path).parse()?;
+        doc.append(header);
+        doc.append(Document::read(Path::new(path))?);
+    }
+    Ok(doc)
+}
+
+impl Connection {
+    /// List the drop-ins for `unit` at `location`, sorted by name as
+    /// systemd applies them.
+    pub fn list_drop_ins(&self, unit: &str, location: DropInLocation)
+                         -> SystemdResult<Vec<PathBuf>> {
+        check_unit(unit)?;
+        let dir = location.directory(unit);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(SystemdError::UnspecifiedError(
+                format!("reading {}: {}", dir.display(), e)))
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map(|e| e == "conf").unwrap_or(false))
+            .collect();
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Create drop-in `name` for `unit`, failing if it exists, and
+    /// reload systemd.
+    pub fn create_drop_in(&self, unit: &str, name: &str, doc: &Document,
+                          location: DropInLocation) -> SystemdResult<PathBuf> {
+        let name = file_name(unit, name)?;
+        let dir = location.directory(unit);
+        let path = write_new(&dir, &name, &doc.to_string())?;
+        self.reload()?;
+        Ok(path)
+    }
+
+    /// Replace drop-in `name` for `unit`, failing if it does not
+    /// exist, and reload systemd.
+    pub fn update_drop_in(&self, unit: &str, name: &str, doc: &Document,
+                          location: DropInLocation) -> SystemdResult<PathBuf> {
+        let name = file_name(unit, name)?;
+        let dir = location.directory(unit);
+        if !dir.join(&name).exists() {
+            return Err(SystemdError::InvalidArg(
+                format!("{} does not exist", dir.join(&name).display())))
+        }
+        let path = write_atomic(&dir, &name, &doc.to_string())?;
+        self.reload()?;
+        Ok(path)
+    }
+
+    /// Delete drop-in `name` for `unit`, and the drop-in directory if
+    /// it is left empty, and reload systemd.
+    pub fn delete_drop_in(&self, unit: &str, name: &str,
+                          location: DropInLocation) -> SystemdResult<()> {
+        let name = file_name(unit, name)?;
+        let dir = location.directory(unit);
+        fs::remove_file(dir.join(&name)).map_err(|e| {
+            SystemdError::UnspecifiedError(format!("deleting {}: {}", name, e))
+        })?;
+        let _ = fs::remove_dir(&dir);
+        self.reload()
+    }
+
+    /// The configuration of `unit` as systemd has loaded it: the unit
+    /// file followed by the drop-ins, from the FragmentPath and
+    /// DropInPaths properties.  Drop-ins changed since the last reload
+    /// are read as they are now.
+    pub fn effective_config(&self, unit: &str) -> SystemdResult<EffectiveConfig> {
+        static UNIT: &str = "org.freedesktop.systemd1.Unit";
+        let path = self.load_unit(unit.to_string())?;
+        let property = |name: &str| -> SystemdResult<MessageItem> {
+            self.get_property(&path, UNIT, name)
+        };
+        let fragment_path: String = decode(vec![property("FragmentPath")?])?;
+        let drop_in_paths: Vec<String> =
+            decode(vec![property("DropInPaths")?])?;
+        let mut paths: Vec<&str> = vec![];
+        if !fragment_path.is_empty() {
+            paths.push(&fragment_path);
+        }
+        paths.extend(drop_in_paths.iter().map(|p| &p[..]));
+        let document = merge(&paths)?;
+        Ok(EffectiveConfig{
+            fragment_path: fragment_path.clone(),
+            drop_in_paths: drop_in_paths.clone(),
+            document
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::merge;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use mock::MockSystemd;
+    use unitfile::Document;
+    use Connection;
+
+    #[test]
+    fn drop_in_lifecycle() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-dropin-{}",
+                                               ::std::process::id()));
+        let location = DropInLocation::Directory(dir.clone());
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let doc: Document = "[Service]\nLimitNOFILE=65536\n".parse().unwrap();
+        assert!(conn.list_drop_ins("app.service", location.clone())
+                .unwrap().is_empty());
+        assert!(conn.update_drop_in("app.service", "limits", &doc,
+                                    location.clone()).is_err());
+        let path = conn.create_drop_in("app.service", "limits", &doc,
+                                       location.clone()).unwrap();
+        assert_eq!(dir.join("app.service.d").join("limits.conf"), path);
+        assert!(conn.create_drop_in("app.service", "limits.conf", &doc,
+                                    location.clone()).is_err());
+        conn.update_drop_in("app.service", "limits", &doc, location.clone())
+            .unwrap();
+        assert_eq!(vec![path], conn.list_drop_ins("app.service", location.clone())
+                   .unwrap());
+        conn.delete_drop_in("app.service", "limits", location.clone()).unwrap();
+        assert!(!dir.join("app.service.d").exists());
+        assert_eq!(3, mock.calls().iter().filter(|c| c.method == "Reload").count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unreadable_drop_in_directory() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-unreadable-{}",
+                                               ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("app.service.d")).unwrap();
+        let location = DropInLocation::Directory(dir.clone());
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        assert!(conn.list_drop_ins("app.service", location).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_never_replaces() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-create-{}",
+                                               ::std::process::id()));
+        let location = DropInLocation::Directory(dir.clone());
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let first: Document = "[Service]\nNice=5\n".parse().unwrap();
+        let second: Document = "[Service]\nNice=10\n".parse().unwrap();
+        let path = conn.create_drop_in("app.service", "nice", &first,
+                                       location.clone()).unwrap();
+        match conn.create_drop_in("app.service", "nice", &second, location) {
+            Err(SystemdError::InvalidArg(_)) => (),
+            other => panic!("expected InvalidArg, got {:?}", other)
+        }
+        assert_eq!(first.to_string(), fs::read_to_string(&path).unwrap());
+        // The temporary file is gone
+        assert_eq!(1, fs::read_dir(path.parent().unwrap()).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_unit_names() {
+        let location = DropInLocation::Directory(env::temp_dir());
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        for unit in ["", "..", "app", "../app.service", "app.service/x"].iter() {
+            assert!(conn.list_drop_ins(unit, location.clone()).is_err(),
+                    "{:?} was accepted", unit);
+        }
+        assert!(conn.list_drop_ins("app@.service", location).is_ok());
+    }
+
+    #[test]
+    fn effective_config_test() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-effective-{}",
+                                               ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let unit = dir.join("app.service");
+        let conf = dir.join("limits.conf");
+        File::create(&unit).unwrap()
+            .write_all(b"[Service]\nLimitNOFILE=1024\n").unwrap();
+        File::create(&conf).unwrap()
+            .write_all(b"[Service]\nLimitNOFILE=65536\n").unwrap();
+        let (unit, conf) = (unit.to_str().unwrap(), conf.to_str().unwrap());
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("app.service", "App", "active", "running");
+        mock.set_unit_sources("app.service", unit, &[conf]);
+        let conn = Connection::open(mock.bus()).unwrap();
+        let config = conn.effective_config("app.service").unwrap();
+        assert_eq!(unit, config.fragment_path);
+        assert_eq!(vec![conf.to_string()], config.drop_in_paths);
+        assert_eq!(Some("65536"), config.document.get("Service", "LimitNOFILE"));
+        assert_eq!(vec!["1024", "65536"],
+                   config.document.values("Service", "LimitNOFILE"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_drop_ins() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-merge-{}",
+                                               ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let unit = dir.join("app.service");
+        let conf = dir.join("limits.conf");
+        File::create(&unit).unwrap()
+            .write_all(b"[Service]\nLimitNOFILE=1024\n").unwrap();
+        File::create(&conf).unwrap()
+            .write_all(b"[Service]\nLimitNOFILE=65536\n").unwrap();
+        let doc = merge(&[unit.to_str().unwrap(), conf.to_str().unwrap()])
+            .unwrap();
+        assert_eq!(Some("65536"), doc.get("Service", "LimitNOFILE"));
+        assert_eq!(format!("# {}\n[Service]\nLimitNOFILE=1024\n\
+                            # {}\n[Service]\nLimitNOFILE=65536\n",
+                           unit.display(), conf.display()),
+                   doc.to_string());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
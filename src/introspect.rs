@@ -0,0 +1,773 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DBus introspection data, and proxy code generated from it.
+//!
+//! `parse` reads the XML returned by `Introspect`, which
+//! `Connection::introspect` calls, into a tree of interfaces.
+//! `generate` turns an interface into `systemd_dbus!` method
+//! wrappers, `systemd_property!` getters and signal structs, and
+//! `generate_object` turns the interface of unit or job objects into
+//! `object_dbus!` and `object_property!` wrappers on their proxy type.
+//! With the `codegen` feature, build.rs runs them over the XML files
+//! in introspection/ and includes the result in the crate.
+//!
+//! This module uses only std, so that build.rs can include it.
+//!
+//! See:
+//!   http://dbus.freedesktop.org/doc/dbus-specification.html#introspection-format
+
+use std::fmt;
+
+/// Errors in parsing introspection data.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IntrospectError {
+    /// The XML is malformed
+    Syntax(String),
+    /// A required attribute is missing, as (element, attribute)
+    MissingAttribute(String, String)
+}
+
+impl fmt::Display for IntrospectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            IntrospectError::Syntax(s) => write!(f, "syntax error: {}", s),
+            IntrospectError::MissingAttribute(e, a) =>
+                write!(f, "<{}> has no {} attribute", e, a)
+        }
+    }
+}
+
+/// An object, with its interfaces and child objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    /// The object's path, relative to its parent; absent on the root
+    pub name: Option<String>,
+    /// The interfaces the object implements
+    pub interfaces: Vec<Interface>,
+    /// The child objects
+    pub nodes: Vec<Node>
+}
+
+impl Node {
+    /// The interface called `name`.
+    pub fn interface(&self, name: &str) -> Option<&Interface> {
+        self.interfaces.iter().find(|i| i.name == name)
+    }
+}
+
+/// An interface and its members.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Interface {
+    /// e.g. "org.freedesktop.systemd1.Manager"
+    pub name: String,
+    /// The methods
+    pub methods: Vec<Method>,
+    /// The signals
+    pub signals: Vec<Signal>,
+    /// The properties
+    pub properties: Vec<Property>
+}
+
+/// A method.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Method {
+    /// The method name, e.g. "GetUnit"
+    pub name: String,
+    /// The in and out arguments, in order
+    pub args: Vec<Arg>
+}
+
+impl Method {
+    /// The in arguments.
+    pub fn inputs(&self) -> Vec<&Arg> {
+        self.args.iter().filter(|a| a.direction == Direction::In).collect()
+    }
+
+    /// The out arguments.
+    pub fn outputs(&self) -> Vec<&Arg> {
+        self.args.iter().filter(|a| a.direction == Direction::Out).collect()
+    }
+}
+
+/// A signal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signal {
+    /// The signal name, e.g. "UnitNew"
+    pub name: String,
+    /// The arguments, in order
+    pub args: Vec<Arg>
+}
+
+/// Whether an argument is passed to or returned from a method.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Passed to the method; the default for methods
+    In,
+    /// Returned by the method; always so for signals
+    Out
+}
+
+/// An argument of a method or signal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Arg {
+    /// The argument name, if given
+    pub name: Option<String>,
+    /// The type signature, e.g. "a(sss)"
+    pub signature: String,
+    /// The direction
+    pub direction: Direction
+}
+
+/// Whether a property can be read, written or both.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// read
+    Read,
+    /// write
+    Write,
+    /// readwrite
+    ReadWrite
+}
+
+/// A property.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Property {
+    /// The property name, e.g. "Version"
+    pub name: String,
+    /// The type signature
+    pub signature: String,
+    /// The access
+    pub access: Access
+}
+
+/// An XML element, before it is interpreted.
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|a| a.0 == name).map(|a| &a.1[..])
+    }
+
+    fn required(&self, name: &str) -> Result<String, IntrospectError> {
+        self.attr(name).map(|s| s.to_string()).ok_or_else(|| {
+            IntrospectError::MissingAttribute(self.name.clone(), name.to_string())
+        })
+    }
+
+    fn children(&self, name: &str) -> Vec<&Element> {
+        self.children.iter().filter(|c| c.name == name).collect()
+    }
+}
+
+/// Parse introspection XML.
+pub fn parse(xml: &str) -> Result<Node, IntrospectError> {
+    let root = parse_xml(xml)?;
+    if root.name != "node" {
+        return Err(IntrospectError::Syntax(
+            format!("expected <node>, found <{}>", root.name)))
+    }
+    node(&root)
+}
+
+fn node(e: &Element) -> Result<Node, IntrospectError> {
+    let mut interfaces = vec![];
+    for i in e.children("interface").into_iter() {
+        interfaces.push(interface(i)?);
+    }
+    let mut nodes = vec![];
+    for n in e.children("node").into_iter() {
+        nodes.push(node(n)?);
+    }
+    Ok(Node{
+        name: e.attr("name").map(|s| s.to_string()),
+        interfaces,
+        nodes
+    })
+}
+
+fn interface(e: &Element) -> Result<Interface, IntrospectError> {
+    let mut methods = vec![];
+    for m in e.children("method").into_iter() {
+        methods.push(Method{
+            name: m.required("name")?,
+            args: args(m, Direction::In)?
+        });
+    }
+    let mut signals = vec![];
+    for s in e.children("signal").into_iter() {
+        signals.push(Signal{
+            name: s.required("name")?,
+            args: args(s, Direction::Out)?
+        });
+    }
+    let mut properties = vec![];
+    for p in e.children("property").into_iter() {
+        let access = match &p.required("access")?[..] {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            "readwrite" => Access::ReadWrite,
+            other => return Err(IntrospectError::Syntax(
+                format!("invalid access {}", other)))
+        };
+        properties.push(Property{
+            name: p.required("name")?,
+            signature: p.required("type")?,
+            access
+        });
+    }
+    Ok(Interface{
+        name: e.required("name")?,
+        methods,
+        signals,
+        properties
+    })
+}
+
+fn args(e: &Element, default: Direction) -> Result<Vec<Arg>, IntrospectError> {
+    let mut args = vec![];
+    for a in e.children("arg").into_iter() {
+        let direction = match a.attr("direction") {
+            Some("in") => Direction::In,
+            Some("out") => Direction::Out,
+            None => default,
+            Some(other) => return Err(IntrospectError::Syntax(
+                format!("invalid direction {}", other)))
+        };
+        args.push(Arg{
+            name: a.attr("name").map(|s| s.to_string()),
+            signature: a.required("type")?,
+            direction
+        });
+    }
+    Ok(args)
+}
+
+/// Parse the element structure of `xml`, ignoring text, comments,
+/// the XML declaration and the doctype.
+fn parse_xml(xml: &str) -> Result<Element, IntrospectError> {
+    let syntax = |s: &str| IntrospectError::Syntax(s.to_string());
+    let mut stack: Vec<Element> = vec![];
+    let mut root = None;
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let skip = if rest.starts_with("<!--") {
+            Some("-->")
+        } else if rest.starts_with("<?") {
+            Some("?>")
+        } else if rest.starts_with("<!") {
+            Some(">")
+        } else {
+            None
+        };
+        if let Some(end) = skip {
+            let i = rest.find(end).ok_or_else(|| syntax("unterminated tag"))?;
+            rest = &rest[i + end.len()..];
+            continue
+        }
+        let end = tag_end(rest).ok_or_else(|| syntax("unterminated tag"))?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim();
+            let element = stack.pop().ok_or_else(|| syntax("unexpected end tag"))?;
+            if element.name != name {
+                return Err(IntrospectError::Syntax(
+                    format!("</{}> closes <{}>", name, element.name)))
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element)
+            }
+            continue
+        }
+        let empty = tag.ends_with("/");
+        let tag = if empty { &tag[..tag.len() - 1] } else { tag };
+        let element = start_tag(tag)?;
+        if !empty {
+            stack.push(element);
+        } else {
+            match stack.last_mut() {
+                Some(parent) => parent.children.push(element),
+                None => root = Some(element)
+            }
+        }
+    }
+    if !stack.is_empty() {
+        return Err(syntax("unclosed element"))
+    }
+    root.ok_or_else(|| syntax("no root element"))
+}
+
+/// The index of the '>' that ends the tag starting `s`, skipping
+/// quoted attribute values.
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, '>') => return Some(i),
+            _ => ()
+        }
+    }
+    None
+}
+
+/// Parse the name and attributes of a start tag, without the brackets.
+fn start_tag(tag: &str) -> Result<Element, IntrospectError> {
+    let syntax = || IntrospectError::Syntax(format!("invalid tag <{}>", tag));
+    let tag = tag.trim();
+    let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+    let name = &tag[..name_end];
+    if name.is_empty() {
+        return Err(syntax())
+    }
+    let mut attrs = vec![];
+    let mut rest = tag[name_end..].trim_start();
+    while !rest.is_empty() {
+        let eq = rest.find('=').ok_or_else(&syntax)?;
+        let key = rest[..eq].trim();
+        let value = rest[eq + 1..].trim_start();
+        let quote = value.chars().next().ok_or_else(&syntax)?;
+        if quote != '"' && quote != '\'' {
+            return Err(syntax())
+        }
+        let close = value[1..].find(quote).ok_or_else(&syntax)?;
+        attrs.push((key.to_string(), unescape(&value[1..close + 1])));
+        rest = value[close + 2..].trim_start();
+    }
+    Ok(Element{ name: name.to_string(), attrs, children: vec![] })
+}
+
+/// Replace the predefined XML entities.
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Convert a DBus member name to a Rust identifier, e.g. "GetUnitByPID"
+/// to "get_unit_by_pid".  systemd's time units, as in "TimeoutUSec",
+/// are kept whole.
+pub fn snake_case(name: &str) -> String {
+    let name = name.replace("USec", "Usec").replace("NSec", "Nsec");
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let previous = chars[i - 1];
+            let next_lower = chars.get(i + 1).map(|n| n.is_lowercase())
+                .unwrap_or(false);
+            if previous.is_lowercase() || previous.is_numeric() ||
+                (previous.is_uppercase() && next_lower) {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    if KEYWORDS.contains(&&snake[..]) {
+        snake.push('_');
+    }
+    snake
+}
+
+static KEYWORDS: [&str; 38] = [
+    "abstract", "as", "box", "break", "const", "continue", "crate", "do",
+    "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl",
+    "in", "let", "loop", "match", "mod", "move", "mut", "override", "priv",
+    "pub", "ref", "return", "self", "static", "struct", "super", "trait",
+    "true", "type", "use", "where"];
+
+/// Split a signature into its complete types.
+fn split_signature(signature: &str) -> Option<Vec<&str>> {
+    let bytes = signature.as_bytes();
+    let mut types = vec![];
+    let mut start = 0;
+    while start < bytes.len() {
+        let mut i = start;
+        while i < bytes.len() && bytes[i] == b'a' {
+            i += 1;
+        }
+        if i == bytes.len() {
+            return None
+        }
+        let close = match bytes[i] {
+            b'(' => Some((b'(', b')')),
+            b'{' => Some((b'{', b'}')),
+            _ => None
+        };
+        if let Some((open, close)) = close {
+            let mut depth = 0;
+            loop {
+                if i == bytes.len() {
+                    return None
+                }
+                if bytes[i] == open {
+                    depth += 1;
+                } else if bytes[i] == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        break
+                    }
+                }
+                i += 1;
+            }
+        }
+        types.push(&signature[start..i + 1]);
+        start = i + 1;
+    }
+    Some(types)
+}
+
+/// The Rust type a value of `signature` is decoded to, or None if the
+/// decoder does not support it.
+pub fn rust_type(signature: &str) -> Option<String> {
+    let basic = match signature {
+        "y" => Some("u8"),
+        "b" => Some("bool"),
+        "n" => Some("i16"),
+        "q" => Some("u16"),
+        "i" => Some("i32"),
+        "u" => Some("u32"),
+        "x" => Some("i64"),
+        "t" => Some("u64"),
+        "d" => Some("f64"),
+        "s" => Some("String"),
+        "o" => Some("ObjectPath"),
+        "g" => Some("Signature"),
+        _ => None
+    };
+    if let Some(t) = basic {
+        return Some(t.to_string())
+    }
+    if signature.starts_with("a{") {
+        None
+    } else if let Some(element) = signature.strip_prefix('a') {
+        rust_type(element).map(|t| format!("Vec<{}>", t))
+    } else if signature.starts_with("(") && signature.ends_with(")") {
+        tuple_type(&signature[1..signature.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// The Rust tuple type for the complete types in `signature`.
+fn tuple_type(signature: &str) -> Option<String> {
+    let types = match split_signature(signature) {
+        Some(ref types) if !types.is_empty() && types.len() <= 12 => types.clone(),
+        _ => return None
+    };
+    let mut rust = vec![];
+    for t in types.iter() {
+        match rust_type(t) {
+            Some(t) => rust.push(t),
+            None => return None
+        }
+    }
+    if rust.len() == 1 {
+        Some(format!("({},)", rust[0]))
+    } else {
+        Some(format!("({})", rust.join(", ")))
+    }
+}
+
+/// The Rust type of a method argument of `signature`, which the
+/// encoder encodes back to `signature`, or None if it can not.
+pub fn argument_type(signature: &str) -> Option<String> {
+    match signature {
+        "s" => Some("&str".to_string()),
+        "o" => Some("&ObjectPath".to_string()),
+        "g" => Some("&Signature".to_string()),
+        "as" => Some("&[&str]".to_string()),
+        _ if signature.len() == 1 => rust_type(signature),
+        _ if signature.len() == 2 && signature.starts_with("a") =>
+            rust_type(&signature[1..]).map(|t| format!("&[{}]", t)),
+        _ => None
+    }
+}
+
+fn arg_name(arg: &Arg, i: usize) -> String {
+    match arg.name {
+        Some(ref name) if !name.is_empty() => snake_case(name),
+        _ => format!("arg{}", i)
+    }
+}
+
+/// Generate Rust for `interface` of the object `bus` calls: a
+/// `systemd_dbus!` wrapper for each method not in `skip`, a
+/// `systemd_property!` getter for each readable property and a struct
+/// for each signal.  Members with types the encoder or decoder does
+/// not support are listed in comments.
+pub fn generate(interface: &Interface, bus: &str, skip: &[String]) -> String {
+    let mut out = format!("// Generated from {}\n\n", interface.name);
+    let methods: Vec<&Method> = interface.methods.iter()
+        .filter(|m| !skip.contains(&m.name))
+        .collect();
+    out.push_str(&members(interface, &methods, "", |method, wrapper| {
+        format!("systemd_dbus!({}, \"{}\",\n              {});\n",
+                bus, method, wrapper)
+    }, |property, getter| {
+        format!("systemd_property!(\"{}\", \"{}\",\n                  {});\n",
+                interface.name, property, getter)
+    }));
+    for signal in interface.signals.iter() {
+        out.push_str(&signal_struct(interface, signal));
+    }
+    out
+}
+
+/// Generate Rust for `interface` of the objects that the `proxy` type
+/// calls, e.g. a service's `unit::Unit`: an `object_dbus!` wrapper for
+/// each method and an `object_property!` getter for each readable
+/// property, named with `prefix`.  Methods whose name is in `skip`,
+/// because `proxy` already has a method of that name, are left out.
+pub fn generate_object(interface: &Interface, proxy: &str, prefix: &str,
+                       skip: &[String]) -> String {
+    let mut out = format!("// Generated from {}\n\n", interface.name);
+    let methods: Vec<&Method> = interface.methods.iter()
+        .filter(|m| !skip.contains(&member_name(prefix, &m.name)))
+        .collect();
+    out.push_str(&members(interface, &methods, prefix, |method, wrapper| {
+        format!("object_dbus!({}, \"{}\", \"{}\",\n             {});\n",
+                proxy, interface.name, method, wrapper)
+    }, |property, getter| {
+        format!("object_property!({}, \"{}\", \"{}\",\n                 {});\n",
+                proxy, interface.name, property, getter)
+    }));
+    out
+}
+
+/// The Rust name of member `name`, after `prefix`.
+fn member_name(prefix: &str, name: &str) -> String {
+    let name = snake_case(name);
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}{}", prefix, name.trim_end_matches('_'))
+    }
+}
+
+/// The wrappers of `methods`, and the getters of the readable
+/// properties of `interface`, formatted by `method` and `property`
+/// from the member's DBus name and its Rust signature.
+fn members<M, P>(interface: &Interface, methods: &[&Method], prefix: &str,
+                 method: M, property: P) -> String
+    where M: Fn(&str, &str) -> String, P: Fn(&str, &str) -> String
+{
+    let mut out = String::new();
+    for m in methods.iter() {
+        let mut inputs = vec![];
+        for (i, arg) in m.inputs().into_iter().enumerate() {
+            match argument_type(&arg.signature) {
+                Some(t) => inputs.push(format!("{}: {}", arg_name(arg, i), t)),
+                None => break
+            }
+        }
+        let outputs: Vec<Option<String>> = m.outputs().into_iter()
+            .map(|a| rust_type(&a.signature))
+            .collect();
+        if inputs.len() != m.inputs().len() ||
+            outputs.iter().any(|o| o.is_none()) {
+            out.push_str(&format!("// Unsupported method {}({}) -> ({})\n",
+                                  m.name, signature(&m.inputs()),
+                                  signature(&m.outputs())));
+            continue
+        }
+        let outputs: Vec<String> = outputs.into_iter().map(|o| o.unwrap()).collect();
+        let ret = match outputs.len() {
+            0 => String::new(),
+            1 => format!(" -> {}", outputs[0]),
+            _ => format!(" -> ({})", outputs.join(", "))
+        };
+        out.push_str(&method(&m.name, &format!("{}({}){}",
+                                               member_name(prefix, &m.name),
+                                               inputs.join(", "), ret)));
+    }
+    out.push('\n');
+    for p in interface.properties.iter() {
+        if p.access == Access::Write {
+            continue
+        }
+        match rust_type(&p.signature) {
+            Some(t) => out.push_str(&property(&p.name, &format!(
+                "{}_property -> {}", member_name(prefix, &p.name), t))),
+            None => out.push_str(&format!("// Unsupported property {} {}\n",
+                                          p.name, p.signature))
+        }
+    }
+    out
+}
+
+fn signature(args: &[&Arg]) -> String {
+    args.iter().map(|a| &a.signature[..]).collect::<Vec<_>>().concat()
+}
+
+/// A struct for the arguments of `signal`, decoded by its
+/// `from_signal` method.
+fn signal_struct(interface: &Interface, signal: &Signal) -> String {
+    let mut fields = vec![];
+    let mut names = vec![];
+    for (i, arg) in signal.args.iter().enumerate() {
+        match rust_type(&arg.signature) {
+            Some(t) => {
+                fields.push(format!("    /// The {} argument\n    pub {}: {}",
+                                    arg.name.as_ref().map(|s| &s[..])
+                                    .unwrap_or("Argument"),
+                                    arg_name(arg, i), t));
+                names.push(arg_name(arg, i));
+            },
+            None => return format!("\n// Unsupported signal {}({})\n", signal.name,
+                                   signature(&signal.args.iter().collect::<Vec<_>>()))
+        }
+    }
+    let name = format!("{}Signal", signal.name);
+    let body = if fields.is_empty() {
+        ";".to_string()
+    } else {
+        format!(" {{\n{}\n}}\ndecodable_struct!({} {{ {} }});",
+                fields.join(",\n"), name, names.join(", "))
+    };
+    let decode = if fields.is_empty() {
+        format!("Ok(Some({}))", name)
+    } else {
+        "Ok(Some(decode(vec![dbus::arg::messageitem::MessageItem::Struct(signal.items.clone())])?))"
+            .to_string()
+    };
+    format!("
+/// The {member} signal of {interface}.
+#[derive(Clone, Debug, PartialEq)]
+pub struct {name}{body}
+
+impl {name} {{
+    /// Decode `signal`, or return None if it is another signal.
+    pub fn from_signal(signal: &Signal) -> SystemdResult<Option<{name}>> {{
+        if signal.interface.as_ref().map(|s| &s[..]) != Some(\"{interface}\") ||
+            signal.member.as_ref().map(|s| &s[..]) != Some(\"{member}\") {{
+            return Ok(None)
+        }}
+        {decode}
+    }}
+}}
+", member = signal.name, interface = interface.name, name = name, body = body,
+            decode = decode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static XML: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+ <!-- the manager -->
+ <interface name="org.freedesktop.systemd1.Manager">
+  <method name="GetUnit">
+   <arg type="s" name="name" direction="in"/>
+   <arg type="o" name="unit" direction="out"/>
+  </method>
+  <method name="KillUnit">
+   <arg type="s" name="name" direction="in"/>
+   <arg type="s" name="whom" direction="in"/>
+   <arg type="i" name="signal" direction="in"/>
+  </method>
+  <method name="GetUnitProcesses">
+   <arg type="s" name="name" direction="in"/>
+   <arg type="a(sus)" name="processes" direction="out"/>
+  </method>
+  <method name="StartTransientUnit">
+   <arg type="s" name="name" direction="in"/>
+   <arg type="s" name="mode" direction="in"/>
+   <arg type="a(sv)" name="properties" direction="in"/>
+   <arg type="a(sa(sv))" name="aux" direction="in"/>
+   <arg type="o" name="job" direction="out"/>
+  </method>
+  <property name="Version" type="s" access="read">
+   <annotation name="org.freedesktop.DBus.Property.EmitsChangedSignal" value="const"/>
+  </property>
+  <property name="Environment" type="as" access="read"/>
+  <signal name="UnitNew">
+   <arg type="s" name="id"/>
+   <arg type="o" name="unit"/>
+  </signal>
+  <signal name="UnitFilesChanged"/>
+ </interface>
+ <node name="unit"/>
+</node>
+"#;
+
+    #[test]
+    fn parse_interface() {
+        let node = parse(XML).unwrap();
+        assert_eq!(None, node.name);
+        assert_eq!(Some("unit".to_string()), node.nodes[0].name);
+        let manager = node.interface("org.freedesktop.systemd1.Manager").unwrap();
+        assert_eq!(4, manager.methods.len());
+        assert_eq!("o", manager.methods[0].outputs()[0].signature);
+        assert_eq!(Access::Read, manager.properties[0].access);
+        assert_eq!(Direction::Out, manager.signals[0].args[1].direction);
+        assert!(parse("<node><interface name='x'></node>").is_err());
+        assert!(parse("<node><method/></node>").is_ok());
+    }
+
+    #[test]
+    fn names_and_types() {
+        assert_eq!("get_unit_by_pid", snake_case("GetUnitByPID"));
+        assert_eq!("k_exec", snake_case("KExec"));
+        assert_eq!("n_failed_units", snake_case("NFailedUnits"));
+        assert_eq!("type_", snake_case("Type"));
+        assert_eq!("runtime_watchdog_usec", snake_case("RuntimeWatchdogUSec"));
+        assert_eq!(Some("Vec<(String, u32, String)>".to_string()),
+                   rust_type("a(sus)"));
+        assert_eq!(None, rust_type("a{sv}"));
+        assert_eq!(Some("&[u8]".to_string()), argument_type("ay"));
+        assert_eq!(None, argument_type("a(sv)"));
+    }
+
+    #[test]
+    fn generate_interface() {
+        let node = parse(XML).unwrap();
+        let code = generate(&node.interfaces[0], "bus", &["GetUnit".to_string()]);
+        assert!(!code.contains("\"GetUnit\""));
+        assert!(code.contains("systemd_dbus!(bus, \"KillUnit\",\n              \
+                               kill_unit(name: &str, whom: &str, signal: i32));"));
+        assert!(code.contains("get_unit_processes(name: &str) \
+                               -> Vec<(String, u32, String)>);"));
+        assert!(code.contains("// Unsupported method StartTransientUnit(ssa(sv)a(sa(sv))) -> (o)"));
+        assert!(code.contains("environment_property -> Vec<String>);"));
+        assert!(code.contains("pub struct UnitNewSignal {\n    /// The id argument\n    pub id: String,"));
+        assert!(code.contains("pub struct UnitFilesChangedSignal;"));
+    }
+
+    #[test]
+    fn generate_object_interface() {
+        let node = parse(XML).unwrap();
+        let code = generate_object(&node.interfaces[0], "unit::Unit", "service_",
+                                   &["service_get_unit".to_string()]);
+        assert!(!code.contains("\"GetUnit\""));
+        assert!(code.contains("object_dbus!(unit::Unit, \
+                               \"org.freedesktop.systemd1.Manager\", \"KillUnit\",\n             \
+                               service_kill_unit(name: &str, whom: &str, signal: i32));"));
+        assert!(code.contains("service_version_property -> String);"));
+        assert!(!code.contains("UnitNewSignal"));
+        assert_eq!("service_type", member_name("service_", "Type"));
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A proxy for one job's object.
+//!
+//! The manager returns the object path of the job it queues for a
+//! unit, e.g. from `start_unit`.  `Connection::job_object` calls that
+//! object directly, until the job finishes and its object goes away.
+
+use dbus::arg::messageitem::MessageItem;
+
+use serialize::decode;
+use {Connection, ObjectPath, SystemdResult};
+
+static JOB_INTERFACE : &str = "org.freedesktop.systemd1.Job";
+
+/// A job, called through its own object rather than the manager.
+pub struct JobObject<'c> {
+    conn: &'c Connection,
+    path: ObjectPath
+}
+
+impl Connection {
+    /// A proxy for the job at `path`.
+    pub fn job_object(&self, path: &ObjectPath) -> JobObject<'_> {
+        JobObject{ conn: self, path: path.clone() }
+    }
+}
+
+impl<'c> JobObject<'c> {
+    /// The job's object path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The connection the job is called through.
+    pub fn connection(&self) -> &'c Connection {
+        self.conn
+    }
+
+    /// Return property `name` of the Job interface.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_property(&self.path, JOB_INTERFACE, name)
+    }
+
+    /// The job's state, "waiting" or "running".
+    pub fn state(&self) -> SystemdResult<String> {
+        Ok(decode(vec![self.property("State")?])?)
+    }
+
+    /// Cancel the job.
+    pub fn cancel(&self) -> SystemdResult<()> {
+        self.conn.call_on(&self.conn.bus, &self.path, JOB_INTERFACE, "Cancel",
+                          &[], |_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mock::MockSystemd;
+    use Connection;
+
+    #[test]
+    fn job_object_test() {
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("app.service", "App", "inactive", "dead");
+        let id = mock.add_job("app.service", "start", "waiting");
+        let conn = Connection::open(mock.bus()).unwrap();
+        let path = conn.get_job(id).unwrap();
+        let job = conn.job_object(&path);
+        assert_eq!("waiting", job.state().unwrap());
+        job.cancel().unwrap();
+        assert!(conn.list_jobs().unwrap().is_empty());
+        assert!(job.state().is_err());
+    }
+}
@@ -13,7 +13,9 @@
 // limitations under the License.
 
 // #![deny(missing_docs)] can not do this yet due to macro issues
-#![feature(core, libc, std_misc)]
+
+// The wide call helpers predate clippy.
+#![allow(clippy::too_many_arguments)]
 
 //! Rust API for Systemd commands via DBus.
 //!
@@ -22,19 +24,179 @@
 //!   http://dbus.freedesktop.org/doc/dbus-specification.html
 
 extern crate libc;
-extern crate "rustc-serialize" as rustc_serialize;
-extern crate "dbus-rs" as dbus;
+#[macro_use] extern crate bitflags;
+extern crate rustc_serialize;
+extern crate dbus;
 
 use std::{error,fmt};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::convert::Infallible;
 use std::fmt::Display;
-use std::slice::SliceConcatExt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+use dbus::arg::messageitem::MessageItem;
 use serialize::{decode};
+use trace::{CallSpan, Tracer};
+
+#[macro_use] pub mod serialize;
+pub mod trace;
+pub mod dispatch;
+pub mod match_rule;
+pub mod watch;
+pub mod unitfile;
+pub mod units;
+pub mod dropin;
+pub mod unit_name;
+pub mod unit;
+pub mod job;
+pub mod introspect;
+pub mod reload;
+pub mod run;
+pub mod login1;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+pub use dispatch::{AsyncConnection, ConnectionEvent, Events, Pending,
+                   SharedConnection, Signals};
+pub use match_rule::MatchRule;
+pub use serialize::UnixFd;
+pub use unit::Unit;
+pub use unit_name::{UnitName, UnitType};
+
+
+/// A DBus object path, e.g. "/org/freedesktop/systemd1".  Encoded as
+/// an object path ('o') rather than a string, and only decoded from
+/// one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ObjectPath(String);
+
+impl ObjectPath {
+    /// Validate `path`.
+    pub fn new(path: &str) -> SystemdResult<ObjectPath> {
+        if !match_rule::is_object_path(path) {
+            return Err(SystemdError::InvalidArg(
+                format!("invalid object path {}", path)))
+        }
+        Ok(ObjectPath(path.to_string()))
+    }
+
+    /// The path as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for ObjectPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for ObjectPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PartialEq<str> for ObjectPath {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for ObjectPath {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<ObjectPath> for &str {
+    fn eq(&self, other: &ObjectPath) -> bool {
+        *self == other.0
+    }
+}
+
+impl rustc_serialize::Decodable for ObjectPath {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<ObjectPath, D::Error> {
+        let path = d.read_struct(serialize::OBJECT_PATH_STRUCT, 1, |d| {
+            d.read_struct_field("path", 0, |d| d.read_str())
+        })?;
+        ObjectPath::new(&path).map_err(|e| d.error(&e.to_string()))
+    }
+}
+
+impl rustc_serialize::Encodable for ObjectPath {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_struct(serialize::OBJECT_PATH_STRUCT, 1, |s| {
+            s.emit_struct_field("path", 0, |s| s.emit_str(&self.0))
+        })
+    }
+}
+
+type_signature!(ObjectPath => "o");
+
+/// A DBus type signature, e.g. "a(sss)".  Encoded as a signature
+/// ('g') rather than a string, and only decoded from one.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Signature(String);
+
+impl Signature {
+    /// Validate `signature`.
+    pub fn new(signature: &str) -> SystemdResult<Signature> {
+        if !serialize::is_signature(signature) {
+            return Err(SystemdError::InvalidArg(
+                format!("invalid signature {}", signature)))
+        }
+        Ok(Signature(signature.to_string()))
+    }
+
+    /// The signature as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Signature {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.0)
+    }
+}
 
-pub mod serialize;
+impl rustc_serialize::Decodable for Signature {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<Signature, D::Error> {
+        let signature = d.read_struct(serialize::SIGNATURE_STRUCT, 1, |d| {
+            d.read_struct_field("signature", 0, |d| d.read_str())
+        })?;
+        Signature::new(&signature).map_err(|e| d.error(&e.to_string()))
+    }
+}
 
+impl rustc_serialize::Encodable for Signature {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_struct(serialize::SIGNATURE_STRUCT, 1, |s| {
+            s.emit_struct_field("signature", 0, |s| s.emit_str(&self.0))
+        })
+    }
+}
 
-/// Define an ObjectPath as a String
-pub type ObjectPath = String;
+type_signature!(Signature => "g");
 
 /// Errors that can arise on systemd DBus operations.
 #[derive(Debug)]
@@ -47,6 +209,18 @@ pub enum SystemdError{
     EncoderError(serialize::EncoderError),
     /// Invalid arg passed to a DBus operation.
     InvalidArg(String),
+    /// No reply was received within the call's timeout.
+    Timeout,
+    /// The bus reported that no reply will arrive, e.g. because the
+    /// callee left the bus before replying.
+    NoReply,
+    /// The connection to the bus was lost.
+    Disconnected,
+    /// The named unit entered the failed state.
+    UnitFailed(String),
+    /// The job for the named unit finished with the given result, e.g.
+    /// "failed" or "dependency", rather than "done".
+    JobFailed(String, String),
     /// Catch all error type
     UnspecifiedError(String)
 }
@@ -63,26 +237,64 @@ impl error::Error for SystemdError {
     }
 }
 
-impl error::FromError<dbus::Error> for SystemdError {
-    fn from_error(err: dbus::Error) -> SystemdError {
-        SystemdError::BusError(err)
+impl From<dbus::Error> for SystemdError {
+    fn from(err: dbus::Error) -> SystemdError {
+        match err.name() {
+            Some("org.freedesktop.DBus.Error.NoReply") =>
+                SystemdError::NoReply,
+            Some("org.freedesktop.DBus.Error.Timeout") |
+            Some("org.freedesktop.DBus.Error.TimedOut") =>
+                SystemdError::Timeout,
+            Some("org.freedesktop.DBus.Error.Disconnected") =>
+                SystemdError::Disconnected,
+            _ => SystemdError::BusError(err)
+        }
     }
 }
 
-impl error::FromError<serialize::DecoderError> for SystemdError {
-    fn from_error(err: serialize::DecoderError) -> SystemdError {
+impl From<serialize::DecoderError> for SystemdError {
+    fn from(err: serialize::DecoderError) -> SystemdError {
         SystemdError::DecoderError(err)
     }
 }
 
-impl error::FromError<serialize::EncoderError> for SystemdError {
-    fn from_error(err: serialize::EncoderError) -> SystemdError {
+impl From<serialize::EncoderError> for SystemdError {
+    fn from(err: serialize::EncoderError) -> SystemdError {
         SystemdError::EncoderError(err)
     }
 }
 
-impl error::FromError<()> for SystemdError {
-    fn from_error(_: ()) -> SystemdError {
+impl From<match_rule::MatchRuleError> for SystemdError {
+    fn from(err: match_rule::MatchRuleError) -> SystemdError {
+        SystemdError::InvalidArg(err.to_string())
+    }
+}
+
+impl From<unitfile::UnitFileError> for SystemdError {
+    fn from(err: unitfile::UnitFileError) -> SystemdError {
+        match err {
+            unitfile::UnitFileError::Io(e) =>
+                SystemdError::UnspecifiedError(e.to_string()),
+            err => SystemdError::InvalidArg(err.to_string())
+        }
+    }
+}
+
+impl From<unit_name::UnitNameError> for SystemdError {
+    fn from(err: unit_name::UnitNameError) -> SystemdError {
+        SystemdError::InvalidArg(err.to_string())
+    }
+}
+
+impl From<introspect::IntrospectError> for SystemdError {
+    fn from(err: introspect::IntrospectError) -> SystemdError {
+        SystemdError::DecoderError(serialize::DecoderError::ApplicationError(
+            err.to_string()))
+    }
+}
+
+impl From<()> for SystemdError {
+    fn from(_: ()) -> SystemdError {
         SystemdError::UnspecifiedError(
             "while converting from () error type".to_string())
     }
@@ -91,6 +303,137 @@ impl error::FromError<()> for SystemdError {
 /// Result type for systemd DBus errors.
 pub type SystemdResult<T> = Result<T,SystemdError>;
 
+/// Timeout used for DBus calls, unless set on the connection.
+pub const DEFAULT_TIMEOUT_MS: u32 = 2000;
+
+/// Delays between attempts to reconnect to the bus.  Each delay is
+/// double the previous one, up to `max`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt
+    pub initial: Duration,
+    /// Longest delay between attempts
+    pub max: Duration,
+    /// Give up after this many attempts, or never if None
+    pub attempts: Option<u32>
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff{
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            attempts: None
+        }
+    }
+}
+
+impl Backoff {
+    /// Return the sequence of delays to wait before each attempt.
+    pub fn delays(&self) -> BackoffDelays {
+        BackoffDelays{ next: self.initial, max: self.max,
+                       remaining: self.attempts }
+    }
+}
+
+/// Iterator over the delays of a Backoff.
+#[derive(Clone, Debug)]
+pub struct BackoffDelays {
+    next: Duration,
+    max: Duration,
+    remaining: Option<u32>
+}
+
+impl Iterator for BackoffDelays {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        match self.remaining {
+            Some(0) => return None,
+            Some(ref mut n) => *n -= 1,
+            None => ()
+        }
+        let delay = self.next;
+        self.next = delay.checked_mul(2)
+            .map_or(self.max, |next| next.min(self.max));
+        Some(delay)
+    }
+}
+
+/// The bus on which to talk to systemd.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bus {
+    /// The system bus, for the system manager
+    System,
+    /// The session bus, for a user's manager
+    Session,
+    /// A bus at an explicit address, e.g. a private dbus-daemon
+    Address(String)
+}
+
+impl Bus {
+    /// Open a new private connection to the bus.
+    fn connect(&self) -> SystemdResult<dbus::ffidisp::Connection> {
+        use dbus::ffidisp::{BusType, Connection};
+        Ok(match *self {
+            Bus::System => Connection::get_private(BusType::System)?,
+            Bus::Session => Connection::get_private(BusType::Session)?,
+            Bus::Address(ref address) => {
+                let conn = Connection::open_private(address)?;
+                conn.register()?;
+                conn
+            }
+        })
+    }
+}
+
+/// Open a private connection to `bus`, retrying with `backoff`.
+fn connect_with_backoff(bus: &Bus, backoff: &Backoff)
+                        -> SystemdResult<dbus::ffidisp::Connection> {
+    let mut last = SystemdError::Disconnected;
+    for delay in backoff.delays() {
+        thread::sleep(delay);
+        match bus.connect() {
+            Ok(conn) => return Ok(conn),
+            Err(e) => last = e
+        }
+    }
+    Err(last)
+}
+
+/// Re-add match `rules` on a new connection, and re-subscribe to
+/// systemd signals if `subscribed`.
+fn restore_subscriptions(conn: &dbus::ffidisp::Connection, rules: &[String],
+                         subscribed: bool) -> SystemdResult<()> {
+    for rule in rules.iter() {
+        let mut m = DBUS.method("AddMatch").unwrap();
+        m.append_items(&[MessageItem::Str(rule.clone())]);
+        conn.send(m)?;
+    }
+    if subscribed {
+        conn.send(SYSTEMD.method("Subscribe").unwrap())?;
+    }
+    Ok(())
+}
+
+/// Whether calling `method` on the systemd manager changes the
+/// subscription state, and if so to what.
+fn subscription_change(method: &str) -> Option<bool> {
+    match method {
+        "Subscribe" => Some(true),
+        "Unsubscribe" => Some(false),
+        _ => None
+    }
+}
+
+/// Convert a Duration to the millisecond timeout used by libdbus.
+fn timeout_ms(timeout: Duration) -> i32 {
+    let ms = timeout.as_secs()
+        .saturating_mul(1000)
+        .saturating_add(timeout.subsec_millis() as u64);
+    if ms > i32::MAX as u64 { i32::MAX } else { ms as i32 }
+}
+
 /// Remote object, on which methods can be called.
 #[derive(Debug)]
 struct Object {
@@ -99,9 +442,9 @@ struct Object {
     interface: &'static str,
 }
 
-static DBUS : &'static Object = &Object{
-    service:"org.freedesktop",
-    path: "/org/freedesktop",
+static DBUS : &Object = &Object{
+    service:"org.freedesktop.DBus",
+    path: "/org/freedesktop/DBus",
     interface:"org.freedesktop.DBus"};
 
 // static DBUS_PROPERTIES : &'static Object = &Object{
@@ -109,11 +452,41 @@ static DBUS : &'static Object = &Object{
 //     path: "/org/freedesktop/DBus",
 //     interface:"org.freedesktop.DBus.Properties"};
 
-static SYSTEMD : &'static Object = &Object{
+static SYSTEMD : &Object = &Object{
     service:"org.freedesktop.systemd1",
     path: "/org/freedesktop/systemd1",
     interface:"org.freedesktop.systemd1.Manager"};
 
+static SYSTEMD_PROPERTIES : &Object = &Object{
+    service:"org.freedesktop.systemd1",
+    path: "/org/freedesktop/systemd1",
+    interface:"org.freedesktop.DBus.Properties"};
+
+static PROPERTIES : &str = "org.freedesktop.DBus.Properties";
+static INTROSPECTABLE : &str = "org.freedesktop.DBus.Introspectable";
+
+/// Convert an `a{sv}` property dictionary to (name, value) pairs.
+fn property_dict(dict: MessageItem)
+                 -> SystemdResult<Vec<(String, MessageItem)>> {
+    let entries = match dict {
+        MessageItem::Dict(entries) => entries.into_vec(),
+        other => return Err(SystemdError::DecoderError(
+            serialize::DecoderError::ExpectedError(
+                "Dict".to_string(), format!("{:?}", other))))
+    };
+    let mut properties = vec![];
+    for entry in entries.into_iter() {
+        match entry {
+            (MessageItem::Str(name), MessageItem::Variant(value)) =>
+                properties.push((name, *value)),
+            (k, v) => return Err(SystemdError::DecoderError(
+                serialize::DecoderError::ExpectedError(
+                    "{sv}".to_string(), format!("{:?} {:?}", k, v))))
+        }
+    }
+    Ok(properties)
+}
+
 impl Object {
     fn method(&self, method: &str) -> Option<dbus::Message> {
         let m = dbus::Message::new_method_call(self.service, self.path,
@@ -123,7 +496,7 @@ impl Object {
 }
 
 /// Unit status returned from systemd.
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug)]
 pub struct UnitStatus {
     name: String,
     description: String,
@@ -136,10 +509,16 @@ pub struct UnitStatus {
     job_type: String,
     job_path: ObjectPath
 }
+decodable_struct!(UnitStatus { name, description, load_state, active_state,
+                               sub_state, followed, path, job_id, job_type,
+                               job_path });
+encodable_struct!(UnitStatus { name, description, load_state, active_state,
+                               sub_state, followed, path, job_id, job_type,
+                               job_path });
 
 
 /// Systemd Job information
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug)]
 pub struct Job {
     job_id: u32, // The numeric job id
     name: String, // The primary unit name for this job
@@ -148,45 +527,373 @@ pub struct Job {
     job_path: ObjectPath, // The job object path
     path: ObjectPath, // The unit object path
 }
+decodable_struct!(Job { job_id, name, job_type, job_state, job_path, path });
+encodable_struct!(Job { job_id, name, job_type, job_state, job_path, path });
 
 /// Systemd unit file information
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug,Clone)]
 pub struct UnitFile {
-    name: String,
-    state: String
+    /// The path of the unit file
+    pub name: String,
+    /// The enablement state of the unit file
+    pub state: UnitFileState
+}
+decodable_struct!(UnitFile { name, state });
+encodable_struct!(UnitFile { name, state });
+
+/// The enablement state of a unit file, as shown by
+/// `systemctl is-enabled`.
+#[derive(Clone,Debug,PartialEq)]
+pub enum UnitFileState {
+    /// Enabled via symlinks in .wants/ or .requires/
+    Enabled,
+    /// Enabled via symlinks in /run, until the next reboot
+    EnabledRuntime,
+    /// Made available through a symlink to a file outside the search path
+    Linked,
+    /// Linked via /run, until the next reboot
+    LinkedRuntime,
+    /// An alias of another unit file
+    Alias,
+    /// Masked, so it can not be started
+    Masked,
+    /// Masked via /run, until the next reboot
+    MaskedRuntime,
+    /// Has no [Install] section, so can not be enabled
+    Static,
+    /// Can be enabled, but is not
+    Disabled,
+    /// Not a valid unit file
+    Invalid,
+    /// Enabled indirectly, through Also= or a template instance
+    Indirect,
+    /// Generated dynamically by a generator
+    Generated,
+    /// Created dynamically with the runtime API
+    Transient,
+    /// The unit file could not be read
+    Bad,
+    /// A state not known to this crate
+    Other(String)
+}
+
+impl<'a> From<&'a str> for UnitFileState {
+    /// Parse the string form used by systemd.
+    fn from(s: &'a str) -> UnitFileState {
+        match s {
+            "enabled" => UnitFileState::Enabled,
+            "enabled-runtime" => UnitFileState::EnabledRuntime,
+            "linked" => UnitFileState::Linked,
+            "linked-runtime" => UnitFileState::LinkedRuntime,
+            "alias" => UnitFileState::Alias,
+            "masked" => UnitFileState::Masked,
+            "masked-runtime" => UnitFileState::MaskedRuntime,
+            "static" => UnitFileState::Static,
+            "disabled" => UnitFileState::Disabled,
+            "invalid" => UnitFileState::Invalid,
+            "indirect" => UnitFileState::Indirect,
+            "generated" => UnitFileState::Generated,
+            "transient" => UnitFileState::Transient,
+            "bad" => UnitFileState::Bad,
+            other => UnitFileState::Other(other.to_string())
+        }
+    }
+}
+
+impl FromStr for UnitFileState {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<UnitFileState, Infallible> {
+        Ok(UnitFileState::from(s))
+    }
+}
+
+impl UnitFileState {
+    /// Return the string form used by systemd.
+    pub fn as_str(&self) -> &str {
+        match self {
+            &UnitFileState::Enabled => "enabled",
+            &UnitFileState::EnabledRuntime => "enabled-runtime",
+            &UnitFileState::Linked => "linked",
+            &UnitFileState::LinkedRuntime => "linked-runtime",
+            &UnitFileState::Alias => "alias",
+            &UnitFileState::Masked => "masked",
+            &UnitFileState::MaskedRuntime => "masked-runtime",
+            &UnitFileState::Static => "static",
+            &UnitFileState::Disabled => "disabled",
+            &UnitFileState::Invalid => "invalid",
+            &UnitFileState::Indirect => "indirect",
+            &UnitFileState::Generated => "generated",
+            &UnitFileState::Transient => "transient",
+            &UnitFileState::Bad => "bad",
+            UnitFileState::Other(s) => s
+        }
+    }
+}
+
+impl fmt::Display for UnitFileState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl rustc_serialize::Decodable for UnitFileState {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<UnitFileState, D::Error> {
+        Ok(UnitFileState::from(d.read_str()?.as_str()))
+    }
+}
+
+impl rustc_serialize::Encodable for UnitFileState {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(self.as_str())
+    }
+}
+
+type_signature!(UnitFileState => "s");
+
+/// The kind of change made to a unit file.
+#[derive(Clone,Debug,PartialEq)]
+pub enum UnitFileChangeType {
+    /// A symlink was created
+    Symlink,
+    /// A symlink or file was removed
+    Unlink,
+    /// The unit is masked, so was not changed
+    IsMasked,
+    /// The unit is an alias to a unit that is not present
+    IsDangling,
+    /// The unit is a dependency of a unit that is not present
+    DestinationNotPresent,
+    /// An auxiliary unit (Also=) could not be changed
+    AuxiliaryFailed,
+    /// A change type not known to this crate
+    Other(String)
+}
+
+impl<'a> From<&'a str> for UnitFileChangeType {
+    /// Parse the string form used by systemd.
+    fn from(s: &'a str) -> UnitFileChangeType {
+        match s {
+            "symlink" => UnitFileChangeType::Symlink,
+            "unlink" => UnitFileChangeType::Unlink,
+            "is-masked" => UnitFileChangeType::IsMasked,
+            "is-dangling" => UnitFileChangeType::IsDangling,
+            "destination-not-present" =>
+                UnitFileChangeType::DestinationNotPresent,
+            "auxiliary-failed" => UnitFileChangeType::AuxiliaryFailed,
+            other => UnitFileChangeType::Other(other.to_string())
+        }
+    }
+}
+
+impl FromStr for UnitFileChangeType {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<UnitFileChangeType, Infallible> {
+        Ok(UnitFileChangeType::from(s))
+    }
+}
+
+impl UnitFileChangeType {
+    /// Return the string form used by systemd.
+    pub fn as_str(&self) -> &str {
+        match self {
+            &UnitFileChangeType::Symlink => "symlink",
+            &UnitFileChangeType::Unlink => "unlink",
+            &UnitFileChangeType::IsMasked => "is-masked",
+            &UnitFileChangeType::IsDangling => "is-dangling",
+            &UnitFileChangeType::DestinationNotPresent =>
+                "destination-not-present",
+            &UnitFileChangeType::AuxiliaryFailed => "auxiliary-failed",
+            UnitFileChangeType::Other(s) => s
+        }
+    }
 }
 
+impl rustc_serialize::Decodable for UnitFileChangeType {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<UnitFileChangeType, D::Error> {
+        Ok(UnitFileChangeType::from(d.read_str()?.as_str()))
+    }
+}
+
+impl rustc_serialize::Encodable for UnitFileChangeType {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(self.as_str())
+    }
+}
+
+type_signature!(UnitFileChangeType => "s");
+
 /// Systemd unit file change information
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub struct UnitFileChange {
-    action: String,
-    link: String,
-    destination: String
+    /// What was changed
+    pub change_type: UnitFileChangeType,
+    /// The file name of the symlink, or the unit
+    pub link: String,
+    /// The destination of the symlink, or empty
+    pub destination: String
+}
+decodable_struct!(UnitFileChange { change_type, link, destination });
+encodable_struct!(UnitFileChange { change_type, link, destination });
+
+impl fmt::Display for UnitFileChange {
+    /// Describe the change as systemctl does.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.change_type {
+            UnitFileChangeType::Symlink =>
+                write!(f, "Created symlink {} \u{2192} {}.",
+                       self.link, self.destination),
+            UnitFileChangeType::Unlink =>
+                write!(f, "Removed \"{}\".", self.link),
+            UnitFileChangeType::IsMasked =>
+                write!(f, "Unit {} is masked, ignoring.", self.link),
+            UnitFileChangeType::IsDangling =>
+                write!(f, "Unit {} is an alias to a unit that is not present, \
+                           ignoring.", self.link),
+            UnitFileChangeType::DestinationNotPresent =>
+                write!(f, "Unit {} is added as a dependency to a non-existent \
+                           unit {}.", self.link, self.destination),
+            UnitFileChangeType::AuxiliaryFailed =>
+                write!(f, "Failed to enable auxiliary unit {}, ignoring.",
+                       self.link),
+            UnitFileChangeType::Other(ref t) =>
+                write!(f, "{}: {} {}", t, self.link, self.destination)
+        }
+    }
 }
 
 /// Systemd unit file changes
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug,Clone,PartialEq)]
 pub struct UnitFileChanges {
-    carries_install_info: bool,
-    changes: Vec<UnitFileChange>
+    /// Whether the unit files have an [Install] section
+    pub carries_install_info: bool,
+    /// The changes made
+    pub changes: Vec<UnitFileChange>
+}
+decodable_struct!(UnitFileChanges { carries_install_info, changes });
+encodable_struct!(UnitFileChanges { carries_install_info, changes });
+
+impl fmt::Display for UnitFileChanges {
+    /// Describe the changes as systemctl does, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+bitflags! {
+    /// Flags for the `*UnitFilesWithFlags` calls.
+    pub struct UnitFileFlags: u64 {
+        /// Make the change in /run, so it is lost on reboot
+        const RUNTIME = 1 << 0;
+        /// Replace existing symlinks that point elsewhere
+        const FORCE = 1 << 1;
+        /// Also consider portable service images
+        const PORTABLE = 1 << 2;
+    }
+}
+
+impl rustc_serialize::Encodable for UnitFileFlags {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_u64(self.bits())
+    }
 }
 
-/// A Unit property
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+type_signature!(UnitFileFlags => "t");
+
+/// Which unit files `preset_unit_files_with_mode` may change.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PresetMode {
+    /// Enable and disable units as the preset policy says
+    Full,
+    /// Only enable units
+    EnableOnly,
+    /// Only disable units
+    DisableOnly
+}
+
+impl PresetMode {
+    /// The string form used by systemd.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PresetMode::Full => "full",
+            PresetMode::EnableOnly => "enable-only",
+            PresetMode::DisableOnly => "disable-only"
+        }
+    }
+}
+
+impl rustc_serialize::Encodable for PresetMode {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(self.as_str())
+    }
+}
+
+type_signature!(PresetMode => "s");
+
+/// The runtime and force arguments of ReenableUnitFiles for `flags`.
+/// systemd has no ReenableUnitFilesWithFlags, so the portable flag
+/// can not be honoured.
+fn reenable_args(flags: UnitFileFlags) -> SystemdResult<(bool, bool)> {
+    if flags.contains(UnitFileFlags::PORTABLE) {
+        return Err(SystemdError::InvalidArg(
+            "reenable does not support UnitFileFlags::PORTABLE".to_string()))
+    }
+    Ok((flags.contains(UnitFileFlags::RUNTIME),
+        flags.contains(UnitFileFlags::FORCE)))
+}
+
+/// A Unit property, as set by SetUnitProperties and
+/// StartTransientUnit.
+#[derive(Debug)]
 pub struct UnitProperty {
     name: String,
-    value: String
+    value: serialize::Variant
 }
+encodable_struct!(UnitProperty { name, value });
 
-/// Unit properties
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+impl UnitProperty {
+    /// Property `name` with `value`, which must encode to the
+    /// property's DBus type, e.g. a u64 for MemoryMax.
+    pub fn new<T>(name: &str, value: T) -> SystemdResult<UnitProperty>
+        where T: rustc_serialize::Encodable + serialize::TypeSignature
+    {
+        Ok(UnitProperty{
+            name: name.to_string(),
+            value: serialize::Variant::new(value)?
+        })
+    }
+}
+
+/// The properties of an auxiliary unit started with a transient unit
+#[derive(Debug)]
 pub struct UnitAux {
     name: String,
     properties: Vec<UnitProperty>
 }
+encodable_struct!(UnitAux { name, properties });
+
+impl UnitAux {
+    /// Auxiliary unit `name` with `properties`.
+    pub fn new(name: &str, properties: Vec<UnitProperty>) -> UnitAux {
+        UnitAux{ name: name.to_string(), properties }
+    }
+}
 
 /// Unit Mode
-#[derive(RustcDecodable,RustcEncodable,Debug)]
+#[derive(Debug)]
 pub enum Mode {
     /// The call will start the unit and its dependencies, possibly
     /// replacing already queued jobs that conflict with this.
@@ -203,7 +910,44 @@ pub enum Mode {
     IgnoreRequirements
 }
 
-/// Match rules for notifications
+impl Mode {
+    /// The string form used by systemd.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Mode::Replace => "replace",
+            Mode::Fail => "fail",
+            Mode::Isolate => "isolate",
+            Mode::IgnoreDependencies => "ignore-dependencies",
+            Mode::IgnoreRequirements => "ignore-requirements"
+        }
+    }
+}
+
+impl rustc_serialize::Encodable for Mode {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(self.as_str())
+    }
+}
+
+type_signature!(Mode => "s");
+
+impl rustc_serialize::Decodable for Mode {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<Mode, D::Error> {
+        match &d.read_str()?[..] {
+            "replace" => Ok(Mode::Replace),
+            "fail" => Ok(Mode::Fail),
+            "isolate" => Ok(Mode::Isolate),
+            "ignore-dependencies" => Ok(Mode::IgnoreDependencies),
+            "ignore-requirements" => Ok(Mode::IgnoreRequirements),
+            other => Err(d.error(&format!("unknown mode {}", other)))
+        }
+    }
+}
+
+/// Match rules for notifications.  Values are not escaped; prefer
+/// `MatchRule`, which validates and escapes them.
 #[derive(Debug)]
 pub enum Match{
     /// match on type, type='…'
@@ -225,12 +969,12 @@ pub enum Match{
 impl fmt::Display for Match {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
-            &Match::Type(ref s) => write!(fmt,"type='{}'",s),
-            &Match::Sender(ref s) => write!(fmt,"sender='{}'",s),
-            &Match::Interface(ref s) => write!(fmt,"interface='{}'",s),
-            &Match::Member(ref s) => write!(fmt,"member='{}'",s),
-            &Match::Path(ref s) => write!(fmt,"path='{}'",s),
-            &Match::Destination(ref s) => write!(fmt,"destination='{}'",s),
+            Match::Type(s) => write!(fmt,"type='{}'",s),
+            Match::Sender(s) => write!(fmt,"sender='{}'",s),
+            Match::Interface(s) => write!(fmt,"interface='{}'",s),
+            Match::Member(s) => write!(fmt,"member='{}'",s),
+            Match::Path(s) => write!(fmt,"path='{}'",s),
+            Match::Destination(s) => write!(fmt,"destination='{}'",s),
             &Match::Arg(n, ref s) => write!(fmt,"arg{}='{}'",n,s),
         }}
 }
@@ -240,158 +984,660 @@ pub fn rule_string(matches: &[Match]) -> String {
     let s : Vec<String> = matches.iter()
         .map(|m| m.to_string())
         .collect();
-    s.connect(",")
+    s.join(",")
+}
+
+/// Rule strings for the signals delivered by `systemd_signals`.
+fn systemd_signal_rules() -> Vec<MatchRule> {
+    vec![MatchRule::signal()
+         .interface("org.freedesktop.systemd1.Manager")
+         .member("UnitNew"),
+         MatchRule::signal()
+         .interface("org.freedesktop.DBus.Properties")
+         .member("PropertiesChanged")]
+}
+
+/// Whether `signal` may have been received because of match rule
+/// `rule`.  The bus resolves well-known sender names, which
+/// `MatchRule::matches` can not, so rules with a sender always may.
+fn may_match(rule: &str, signal: &Signal) -> bool {
+    match rule.parse::<MatchRule>() {
+        Ok(ref parsed) if !rule.contains("sender=") => parsed.matches(signal),
+        _ => true
+    }
+}
+
+/// A signal received from DBus.
+#[derive(Clone, Debug)]
+pub struct Signal {
+    /// The unique bus name of the sender
+    pub sender: Option<String>,
+    /// The object path the signal was emitted from
+    pub path: Option<String>,
+    /// The interface of the signal
+    pub interface: Option<String>,
+    /// The signal name
+    pub member: Option<String>,
+    /// The signal arguments
+    pub items: Vec<MessageItem>
+}
+
+impl Signal {
+    fn from_message(m: &mut dbus::Message) -> Signal {
+        Signal{
+            sender: m.sender().map(|s| s.to_string()),
+            path: m.path().map(|s| s.to_string()),
+            interface: m.interface().map(|s| s.to_string()),
+            member: m.member().map(|s| s.to_string()),
+            items: m.get_items()
+        }
+    }
+
+    /// Whether this is libdbus reporting that the connection was lost.
+    fn is_disconnected(&self) -> bool {
+        self.interface.as_ref().map(|s| &s[..]) ==
+            Some("org.freedesktop.DBus.Local") &&
+            self.member.as_ref().map(|s| &s[..]) == Some("Disconnected")
+    }
+
+    /// Whether this is the bus telling us about our own names, which
+    /// it sends whether or not a match rule asked for it.
+    fn is_name_bookkeeping(&self) -> bool {
+        self.interface.as_ref().map(|s| &s[..]) == Some("org.freedesktop.DBus") &&
+            matches!(self.member.as_ref().map(|s| &s[..]),
+                     Some("NameAcquired") | Some("NameLost"))
+    }
+
+    /// Decode the signal arguments.
+    pub fn decode<T: rustc_serialize::Decodable>(&self) -> SystemdResult<T> {
+        Ok(decode::<T>(self.items.clone())?)
+    }
+
+    /// The name of the unit that emitted the signal, when it was
+    /// emitted from a unit's object, e.g. PropertiesChanged.
+    pub fn unit_name(&self) -> Option<UnitName> {
+        self.path.as_ref().and_then(|p| UnitName::from_object_path(p).ok())
+    }
 }
 
 /// Add some sugar for calling methods on a DBus connection
 trait DBusCallable {
-    /// Call a dbus method, and return the reply
-    fn call(&self, mut method: dbus::Message, args: &[dbus::MessageItem])
-            -> Result<Vec<dbus::MessageItem>,SystemdError>;
+    /// Call a dbus method, and return the reply.  Fails with
+    /// `SystemdError::Timeout` if no reply arrives within `timeout`, or
+    /// `SystemdError::NoReply` if the bus gives up on the reply sooner.
+    /// File descriptors are passed as `MessageItem::UnixFd`, see
+    /// `serialize::UnixFd`.
+    fn call(&self, method: dbus::Message, args: &[MessageItem],
+            timeout: Duration)
+            -> Result<Vec<MessageItem>,SystemdError>;
     /// Like call, but when no reply is expected
-    fn send(&self, mut method: dbus::Message, args: &[dbus::MessageItem])
+    #[allow(dead_code)]
+    fn send(&self, method: dbus::Message, args: &[MessageItem])
             -> Result<(),SystemdError>;
 }
 
-impl DBusCallable for dbus::Connection {
-    fn call(&self, mut method: dbus::Message, args: &[dbus::MessageItem])
-            -> Result<Vec<dbus::MessageItem>,SystemdError>
+impl DBusCallable for dbus::ffidisp::Connection {
+    fn call(&self, mut method: dbus::Message, args: &[MessageItem],
+            timeout: Duration)
+            -> Result<Vec<MessageItem>,SystemdError>
     {
         if !args.is_empty() {
             method.append_items(args);
         }
-        let mut r = try!(self.send_with_reply_and_block(method, 2000));
-        Ok(r.get_items())
+        let start = Instant::now();
+        match self.send_with_reply_and_block(method, timeout_ms(timeout)) {
+            Ok(r) => Ok(r.get_items()),
+            // libdbus reports its own timeout as NoReply
+            Err(e) => Err(match SystemdError::from(e) {
+                SystemdError::NoReply if start.elapsed() >= timeout =>
+                    SystemdError::Timeout,
+                e => e
+            })
+        }
     }
 
-    fn send(&self, mut method: dbus::Message, args: &[dbus::MessageItem])
+    fn send(&self, mut method: dbus::Message, args: &[MessageItem])
             -> Result<(),SystemdError>
     {
         if !args.is_empty() {
             method.append_items(args);
         }
-        Ok(try!(self.send(method)))
+        self.send(method)?;
+        Ok(())
     }
 }
 
 /// Macro to create a wrapper method to invoke a Systemd DBus API call.
 macro_rules! systemd_dbus {
     // A match with return type
-    ($bus:ident, $m:expr, $n:ident ( $($a:ident : $at:ty),* ) -> $t:ty ) => (
-        impl <'a> Connection<'a> {
+    ($bus:ident, $m:expr, $(#[$attr:meta])* $n:ident ( $($a:ident : $at:ty),* ) -> $t:ty ) => (
+        impl Connection {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* ) -> Result<$t, SystemdError> {
+                let args = [$(serialize::encode($a)?),*];
+                self.call(&self.$bus, $m, &args,
+                          |res| Ok(decode::<$t>(res)?))
+            }
+        }
+        impl<'c> WithTimeout<'c> {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* ) -> Result<$t, SystemdError> {
+                let args = [$(serialize::encode($a)?),*];
+                self.call(&self.conn.$bus, $m, &args,
+                          |res| Ok(decode::<$t>(res)?))
+            }
+        }
+        impl dispatch::AsyncConnection {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* )
+                       -> Result<dispatch::Pending<$t>, SystemdError> {
+                let args = vec![$(serialize::encode($a)?),*];
+                Ok(self.call($m, args, dispatch::decode_reply::<$t>))
+            }
+        }
+        impl dispatch::SharedConnection {
+            $(#[$attr])*
+            #[allow(deprecated)]
             pub fn $n (&self, $($a: $at),* ) -> Result<$t, SystemdError> {
-                let m = self.object.method($m).unwrap();
-                let res =
-                    try!(self.$bus.call(m,
-                                        &[$(try!(serialize::encode($a))),*]));
-                let v=decode::<$t>(res);
-                match &v {
-                    &Ok(_) => (),
-                    &Err(ref x) => println!("Error {:?}", x)
-                }
-                assert!(v.is_ok(), "decode failed");
-                Ok(try!(v))
+                self.as_async().$n($($a),*)?.wait()
             }
         });
     // A match without return type
-    ($bus:ident, $m:expr, $n:ident ( $($a:ident : $at:ty),* ) ) => (
-        impl <'a> Connection<'a> {
+    ($bus:ident, $m:expr, $(#[$attr:meta])* $n:ident ( $($a:ident : $at:ty),* ) ) => (
+        impl Connection {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* ) -> Result<(), SystemdError> {
+                let args = [$(serialize::encode($a)?),*];
+                self.call(&self.$bus, $m, &args, |_| Ok(()))
+            }
+        }
+        impl<'c> WithTimeout<'c> {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* ) -> Result<(), SystemdError> {
+                let args = [$(serialize::encode($a)?),*];
+                self.call(&self.conn.$bus, $m, &args, |_| Ok(()))
+            }
+        }
+        impl dispatch::AsyncConnection {
+            $(#[$attr])*
+            pub fn $n (&self, $($a: $at),* )
+                       -> Result<dispatch::Pending<()>, SystemdError> {
+                let args = vec![$(serialize::encode($a)?),*];
+                Ok(self.call($m, args, dispatch::ignore_reply))
+            }
+        }
+        impl dispatch::SharedConnection {
+            $(#[$attr])*
+            #[allow(deprecated)]
             pub fn $n (&self, $($a: $at),* ) -> Result<(), SystemdError> {
-                let m = self.object.method($m).unwrap();
-                try!(self.$bus.call(m, &[$(try!(serialize::encode($a))),*]));
-                Ok(())
+                self.as_async().$n($($a),*)?.wait()
             }
         })
 }
 
 
-/// Macro to create a wrapper method to invoke a Systemd DBus API call.
-/// This doesn't work due to issue
-macro_rules! systemd_dbus1 {
+/// Macro to create a getter for a property of the connection's object.
+#[cfg_attr(not(feature = "codegen"), allow(unused_macros))]
+macro_rules! systemd_property {
+    ($i:expr, $p:expr, $n:ident -> $t:ty) => (
+        impl Connection {
+            pub fn $n (&self) -> Result<$t, SystemdError> {
+                let value = self.get_property(self.object.path, $i, $p)?;
+                Ok(decode::<$t>(vec![value])?)
+            }
+        }
+        impl dispatch::AsyncConnection {
+            pub fn $n (&self) -> Result<dispatch::Pending<$t>, SystemdError> {
+                Ok(self.get_property($i, $p, dispatch::decode_property::<$t>))
+            }
+        }
+        impl dispatch::SharedConnection {
+            pub fn $n (&self) -> Result<$t, SystemdError> {
+                self.as_async().$n()?.wait()
+            }
+        });
+}
+
+/// Macro to create a wrapper method on an object proxy, such as
+/// `unit::Unit`, to invoke a method of one of the object's interfaces.
+#[cfg_attr(not(feature = "codegen"), allow(unused_macros))]
+macro_rules! object_dbus {
     // A match with return type
-    ($bus:ident, $m:expr, $n:ident ( $($a:ident : $at:ty),* ) -> $t:ty ) => (
-        fn $n (&self, $($a: $at),* ) -> Result<$t, SystemdError> {
-                let m = self.object.method($m).unwrap();
-                let res =
-                    try!(self.$bus.call(m,
-                                        &[$(try!(serialize::encode($a))),*]));
-                let v=decode::<$t>(res);
-                match &v {
-                    &Ok(_) => (),
-                    &Err(ref x) => println!("Error {:?}", x)
-                }
-                assert!(v.is_ok(), "decode failed");
-                Ok(try!(v))
+    ($($o:ident)::+, $i:expr, $m:expr,
+     $n:ident ( $($a:ident : $at:ty),* ) -> $t:ty ) => (
+        impl<'c> $($o)::+<'c> {
+            pub fn $n (&self, $($a: $at),* ) -> Result<$t, SystemdError> {
+                let conn = self.connection();
+                let args = [$(serialize::encode($a)?),*];
+                conn.call_on(&conn.bus, self.path(), $i, $m, &args,
+                             |res| Ok(decode::<$t>(res)?))
+            }
         });
     // A match without return type
-    ($bus:ident, $m:expr, $n:ident ( $($a:ident : $at:ty),* ) ) => (
-            fn $n (&self, $($a: $at),* ) -> Result<(), SystemdError> {
-                let m = self.object.method($m).unwrap();
-                try!(self.$bus.call(m, &[$(try!(serialize::encode($a))),*]));
-                Ok(())
+    ($($o:ident)::+, $i:expr, $m:expr, $n:ident ( $($a:ident : $at:ty),* ) ) => (
+        impl<'c> $($o)::+<'c> {
+            pub fn $n (&self, $($a: $at),* ) -> Result<(), SystemdError> {
+                let conn = self.connection();
+                let args = [$(serialize::encode($a)?),*];
+                conn.call_on(&conn.bus, self.path(), $i, $m, &args, |_| Ok(()))
+            }
         })
 }
 
+/// Macro to create a getter on an object proxy, such as `unit::Unit`,
+/// for a property of one of the object's interfaces.
+#[cfg_attr(not(feature = "codegen"), allow(unused_macros))]
+macro_rules! object_property {
+    ($($o:ident)::+, $i:expr, $p:expr, $n:ident -> $t:ty) => (
+        impl<'c> $($o)::+<'c> {
+            pub fn $n (&self) -> Result<$t, SystemdError> {
+                let value = self.connection().get_property(self.path(), $i, $p)?;
+                Ok(decode::<$t>(vec![value])?)
+            }
+        });
+}
+
+
 /// Main type representing a connection to systemd via Dbus.
 ///
-/// ```
-/// use system-dbus::Connection;
+/// ```no_run
+/// use systemd_dbus::Connection;
 ///
-/// let conn=Connection::new();
+/// let conn = Connection::new().unwrap();
 /// println!("{:?}", conn.list_units());
 /// ```
-#[derive(Debug)]
-pub struct Connection<'a> {
-    bus: dbus::Connection,
-    signal_bus: dbus::Connection,
-    object: &'a Object
+pub struct Connection {
+    bus: dbus::ffidisp::Connection,
+    signal_bus: dbus::ffidisp::Connection,
+    bus_type: Bus,
+    rules: RefCell<Vec<String>>,
+    held: RefCell<VecDeque<Signal>>,
+    subscribed: Cell<bool>,
+    object: &'static Object,
+    timeout: Duration,
+    tracer: Option<Box<dyn Tracer>>
 }
 
-impl<'a> Connection<'a> {
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(),fmt::Error> {
+        write!(f, "Connection {{ object: {:?}, timeout: {:?}, traced: {} }}",
+               self.object, self.timeout, self.tracer.is_some())
+    }
+}
+
+impl Connection {
     /// Create a new connection to systemd
-    pub fn new() -> Result<Connection<'a>,SystemdError> {
+    pub fn new() -> Result<Connection,SystemdError> {
+        Connection::open(Bus::System)
+    }
+
+    /// Create a new connection to the systemd manager on `bus`.
+    pub fn open(bus: Bus) -> Result<Connection,SystemdError> {
         Ok(Connection{
-            bus: try!(dbus::Connection::get_private(dbus::BusType::System)),
-            signal_bus: try!(
-                dbus::Connection::get_private(dbus::BusType::System)),
-            object: SYSTEMD
+            bus: bus.connect()?,
+            signal_bus: bus.connect()?,
+            bus_type: bus,
+            rules: RefCell::new(vec![]),
+            held: RefCell::new(VecDeque::new()),
+            subscribed: Cell::new(false),
+            object: SYSTEMD,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS as u64),
+            tracer: None
         })
     }
 
+    /// Set the default timeout for calls on this connection.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Return the default timeout for calls on this connection.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Use `timeout` for the manager calls made through the returned
+    /// handle, e.g. `conn.with_timeout(Duration::from_secs(90)).reload()`.
+    /// The connection's own default is left unchanged.
+    pub fn with_timeout(&self, timeout: Duration) -> WithTimeout<'_> {
+        WithTimeout{ conn: self, timeout }
+    }
+
+    /// Install a tracer, which receives a span for every DBus call
+    /// made on this connection.  Calls are not traced by default.
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    /// Remove any installed tracer.
+    pub fn clear_tracer(&mut self) {
+        self.tracer = None;
+    }
+
+    /// Call `method` on `bus`, decode the reply with `decode`, and
+    /// report the call to the tracer, if any.
+    fn call<T, F>(&self, bus: &dbus::ffidisp::Connection, method: &str,
+                  args: &[MessageItem], decode: F)
+                  -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        self.call_timeout(bus, method, args, self.timeout, decode)
+    }
+
+    /// Like `call`, but waiting up to `timeout` for the reply.
+    fn call_timeout<T, F>(&self, bus: &dbus::ffidisp::Connection, method: &str,
+                          args: &[MessageItem], timeout: Duration, decode: F)
+                          -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        let value = self.call_service_timeout(
+            bus, self.object.service, self.object.path, self.object.interface,
+            method, args, timeout, decode)?;
+        if let Some(subscribed) = subscription_change(method) {
+            self.subscribed.set(subscribed);
+        }
+        Ok(value)
+    }
+
+    /// Like `call`, but on the object at `path` of the connection's
+    /// service, e.g. a unit or job.
+    fn call_on<T, F>(&self, bus: &dbus::ffidisp::Connection, path: &str,
+                     interface: &str, method: &str,
+                     args: &[MessageItem], decode: F)
+                     -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        self.call_service(bus, self.object.service, path, interface, method,
+                          args, decode)
+    }
+
+    /// Like `call_on`, but on an object of another `service`, e.g.
+    /// logind.
+    fn call_service<T, F>(&self, bus: &dbus::ffidisp::Connection, service: &str,
+                          path: &str, interface: &str, method: &str,
+                          args: &[MessageItem], decode: F)
+                          -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        self.call_service_timeout(bus, service, path, interface, method, args,
+                                  self.timeout, decode)
+    }
+
+    /// Like `call_service`, but waiting up to `timeout` for the reply.
+    fn call_service_timeout<T, F>(&self, bus: &dbus::ffidisp::Connection,
+                                  service: &str, path: &str, interface: &str,
+                                  method: &str, args: &[MessageItem],
+                                  timeout: Duration, decode: F)
+                                  -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        let m = dbus::Message::new_method_call(service, path, interface, method)
+            .map_err(SystemdError::InvalidArg)?;
+        let tracer = match self.tracer {
+            Some(ref tracer) => tracer,
+            None => return bus.call(m, args, timeout).and_then(decode)
+        };
+        let start = Instant::now();
+        let (reply, value) = match bus.call(m, args, timeout) {
+            Ok(items) => {
+                let reply = items.clone();
+                (reply, decode(items))
+            },
+            Err(e) => (vec![], Err(e))
+        };
+        tracer.call(&CallSpan{
+            service,
+            path,
+            interface,
+            method,
+            args,
+            elapsed: start.elapsed(),
+            result: match value {
+                Ok(_) => Ok(&reply[..]),
+                Err(ref e) => Err(e)
+            }
+        });
+        value
+    }
+
+    /// Return property `name` of `interface` on the object at `path`.
+    pub fn get_property(&self, path: &str, interface: &str, name: &str)
+                        -> SystemdResult<MessageItem> {
+        self.get_service_property(self.object.service, path, interface, name)
+    }
+
+    /// Like `get_property`, but on an object of another `service`.
+    fn get_service_property(&self, service: &str, path: &str,
+                            interface: &str, name: &str)
+                            -> SystemdResult<MessageItem> {
+        let args = [MessageItem::Str(interface.to_string()),
+                    MessageItem::Str(name.to_string())];
+        self.call_service(&self.bus, service, path, PROPERTIES, "Get", &args,
+                          |mut res| {
+            match res.pop() {
+                Some(MessageItem::Variant(v)) => Ok(*v),
+                other => Err(SystemdError::DecoderError(
+                    serialize::DecoderError::ExpectedError(
+                        "Variant".to_string(), format!("{:?}", other))))
+            }
+        })
+    }
+
+    /// Introspect the object at `path`, returning its interfaces and
+    /// child objects.
+    pub fn introspect(&self, path: &str) -> SystemdResult<introspect::Node> {
+        self.call_on(&self.bus, path, INTROSPECTABLE, "Introspect", &[], |res| {
+            let xml: String = decode(res)?;
+            Ok(introspect::parse(&xml)?)
+        })
+    }
+
+    /// Return all properties of `interface` on the object at `path`.
+    pub fn get_all_properties(&self, path: &str, interface: &str)
+                              -> SystemdResult<Vec<(String, MessageItem)>> {
+        let args = [MessageItem::Str(interface.to_string())];
+        self.call_on(&self.bus, path, PROPERTIES, "GetAll", &args, |mut res| {
+            match res.pop() {
+                Some(dict) => property_dict(dict),
+                None => Ok(vec![])
+            }
+        })
+    }
+
+    /// Wait up to `timeout` for a signal matching one of the rules
+    /// added to this connection, and return it.  Signals that arrived
+    /// while this connection waited for something else, e.g. in
+    /// `run_command`, are returned first.
+    pub fn next_signal(&self, timeout: Duration)
+                       -> SystemdResult<Option<Signal>> {
+        if let Some(signal) = self.held.borrow_mut().pop_front() {
+            return Ok(Some(signal))
+        }
+        self.read_signal(timeout)
+    }
+
+    /// Wait up to `timeout` for a signal that is `wanted`, and take it.
+    /// Other signals are held for `next_signal`.
+    fn take_signal<F>(&self, timeout: Duration, wanted: F)
+                      -> SystemdResult<Option<Signal>>
+        where F: Fn(&Signal) -> bool
+    {
+        {
+            let mut held = self.held.borrow_mut();
+            if let Some(i) = held.iter().position(&wanted) {
+                return Ok(held.remove(i))
+            }
+        }
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            match self.read_signal(deadline - now)? {
+                Some(signal) => {
+                    if wanted(&signal) {
+                        return Ok(Some(signal))
+                    }
+                    self.held.borrow_mut().push_back(signal);
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a new signal that is `wanted`, and
+    /// return a copy of it.  Every signal received, including the one
+    /// returned, is held for `next_signal`, until the rules it matched
+    /// are removed.
+    fn watch_signal<F>(&self, timeout: Duration, wanted: F)
+                       -> SystemdResult<Option<Signal>>
+        where F: Fn(&Signal) -> bool
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            match self.read_signal(deadline - now)? {
+                Some(signal) => {
+                    self.held.borrow_mut().push_back(signal.clone());
+                    if wanted(&signal) {
+                        return Ok(Some(signal))
+                    }
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a signal from the bus.
+    fn read_signal(&self, timeout: Duration) -> SystemdResult<Option<Signal>> {
+        for item in self.signal_bus.iter(timeout_ms(timeout)) {
+            match item {
+                dbus::ffidisp::ConnectionItem::Signal(mut m) => {
+                    let signal = Signal::from_message(&mut m);
+                    if signal.is_disconnected() {
+                        return Err(SystemdError::Disconnected)
+                    }
+                    if signal.is_name_bookkeeping() {
+                        continue
+                    }
+                    return Ok(Some(signal))
+                },
+                dbus::ffidisp::ConnectionItem::Nothing => return Ok(None),
+                _ => ()
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for the bus to process the messages sent on the signal
+    /// connection so far, e.g. AddMatch.
+    fn sync_signal_bus(&self) -> SystemdResult<()> {
+        self.signal_bus.call(DBUS.method("GetId").unwrap(), &[],
+                             self.timeout)?;
+        Ok(())
+    }
+
+    /// Call `method` of the bus itself on the signal connection, with
+    /// a match rule as its argument, so the call is traced.
+    fn call_bus(&self, method: &str, rule_string: &str) -> SystemdResult<()> {
+        self.call_service(&self.signal_bus, DBUS.service, DBUS.path,
+                          DBUS.interface, method,
+                          &[MessageItem::Str(rule_string.to_string())],
+                          |_| Ok(()))
+    }
+
     /// Add a match rule for signals
     pub fn add_match(&self, rule_string: &str) -> Result<(),SystemdError> {
-        let mut method=DBUS.method("AddMatch").unwrap();
-        method.append_items(&[dbus::MessageItem::Str(rule_string.to_string())]);
-        Ok(try!(self.signal_bus.send(method)))
+        self.call_bus("AddMatch", rule_string)?;
+        self.rules.borrow_mut().push(rule_string.to_string());
+        Ok(())
+    }
+
+    /// Validate and add a match rule for signals
+    pub fn add_match_rule(&self, rule: &MatchRule) -> Result<(),SystemdError> {
+        rule.validate()?;
+        self.add_match(rule.to_string().as_str())
+    }
+
+    /// Remove a match rule added with `add_match`
+    pub fn remove_match(&self, rule_string: &str) -> Result<(),SystemdError> {
+        self.call_bus("RemoveMatch", rule_string)?;
+        let mut rules = self.rules.borrow_mut();
+        if let Some(i) = rules.iter().position(|r| *r == rule_string) {
+            rules.remove(i);
+        }
+        // Drop held signals that no remaining rule asked for
+        self.held.borrow_mut()
+            .retain(|signal| rules.iter().any(|rule| may_match(rule, signal)));
+        Ok(())
+    }
+
+    /// Remove a match rule added with `add_match_rule`
+    pub fn remove_match_rule(&self, rule: &MatchRule)
+                             -> Result<(),SystemdError> {
+        self.remove_match(rule.to_string().as_str())
+    }
+
+    /// Replace both bus connections with new ones, retrying with
+    /// `backoff`, e.g. after systemd has been re-executed.  Match rules
+    /// added with `add_match`, and any systemd subscription, are
+    /// restored on the new connections.
+    pub fn reconnect(&mut self, backoff: &Backoff) -> SystemdResult<()> {
+        let bus = connect_with_backoff(&self.bus_type, backoff)?;
+        let signal_bus = connect_with_backoff(&self.bus_type, backoff)?;
+        restore_subscriptions(&signal_bus, &self.rules.borrow(),
+                              self.subscribed.get())?;
+        self.bus = bus;
+        self.signal_bus = signal_bus;
+        Ok(())
     }
 
     /// Start subscription to systemd signals
     pub fn systemd_signals(&self) -> Result<(),SystemdError> {
-        try!(self.add_match(
-            rule_string(
-                &[Match::Type("Signal".to_string()),
-                  Match::Interface(
-                      "org.freedesktop.systemd1.Manager".to_string()),
-                  Match::Member("UnitNew".to_string())]).as_slice()));
-        try!(self.add_match(
-            rule_string(
-                &[Match::Type("Signal".to_string()),
-                  Match::Interface(
-                      "org.freedesktop.DBus.Properties".to_string()),
-                  Match::Member("PropertiesChanged".to_string())]).as_slice()));
-        try!(self.subscribe());
+        for rule in systemd_signal_rules().iter() {
+            self.add_match_rule(rule)?;
+        }
+        self.subscribe()?;
         Ok(())
     }
 
 
 }
 
+/// A Connection with a per-call timeout override.
+///
+/// Returned by `Connection::with_timeout`.  It has the manager methods
+/// of the Connection, each waiting up to its own timeout for a reply.
+pub struct WithTimeout<'c> {
+    conn: &'c Connection,
+    timeout: Duration
+}
+
+impl<'c> WithTimeout<'c> {
+    /// Return the timeout for calls made through this handle.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn call<T, F>(&self, bus: &dbus::ffidisp::Connection, method: &str,
+                  args: &[MessageItem], decode: F)
+                  -> SystemdResult<T>
+        where F: FnOnce(Vec<MessageItem>) -> SystemdResult<T>
+    {
+        self.conn.call_timeout(bus, method, args, self.timeout, decode)
+    }
+}
+
 systemd_dbus!(bus, "GetUnit",get_unit(name: &str) -> ObjectPath);
-systemd_dbus!(bus, "GetUnitByPID",get_unit_by_pid(pid: u32) -> Vec<Job>);
+systemd_dbus!(bus, "GetUnitByPID",get_unit_by_pid(pid: u32) -> ObjectPath);
 systemd_dbus!(bus, "LoadUnit",load_unit(name: String) -> ObjectPath);
 systemd_dbus!(bus, "StartUnit",
               start_unit(name: String, mode: Mode) -> ObjectPath);
-systemd_dbus!(bus, "StartUnitRecplace",
+systemd_dbus!(bus, "StartUnitReplace",
               start_unit_replace(old_unit: String,
                                  new_unit: String,
                                  mode: Mode) -> ObjectPath);
@@ -409,11 +1655,13 @@ systemd_dbus!(bus, "ReloadOrTryRestartUnit",
               reload_or_try_restart_unit(name: String,
                                          mode: Mode) -> ObjectPath);
 systemd_dbus!(bus, "KillUnit",
-              kill_unit(name: String, who: String, signal: u32) -> ObjectPath);
+              kill_unit(name: String, who: String, signal: i32));
 systemd_dbus!(bus, "ResetFailedUnit",
-              reset_failed_unit(name: String) -> ObjectPath);
+              reset_failed_unit(name: String));
 
 systemd_dbus!(bus, "GetJob",get_job(id: u32) -> ObjectPath);
+systemd_dbus!(bus, "AttachProcessesToUnit",
+              attach_processes_to_unit(name: &str, subcgroup: &str, pids: &[u32]));
 systemd_dbus!(bus, "CancelJob", cancel_job(id: u32));
 systemd_dbus!(bus, "ClearJobs", clear_jobs());
 systemd_dbus!(bus, "ResetFailed", reset_failed());
@@ -430,114 +1678,287 @@ systemd_dbus!(bus, "RemoveSnapshot", remove_snapshot(name: String));
 
 systemd_dbus!(bus, "Reload", reload());
 systemd_dbus!(bus, "Reexecute", reexecute());
-systemd_dbus!(bus, "Reboot", reboot());
-systemd_dbus!(bus, "PowerOff", power_off());
-systemd_dbus!(bus, "Halt", halt());
-systemd_dbus!(bus, "KExec", k_exec());
+systemd_dbus!(bus, "Reboot",
+              /// Reboot at once, ignoring inhibitor locks and logged in
+              /// users.  `login1::Manager::reboot` honours them.
+              #[deprecated(note = "use login1::Manager::reboot")]
+              reboot());
+systemd_dbus!(bus, "PowerOff",
+              /// Power off at once, ignoring inhibitor locks and logged in
+              /// users.  `login1::Manager::power_off` honours them.
+              #[deprecated(note = "use login1::Manager::power_off")]
+              power_off());
+systemd_dbus!(bus, "Halt",
+              /// Halt at once, ignoring inhibitor locks and logged in
+              /// users.  `login1::Manager::halt` honours them.
+              #[deprecated(note = "use login1::Manager::halt")]
+              halt());
+systemd_dbus!(bus, "KExec",
+              /// Reboot with kexec at once, ignoring inhibitor locks and
+              /// logged in users.  Prefer `login1::Manager::reboot`.
+              k_exec());
 systemd_dbus!(bus, "SwitchRoot", switch_root(new_root: String, init: String));
 
-systemd_dbus!(bus, "SetEnvironment", set_environment(names: String));
-systemd_dbus!(bus, "UnsetEnvironment", unset_environment(names: String));
+systemd_dbus!(bus, "SetEnvironment", set_environment(assignments: &[&str]));
+systemd_dbus!(bus, "UnsetEnvironment", unset_environment(names: &[&str]));
 systemd_dbus!(bus, "UnsetAndSetEnvironment",
-              unset_and_set_environment(unset: String, set: String));
+              unset_and_set_environment(unset: &[&str], set: &[&str]));
 
 systemd_dbus!(bus, "ListUnitFiles",list_unit_files() -> Vec<UnitFile>);
-systemd_dbus!(bus, "GetUnitFileState",get_unit_file_state(file: String) -> String);
+systemd_dbus!(bus, "ListUnitFilesByPatterns",
+              list_unit_files_by_patterns(states: &[UnitFileState],
+                                          patterns: &[&str])
+                                          -> Vec<UnitFile>);
+systemd_dbus!(bus, "GetUnitFileState",
+              get_unit_file_state(file: String) -> UnitFileState);
 systemd_dbus!(bus, "EnableUnitFiles",
-              enable_unit_files(files: String, runtime: bool, force: bool)
+              enable_unit_files(files: &[&str], runtime: bool, force: bool)
                                 -> UnitFileChanges);
 systemd_dbus!(bus, "DisableUnitFiles",
-              disable_unit_files(files: String, runtime: bool)
+              disable_unit_files(files: &[&str], runtime: bool)
                                  -> Vec<UnitFileChange>);
 systemd_dbus!(bus, "ReenableUnitFiles",
-              reenable_unit_files(files: String, runtime: bool, force: bool)
+              reenable_unit_files(files: &[&str], runtime: bool, force: bool)
                                   -> UnitFileChanges);
 systemd_dbus!(bus, "LinkUnitFiles",
-              link_unit_files(files: String, runtime: bool, force: bool)
+              link_unit_files(files: &[&str], runtime: bool, force: bool)
                               -> Vec<UnitFileChange>);
 systemd_dbus!(bus, "PresetUnitFiles",
-              preset_unit_files(files: String, runtime: bool, force: bool)
+              preset_unit_files(files: &[&str], runtime: bool, force: bool)
                                 -> UnitFileChanges);
 systemd_dbus!(bus, "MaskUnitFiles",
-              mask_unit_files(files: String, runtime: bool, force: bool)
+              mask_unit_files(files: &[&str], runtime: bool, force: bool)
                               -> Vec<UnitFileChange>);
 systemd_dbus!(bus, "UnmaskUnitFiles",
-              unmask_unit_files(files: String, runtime: bool)
+              unmask_unit_files(files: &[&str], runtime: bool)
                                 -> Vec<UnitFileChange>);
+systemd_dbus!(bus, "EnableUnitFilesWithFlags",
+              enable_unit_files_with_flags(files: &[&str], flags: UnitFileFlags)
+                                           -> UnitFileChanges);
+systemd_dbus!(bus, "DisableUnitFilesWithFlags",
+              disable_unit_files_with_flags(files: &[&str], flags: UnitFileFlags)
+                                            -> Vec<UnitFileChange>);
+systemd_dbus!(bus, "PresetUnitFilesWithMode",
+              preset_unit_files_with_mode(files: &[&str], mode: PresetMode,
+                                          runtime: bool, force: bool)
+                                          -> UnitFileChanges);
+systemd_dbus!(bus, "RevertUnitFiles",
+              revert_unit_files(files: &[&str]) -> Vec<UnitFileChange>);
+
+/// Macro to create a wrapper method taking `UnitFileFlags` for a call
+/// that only has the older runtime and force arguments.
+macro_rules! systemd_dbus_flags {
+    ($(#[$attr:meta])* $n:ident => $m:ident -> $t:ty) => (
+        impl Connection {
+            $(#[$attr])*
+            pub fn $n (&self, files: &[&str], flags: UnitFileFlags)
+                       -> Result<$t, SystemdError> {
+                let (runtime, force) = reenable_args(flags)?;
+                self.$m(files, runtime, force)
+            }
+        }
+        impl<'c> WithTimeout<'c> {
+            $(#[$attr])*
+            pub fn $n (&self, files: &[&str], flags: UnitFileFlags)
+                       -> Result<$t, SystemdError> {
+                let (runtime, force) = reenable_args(flags)?;
+                self.$m(files, runtime, force)
+            }
+        }
+        impl dispatch::AsyncConnection {
+            $(#[$attr])*
+            pub fn $n (&self, files: &[&str], flags: UnitFileFlags)
+                       -> Result<dispatch::Pending<$t>, SystemdError> {
+                let (runtime, force) = reenable_args(flags)?;
+                self.$m(files, runtime, force)
+            }
+        }
+        impl dispatch::SharedConnection {
+            $(#[$attr])*
+            pub fn $n (&self, files: &[&str], flags: UnitFileFlags)
+                       -> Result<$t, SystemdError> {
+                let (runtime, force) = reenable_args(flags)?;
+                self.$m(files, runtime, force)
+            }
+        })
+}
+
+systemd_dbus_flags!(
+    /// Reenable unit files, with `flags` mapped onto ReenableUnitFiles.
+    /// Fails with `InvalidArg` if `flags` contains `UnitFileFlags::PORTABLE`.
+    reenable_unit_files_with_flags => reenable_unit_files -> UnitFileChanges);
+
 systemd_dbus!(bus, "SetDefaultTarget",
-              set_default_target(files: String) -> Vec<UnitFileChange>);
+              set_default_target(name: String, force: bool)
+                                 -> Vec<UnitFileChange>);
 systemd_dbus!(bus, "GetDefaultTarget", get_default_target() -> String);
+systemd_dbus!(bus, "Dump", dump() -> String);
+systemd_dbus!(bus, "DumpByFileDescriptor", dump_by_file_descriptor() -> UnixFd);
 systemd_dbus!(bus, "SetUnitProperties",
               set_unit_properties(name: String,
                                   runtime: bool,
                                   properties: Vec<UnitProperty>));
-systemd_dbus!(bus, "SetTransientUnit",
-              set_transient_unit(name: String,
-                                 mode: Mode,
-                                 properties: Vec<UnitProperty>,
-                                 aux: Vec<UnitAux>) -> ObjectPath);
+systemd_dbus!(bus, "StartTransientUnit",
+              start_transient_unit(name: String,
+                                   mode: Mode,
+                                   properties: Vec<UnitProperty>,
+                                   aux: Vec<UnitAux>) -> ObjectPath);
+
+// Wrappers generated by build.rs from introspection/, for the methods
+// not wrapped above, and for the methods and properties of unit and
+// job objects.
+#[cfg(feature = "codegen")]
+include!(concat!(env!("OUT_DIR"), "/systemd1.rs"));
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dbus::MessageItem;
+    use mock::MockSystemd;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::time::Duration;
+    use dbus::arg::messageitem::MessageItem;
+
+    fn mock_connection() -> (MockSystemd, Connection) {
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("syslog.socket", "Syslog Socket", "active", "running");
+        let conn = Connection::open(mock.bus()).unwrap();
+        (mock, conn)
+    }
 
     #[test]
     fn connection_test() {
-        Connection::new().as_ref().unwrap();
+        let mock = MockSystemd::start().unwrap();
+        Connection::open(mock.bus()).as_ref().unwrap();
+    }
+
+    /// A tracer that records the method name of each span.
+    struct RecordingTracer(::std::sync::Arc<::std::sync::Mutex<Vec<String>>>);
+
+    impl Tracer for RecordingTracer {
+        fn call(&self, span: &CallSpan) {
+            self.0.lock().unwrap().push(span.method.to_string());
+        }
+    }
+
+    #[test]
+    fn match_rules_are_traced_test() {
+        let (_mock, mut conn) = mock_connection();
+        let methods = ::std::sync::Arc::new(::std::sync::Mutex::new(vec![]));
+        conn.set_tracer(Box::new(RecordingTracer(methods.clone())));
+        let rule = "type='signal',interface='org.freedesktop.systemd1.Manager'";
+        conn.add_match(rule).unwrap();
+        conn.remove_match(rule).unwrap();
+        assert_eq!(vec!["AddMatch".to_string(), "RemoveMatch".to_string()],
+                   *methods.lock().unwrap());
     }
 
     #[test]
     fn list_units_test() {
-        match Connection::new().unwrap().list_units() {
-            Ok(v) => println!("list_units succeeded {:?}", v),
+        let (mock, conn) = mock_connection();
+        match conn.list_units() {
+            Ok(v) => assert_eq!(1, v.len()),
             Err(e) => {
                 println!("list_units failed {:?}", e);
-                assert!(false, "list_units failed");
+                panic!("list_units failed");
             }}
+        assert_eq!("ListUnits", mock.calls()[0].method);
     }
 
     #[test]
     fn list_jobs_test() {
-        match Connection::new().unwrap().list_jobs() {
-            Ok(v) => println!("list_jobs succeeded {:?}", v),
+        let (mock, conn) = mock_connection();
+        mock.add_job("syslog.socket", "start", "waiting");
+        match conn.list_jobs() {
+            Ok(v) => assert_eq!(1, v.len()),
             Err(e) => {
                 println!("list_jobs failed {:?}", e);
-                assert!(false, "list_jobs failed");
+                panic!("list_jobs failed");
             }}
     }
 
     #[test]
     fn get_unit_test() {
-        match Connection::new().unwrap().get_unit("syslog.socket") {
-            Ok(v) => println!("get_unit succeeded {:?}", v),
+        let (_mock, conn) = mock_connection();
+        match conn.get_unit("syslog.socket") {
+            Ok(v) => assert_eq!("/org/freedesktop/systemd1/unit/syslog_2esocket",
+                                v),
             Err(e) => {
                 println!("get_unit failed {:?}", e);
-                assert!(false, "get_unit failed");
+                panic!("get_unit failed");
             }}
     }
 
     #[test]
     fn get_unit_by_pid_test() {
-        match Connection::new().unwrap().get_unit_by_pid(1) {
-            Ok(v) => println!("get_unit succeeded {:?}", v),
-            Err(e) => {
-                println!("get_unit failed {:?}", e);
-                assert!(false, "get_unit failed");
-            }}
+        let (mock, conn) = mock_connection();
+        assert_eq!(ObjectPath::new(
+            "/org/freedesktop/systemd1/unit/syslog_2esocket").unwrap(),
+                   conn.get_unit_by_pid(1).unwrap());
+        assert_eq!(vec![MessageItem::UInt32(1)], mock.calls()[0].args);
     }
 
     #[test]
     fn clear_jobs_test() {
-        match Connection::new().unwrap().clear_jobs() {
+        let (mock, conn) = mock_connection();
+        mock.add_job("syslog.socket", "start", "waiting");
+        match conn.clear_jobs() {
             Ok(v) => println!("clear_jobs succeeded {:?}", v),
             Err(e) => {
                 println!("get_unit failed {:?}", e);
-                assert!(false, "get_unit failed");
+                panic!("get_unit failed");
             }}
+        assert_eq!(0, conn.list_jobs().unwrap().len());
+    }
+
+    #[cfg(feature = "codegen")]
+    #[test]
+    fn generated_wrappers_test() {
+        let (mock, conn) = mock_connection();
+        assert_eq!("mock", conn.version_property().unwrap());
+        let async_conn = AsyncConnection::open(mock.bus(), None).unwrap();
+        assert_eq!("mock", async_conn.version_property().unwrap().wait().unwrap());
+        let shared = SharedConnection::open(mock.bus(), None).unwrap();
+        assert_eq!(1, shared.n_names_property().unwrap());
+        assert_eq!(0, conn.exit_code_property().unwrap());
+        let unit = conn.unit("syslog.socket").unwrap();
+        assert_eq!("Syslog Socket", unit.description_property().unwrap());
+        assert_eq!(vec![] as Vec<String>, unit.drop_in_paths_property().unwrap());
+        assert_eq!((0..16).collect::<Vec<u8>>(),
+                   unit.invocation_id_property().unwrap());
+        let id = mock.add_job("syslog.socket", "restart", "running");
+        let job = conn.job_object(&conn.get_job(id).unwrap());
+        assert_eq!(id, job.id_property().unwrap());
+        assert_eq!("syslog.socket", job.unit_property().unwrap().0);
+    }
+
+    #[test]
+    fn unit_new_signal_test() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = AsyncConnection::open(mock.bus(), None).unwrap();
+        let signals = conn.systemd_signals().unwrap();
+        mock.emit_unit_new("foo.service");
+        let signal = signals.take(1).next().unwrap();
+        assert_eq!(Some("UnitNew".to_string()), signal.member);
+        assert!(mock.calls().iter().any(|c| c.method == "Subscribe"));
     }
 
+    #[test]
+    fn signal_unit_name_test() {
+        let signal = Signal{
+            sender: None,
+            path: Some("/org/freedesktop/systemd1/unit/sshd_2eservice".to_string()),
+            interface: Some("org.freedesktop.DBus.Properties".to_string()),
+            member: Some("PropertiesChanged".to_string()),
+            items: vec![]
+        };
+        assert_eq!(Some(UnitName::new("sshd.service").unwrap()),
+                   signal.unit_name());
+        assert_eq!(None, Signal{ path: Some("/org/freedesktop/systemd1".to_string()),
+                                 ..signal }.unit_name());
+    }
 
     #[test]
     fn rule_string_test() {
@@ -547,6 +1968,239 @@ mod tests {
     }
 
 
+    #[test]
+    fn with_timeout_test() {
+        let (mock, conn) = mock_connection();
+        mock.withhold_replies("Reload");
+        let outer = conn.with_timeout(Duration::from_secs(30));
+        {
+            let inner = conn.with_timeout(Duration::from_millis(100));
+            let start = Instant::now();
+            match inner.reload() {
+                Err(SystemdError::Timeout) => (),
+                other => panic!("expected a timeout, got {:?}", other)
+            }
+            assert!(start.elapsed() < Duration::from_secs(2));
+        }
+        assert_eq!(Duration::from_secs(30), outer.timeout());
+        assert_eq!(Duration::from_millis(DEFAULT_TIMEOUT_MS as u64),
+                   conn.timeout());
+        assert_eq!(1, outer.list_units().unwrap().len());
+    }
+
+    #[test]
+    fn no_reply_error_test() {
+        let err = dbus::Error::new_custom("org.freedesktop.DBus.Error.NoReply",
+                                          "no reply");
+        assert!(matches!(SystemdError::from(err), SystemdError::NoReply));
+        let err = dbus::Error::new_custom("org.freedesktop.DBus.Error.Timeout",
+                                          "timed out");
+        assert!(matches!(SystemdError::from(err), SystemdError::Timeout));
+    }
+
+    #[test]
+    fn timeout_ms_test() {
+        assert_eq!(2500, super::timeout_ms(Duration::from_millis(2500)));
+        assert_eq!(i32::MAX, super::timeout_ms(Duration::from_secs(1 << 40)));
+    }
+
+    #[test]
+    fn backoff_delays_test() {
+        let backoff = Backoff{ initial: Duration::from_millis(100),
+                               max: Duration::from_millis(300),
+                               attempts: Some(4) };
+        let delays: Vec<Duration> = backoff.delays().collect();
+        assert_eq!(vec![Duration::from_millis(100), Duration::from_millis(200),
+                        Duration::from_millis(300), Duration::from_millis(300)],
+                   delays);
+    }
+
+    #[test]
+    fn backoff_delays_do_not_overflow_test() {
+        let huge = Duration::new(u64::MAX, 0);
+        let backoff = Backoff{ initial: huge, max: huge, attempts: Some(3) };
+        assert_eq!(vec![huge, huge, huge],
+                   backoff.delays().collect::<Vec<Duration>>());
+    }
+
+    /// Wait until `mock` has received a call of `method`.
+    fn wait_for_call(mock: &MockSystemd, method: &str) {
+        let start = Instant::now();
+        while !mock.calls().iter().any(|c| c.method == method) {
+            assert!(start.elapsed() < Duration::from_secs(5),
+                    "no {} call", method);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reconnect_restores_subscriptions_test() {
+        let (mut mock, mut conn) = mock_connection();
+        conn.systemd_signals().unwrap();
+        mock.restart().unwrap();
+        mock.clear_calls();
+        let backoff = Backoff{ initial: Duration::from_millis(10),
+                               max: Duration::from_millis(100),
+                               attempts: Some(20) };
+        conn.reconnect(&backoff).unwrap();
+        // The match rules were re-added before Subscribe was sent
+        wait_for_call(&mock, "Subscribe");
+        mock.emit_unit_new("syslog.socket");
+        let signal = conn.next_signal(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(Some("UnitNew".to_string()), signal.member);
+        assert_eq!(1, conn.list_units().unwrap().len());
+    }
+
+    #[test]
+    fn failed_subscribe_is_not_recorded_test() {
+        let (mock, mut conn) = mock_connection();
+        mock.withhold_replies("Subscribe");
+        conn.set_timeout(Duration::from_millis(100));
+        assert!(conn.subscribe().is_err());
+        assert!(!conn.subscribed.get());
+    }
+
+    #[test]
+    fn decode_unit_file_changes_test() {
+        let change = MessageItem::Struct(vec![
+            MessageItem::Str("symlink".to_string()),
+            MessageItem::Str("/etc/systemd/system/multi-user.target.wants/a.service"
+                             .to_string()),
+            MessageItem::Str("/usr/lib/systemd/system/a.service".to_string())]);
+        let items = vec![MessageItem::Bool(true),
+                         serialize::array(vec![change], "(sss)").unwrap()];
+        let changes = serialize::decode::<UnitFileChanges>(items).unwrap();
+        assert!(changes.carries_install_info);
+        assert_eq!(UnitFileChangeType::Symlink, changes.changes[0].change_type);
+        assert_eq!("Created symlink \
+                    /etc/systemd/system/multi-user.target.wants/a.service \
+                    \u{2192} /usr/lib/systemd/system/a.service.",
+                   changes.to_string());
+    }
+
+    #[test]
+    fn unit_file_change_type_test() {
+        assert_eq!(UnitFileChangeType::DestinationNotPresent,
+                   UnitFileChangeType::from("destination-not-present"));
+        assert_eq!(UnitFileChangeType::Other("new-kind".to_string()),
+                   "new-kind".parse().unwrap());
+        assert_eq!("Removed \"/etc/a\".",
+                   UnitFileChange{ change_type: UnitFileChangeType::Unlink,
+                                   link: "/etc/a".to_string(),
+                                   destination: "".to_string() }.to_string());
+    }
+
+    #[test]
+    fn enable_unit_files_test() {
+        let (mock, conn) = mock_connection();
+        mock.add_unit_file("/etc/systemd/system/a.service", "disabled");
+        mock.add_unit_file("/etc/systemd/system/b.service", "disabled");
+        let changes = conn.enable_unit_files(&["a.service", "b.service"],
+                                             false, false).unwrap();
+        assert_eq!(2, changes.changes.len());
+        assert_eq!(serialize::array(
+            vec![MessageItem::Str("a.service".to_string()),
+                 MessageItem::Str("b.service".to_string())], "s").unwrap(),
+                   mock.calls()[0].args[0]);
+        assert_eq!(UnitFileState::Enabled,
+                   conn.get_unit_file_state("b.service".to_string()).unwrap());
+    }
+
+    #[test]
+    fn unit_file_flags_test() {
+        let (mock, conn) = mock_connection();
+        mock.add_unit_file("/etc/systemd/system/a.service", "enabled");
+        let changes = conn.revert_unit_files(&["a.service"]).unwrap();
+        assert_eq!(1, changes.len());
+        conn.enable_unit_files_with_flags(&["a.service"],
+                                          UnitFileFlags::RUNTIME | UnitFileFlags::FORCE)
+            .unwrap();
+        assert_eq!(MessageItem::UInt64(3), mock.calls()[1].args[1]);
+        assert!(conn.reenable_unit_files_with_flags(&["a.service"],
+                                                    UnitFileFlags::PORTABLE)
+                .is_err());
+        assert_eq!(MessageItem::Str("enable-only".to_string()),
+                   serialize::encode(PresetMode::EnableOnly).unwrap());
+    }
+
+    #[test]
+    fn corrected_wrappers_test() {
+        let (mock, conn) = mock_connection();
+        conn.kill_unit("a.service".to_string(), "all".to_string(), 15).unwrap();
+        assert_eq!(MessageItem::Int32(15), mock.calls()[0].args[2]);
+        conn.reset_failed_unit("a.service".to_string()).unwrap();
+        assert_eq!("ResetFailedUnit", mock.calls()[1].method);
+        assert_eq!(MessageItem::Str("ignore-dependencies".to_string()),
+                   serialize::encode(Mode::IgnoreDependencies).unwrap());
+    }
+
+    #[test]
+    fn dump_by_file_descriptor_test() {
+        let (mock, conn) = mock_connection();
+        mock.add_unit("a.service", "A service", "active", "running");
+        let fd = conn.dump_by_file_descriptor().unwrap();
+        let mut dump = String::new();
+        unsafe { File::from_raw_fd(fd.into_fd()) }.read_to_string(&mut dump).unwrap();
+        assert_eq!(conn.dump().unwrap(), dump);
+        assert!(dump.contains("-> Unit a.service:"));
+    }
+
+    #[test]
+    fn list_unit_files_by_patterns_test() {
+        let (mock, conn) = mock_connection();
+        mock.add_unit_file("/etc/systemd/system/a.service", "enabled");
+        mock.add_unit_file("/etc/systemd/system/b.service", "disabled");
+        mock.add_unit_file("/etc/systemd/system/c.socket", "enabled");
+        let files = conn.list_unit_files_by_patterns(&[UnitFileState::Enabled],
+                                                     &["*.service"]).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("/etc/systemd/system/a.service", files[0].name);
+        assert_eq!(3, conn.list_unit_files_by_patterns(&[], &[]).unwrap().len());
+    }
+
+    #[test]
+    fn unit_file_state_test() {
+        assert_eq!(UnitFileState::EnabledRuntime,
+                   UnitFileState::from("enabled-runtime"));
+        assert_eq!(UnitFileState::Other("new".to_string()),
+                   "new".parse().unwrap());
+        assert_eq!("masked-runtime", UnitFileState::MaskedRuntime.as_str());
+    }
+
+    #[test]
+    fn object_path_test() {
+        let path = ObjectPath::new("/org/freedesktop/systemd1").unwrap();
+        assert_eq!(MessageItem::ObjectPath("/org/freedesktop/systemd1".into()),
+                   serialize::encode(&path).unwrap());
+        assert_eq!(path, serialize::decode::<ObjectPath>(
+            vec![MessageItem::ObjectPath("/org/freedesktop/systemd1".into())])
+                   .unwrap());
+        assert!(serialize::decode::<ObjectPath>(
+            vec![MessageItem::Str("/org/freedesktop/systemd1".to_string())]).is_err());
+        assert!(serialize::decode::<String>(
+            vec![MessageItem::ObjectPath("/org/freedesktop/systemd1".into())])
+                .is_err());
+        assert!(ObjectPath::new("org/freedesktop").is_err());
+        let signature = Signature::new("a(sss)").unwrap();
+        assert_eq!(MessageItem::Signature("a(sss)".into()),
+                   serialize::encode(&signature).unwrap());
+        assert!(Signature::new("a(ss").is_err());
+    }
+
+    #[derive(Debug)]
+    struct UnitRef {
+        name: String,
+        path: ObjectPath
+    }
+    encodable_struct!(UnitRef { name, path });
+
+    #[test]
+    fn encode_invalid_object_path_in_struct_test() {
+        let unit = UnitRef{ name: "foo.service".to_string(),
+                            path: ObjectPath("foo.service".to_string()) };
+        assert!(serialize::encode(&unit).is_err());
+    }
+
     #[test]
     fn encode_mode_test() {
         assert_eq!(MessageItem::Str("replace".to_string()),
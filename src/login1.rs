@@ -0,0 +1,1037 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The login manager, logind: power management, inhibitor locks, and
+//! sessions, users and seats.
+//!
+//! Shut down and sleep through logind, which honours inhibitor locks,
+//! rather than the systemd manager's `reboot()`, `power_off()`,
+//! `halt()` and `k_exec()`, which ignore them and are meant for
+//! systemctl's own use.  logind has no kexec; start `kexec.target`
+//! instead.
+//! With `interactive` set, logind asks the user for authorization if
+//! polkit requires it; otherwise the call fails.
+//!
+//! ```no_run
+//! use std::time::{Duration, SystemTime};
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::login1::{Capability, ShutdownType};
+//!
+//! let conn = Connection::new().unwrap();
+//! let login1 = conn.login1();
+//! if login1.can_reboot().unwrap() == Capability::Yes {
+//!     let when = SystemTime::now() + Duration::from_secs(600);
+//!     login1.schedule_shutdown(ShutdownType::Reboot, when).unwrap();
+//! }
+//! ```
+//!
+//! Inhibitor locks delay or block shutdown, sleep and idle while they
+//! are held:
+//!
+//! ```no_run
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::login1::{InhibitMode, InhibitWhat};
+//!
+//! let conn = Connection::new().unwrap();
+//! let lock = conn.login1()
+//!     .inhibit(InhibitWhat::SHUTDOWN | InhibitWhat::SLEEP, "db",
+//!              "Flushing to disk", InhibitMode::Block)
+//!     .unwrap();
+//! // flush
+//! drop(lock);
+//! ```
+//!
+//! Sessions, users and seats have their own proxies:
+//!
+//! ```no_run
+//! use systemd_dbus::Connection;
+//!
+//! let conn = Connection::new().unwrap();
+//! let login1 = conn.login1();
+//! for info in login1.list_sessions().unwrap() {
+//!     if login1.session(&info.id).unwrap().remote().unwrap() {
+//!         println!("{} is logged in remotely", info.user);
+//!     }
+//! }
+//! login1.set_user_linger(990, true, false).unwrap();
+//! ```
+//!
+//! See:
+//!   http://www.freedesktop.org/wiki/Software/systemd/logind/
+//!   http://www.freedesktop.org/wiki/Software/systemd/inhibit/
+
+use std::convert::Infallible;
+use std::fmt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dbus::arg::messageitem::MessageItem;
+use rustc_serialize;
+
+use match_rule::MatchRule;
+use serialize::{decode, UnixFd};
+use {Connection, ObjectPath, Signal, SystemdError, SystemdResult};
+
+static SERVICE : &str = "org.freedesktop.login1";
+static PATH : &str = "/org/freedesktop/login1";
+static MANAGER_INTERFACE : &str = "org.freedesktop.login1.Manager";
+static SESSION_INTERFACE : &str = "org.freedesktop.login1.Session";
+static USER_INTERFACE : &str = "org.freedesktop.login1.User";
+static SEAT_INTERFACE : &str = "org.freedesktop.login1.Seat";
+
+/// Whether an operation is possible, as returned by the CanX methods.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Capability {
+    /// The caller may do it
+    Yes,
+    /// The caller may not do it
+    No,
+    /// The caller may do it after authorizing
+    Challenge,
+    /// The system does not support it
+    NotApplicable,
+    /// A value not known to this crate
+    Other(String)
+}
+
+impl<'a> From<&'a str> for Capability {
+    /// Parse the string form used by logind.
+    fn from(s: &'a str) -> Capability {
+        match s {
+            "yes" => Capability::Yes,
+            "no" => Capability::No,
+            "challenge" => Capability::Challenge,
+            "na" => Capability::NotApplicable,
+            other => Capability::Other(other.to_string())
+        }
+    }
+}
+
+impl FromStr for Capability {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Capability, Infallible> {
+        Ok(Capability::from(s))
+    }
+}
+
+impl Capability {
+    /// The string form used by logind.
+    pub fn as_str(&self) -> &str {
+        match self {
+            &Capability::Yes => "yes",
+            &Capability::No => "no",
+            &Capability::Challenge => "challenge",
+            &Capability::NotApplicable => "na",
+            Capability::Other(s) => s
+        }
+    }
+}
+
+impl rustc_serialize::Decodable for Capability {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<Capability, D::Error> {
+        Ok(Capability::from(d.read_str()?.as_str()))
+    }
+}
+
+/// What a scheduled shutdown does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownType {
+    /// Power off
+    PowerOff,
+    /// Reboot
+    Reboot,
+    /// Halt
+    Halt,
+    /// Warn users of a power off, without powering off
+    DryPowerOff,
+    /// Warn users of a reboot, without rebooting
+    DryReboot,
+    /// Warn users of a halt, without halting
+    DryHalt
+}
+
+impl FromStr for ShutdownType {
+    type Err = SystemdError;
+
+    /// Parse the string form used by logind.
+    fn from_str(s: &str) -> SystemdResult<ShutdownType> {
+        match s {
+            "poweroff" => Ok(ShutdownType::PowerOff),
+            "reboot" => Ok(ShutdownType::Reboot),
+            "halt" => Ok(ShutdownType::Halt),
+            "dry-poweroff" => Ok(ShutdownType::DryPowerOff),
+            "dry-reboot" => Ok(ShutdownType::DryReboot),
+            "dry-halt" => Ok(ShutdownType::DryHalt),
+            other => Err(SystemdError::InvalidArg(
+                format!("unknown shutdown type: {:?}", other)))
+        }
+    }
+}
+
+impl ShutdownType {
+    /// The string form used by logind.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ShutdownType::PowerOff => "poweroff",
+            ShutdownType::Reboot => "reboot",
+            ShutdownType::Halt => "halt",
+            ShutdownType::DryPowerOff => "dry-poweroff",
+            ShutdownType::DryReboot => "dry-reboot",
+            ShutdownType::DryHalt => "dry-halt"
+        }
+    }
+}
+
+bitflags! {
+    /// What an inhibitor lock inhibits.
+    pub struct InhibitWhat: u32 {
+        /// Power off, reboot and halt
+        const SHUTDOWN = 1 << 0;
+        /// Suspend and hibernate
+        const SLEEP = 1 << 1;
+        /// Going idle, e.g. blanking the screen
+        const IDLE = 1 << 2;
+        /// logind's handling of the power key
+        const HANDLE_POWER_KEY = 1 << 3;
+        /// logind's handling of the suspend key
+        const HANDLE_SUSPEND_KEY = 1 << 4;
+        /// logind's handling of the hibernate key
+        const HANDLE_HIBERNATE_KEY = 1 << 5;
+        /// logind's handling of the lid switch
+        const HANDLE_LID_SWITCH = 1 << 6;
+    }
+}
+
+static INHIBIT_NAMES: [(InhibitWhat, &str); 7] = [
+    (InhibitWhat::SHUTDOWN, "shutdown"),
+    (InhibitWhat::SLEEP, "sleep"),
+    (InhibitWhat::IDLE, "idle"),
+    (InhibitWhat::HANDLE_POWER_KEY, "handle-power-key"),
+    (InhibitWhat::HANDLE_SUSPEND_KEY, "handle-suspend-key"),
+    (InhibitWhat::HANDLE_HIBERNATE_KEY, "handle-hibernate-key"),
+    (InhibitWhat::HANDLE_LID_SWITCH, "handle-lid-switch")];
+
+impl<'a> From<&'a str> for InhibitWhat {
+    /// Parse the colon separated form used by logind, ignoring names
+    /// not known to this crate.
+    fn from(s: &'a str) -> InhibitWhat {
+        let mut what = InhibitWhat::empty();
+        for name in s.split(':') {
+            if let Some(&(flag, _)) = INHIBIT_NAMES.iter().find(|n| n.1 == name) {
+                what.insert(flag);
+            }
+        }
+        what
+    }
+}
+
+impl FromStr for InhibitWhat {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<InhibitWhat, Infallible> {
+        Ok(InhibitWhat::from(s))
+    }
+}
+
+impl fmt::Display for InhibitWhat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let names: Vec<&str> = INHIBIT_NAMES.iter()
+            .filter(|n| self.contains(n.0))
+            .map(|n| n.1)
+            .collect();
+        write!(f, "{}", names.join(":"))
+    }
+}
+
+impl rustc_serialize::Decodable for InhibitWhat {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<InhibitWhat, D::Error> {
+        Ok(InhibitWhat::from(d.read_str()?.as_str()))
+    }
+}
+
+impl rustc_serialize::Encodable for InhibitWhat {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(&self.to_string())
+    }
+}
+
+type_signature!(InhibitWhat => "s");
+
+/// How an inhibitor lock inhibits.
+#[derive(Clone, Debug, PartialEq)]
+pub enum InhibitMode {
+    /// Prevent the operation while the lock is held
+    Block,
+    /// Delay the operation until the lock is released, or logind's
+    /// InhibitDelayMaxSec has passed
+    Delay,
+    /// A mode not known to this crate
+    Other(String)
+}
+
+impl<'a> From<&'a str> for InhibitMode {
+    /// Parse the string form used by logind.
+    fn from(s: &'a str) -> InhibitMode {
+        match s {
+            "block" => InhibitMode::Block,
+            "delay" => InhibitMode::Delay,
+            other => InhibitMode::Other(other.to_string())
+        }
+    }
+}
+
+impl FromStr for InhibitMode {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<InhibitMode, Infallible> {
+        Ok(InhibitMode::from(s))
+    }
+}
+
+impl InhibitMode {
+    /// The string form used by logind.
+    pub fn as_str(&self) -> &str {
+        match self {
+            &InhibitMode::Block => "block",
+            &InhibitMode::Delay => "delay",
+            InhibitMode::Other(s) => s
+        }
+    }
+}
+
+impl rustc_serialize::Decodable for InhibitMode {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<InhibitMode, D::Error> {
+        Ok(InhibitMode::from(d.read_str()?.as_str()))
+    }
+}
+
+impl rustc_serialize::Encodable for InhibitMode {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(self.as_str())
+    }
+}
+
+type_signature!(InhibitMode => "s");
+
+/// An inhibitor lock held by some process, as listed by logind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InhibitorInfo {
+    /// What is inhibited
+    pub what: InhibitWhat,
+    /// Who holds the lock, e.g. an application name
+    pub who: String,
+    /// Why the lock is held
+    pub why: String,
+    /// How it inhibits
+    pub mode: InhibitMode,
+    /// The user holding the lock
+    pub uid: u32,
+    /// The process holding the lock
+    pub pid: u32
+}
+decodable_struct!(InhibitorInfo { what, who, why, mode, uid, pid });
+encodable_struct!(InhibitorInfo { what, who, why, mode, uid, pid });
+
+/// An inhibitor lock taken by this process.  The lock is released
+/// when this is dropped, by closing the file descriptor logind
+/// returned for it.
+#[derive(Debug)]
+pub struct Inhibitor {
+    fd: UnixFd,
+    what: InhibitWhat,
+    mode: InhibitMode
+}
+
+impl Inhibitor {
+    /// What the lock inhibits.
+    pub fn what(&self) -> InhibitWhat {
+        self.what
+    }
+
+    /// How the lock inhibits.
+    pub fn mode(&self) -> &InhibitMode {
+        &self.mode
+    }
+
+    /// Release the lock.
+    pub fn release(self) {
+    }
+}
+
+impl AsRawFd for Inhibitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Microseconds since the epoch, as logind represents times.
+fn to_usec(time: SystemTime) -> SystemdResult<u64> {
+    let since = time.duration_since(UNIX_EPOCH).map_err(|_| {
+        SystemdError::InvalidArg("time before the epoch".to_string())
+    })?;
+    Ok(since.as_secs() * 1_000_000 + since.subsec_micros() as u64)
+}
+
+fn from_usec(usec: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(usec / 1_000_000, ((usec % 1_000_000) * 1_000) as u32)
+}
+
+/// The login manager, on the same bus as the connection.
+pub struct Manager<'c> {
+    conn: &'c Connection
+}
+
+impl Connection {
+    /// A proxy for the login manager.
+    pub fn login1(&self) -> Manager<'_> {
+        Manager{ conn: self }
+    }
+}
+
+impl<'c> Manager<'c> {
+    fn call(&self, method: &str, args: &[MessageItem])
+            -> SystemdResult<Vec<MessageItem>> {
+        self.conn.call_service(&self.conn.bus, SERVICE, PATH,
+                               MANAGER_INTERFACE, method, args, Ok)
+    }
+
+    fn capability(&self, method: &str) -> SystemdResult<Capability> {
+        Ok(decode(self.call(method, &[])?)?)
+    }
+
+    /// Return property `name` of the login manager.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_service_property(SERVICE, PATH, MANAGER_INTERFACE, name)
+    }
+
+    /// Power off the system, once inhibitor locks allow.
+    pub fn power_off(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("PowerOff", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Reboot the system, once inhibitor locks allow.
+    pub fn reboot(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("Reboot", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Halt the system, once inhibitor locks allow.  Requires logind
+    /// 237 or later.
+    pub fn halt(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("Halt", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Suspend the system to RAM, once inhibitor locks allow.
+    pub fn suspend(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("Suspend", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Hibernate the system to disk, once inhibitor locks allow.
+    pub fn hibernate(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("Hibernate", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Suspend the system to RAM and disk, once inhibitor locks allow.
+    pub fn hybrid_sleep(&self, interactive: bool) -> SystemdResult<()> {
+        self.call("HybridSleep", &[MessageItem::Bool(interactive)])?;
+        Ok(())
+    }
+
+    /// Whether the caller may power off the system.
+    pub fn can_power_off(&self) -> SystemdResult<Capability> {
+        self.capability("CanPowerOff")
+    }
+
+    /// Whether the caller may reboot the system.
+    pub fn can_reboot(&self) -> SystemdResult<Capability> {
+        self.capability("CanReboot")
+    }
+
+    /// Whether the caller may halt the system.  Requires logind 237
+    /// or later.
+    pub fn can_halt(&self) -> SystemdResult<Capability> {
+        self.capability("CanHalt")
+    }
+
+    /// Whether the caller may suspend the system.
+    pub fn can_suspend(&self) -> SystemdResult<Capability> {
+        self.capability("CanSuspend")
+    }
+
+    /// Whether the caller may hibernate the system.
+    pub fn can_hibernate(&self) -> SystemdResult<Capability> {
+        self.capability("CanHibernate")
+    }
+
+    /// Whether the caller may hybrid-sleep the system.
+    pub fn can_hybrid_sleep(&self) -> SystemdResult<Capability> {
+        self.capability("CanHybridSleep")
+    }
+
+    /// Shut down at `when`, replacing any scheduled shutdown.  logind
+    /// warns logged in users beforehand, and blocks new logins in the
+    /// last five minutes.
+    pub fn schedule_shutdown(&self, kind: ShutdownType, when: SystemTime)
+                             -> SystemdResult<()> {
+        let args = [MessageItem::Str(kind.as_str().to_string()),
+                    MessageItem::UInt64(to_usec(when)?)];
+        self.call("ScheduleShutdown", &args)?;
+        Ok(())
+    }
+
+    /// Cancel the scheduled shutdown, returning whether there was one.
+    pub fn cancel_scheduled_shutdown(&self) -> SystemdResult<bool> {
+        Ok(decode(self.call("CancelScheduledShutdown", &[])?)?)
+    }
+
+    /// Take an inhibitor lock for `what`, held until the returned
+    /// Inhibitor is dropped.  `who` and `why` are shown to users, e.g.
+    /// by `systemd-inhibit --list`.  Delay locks are usually taken in
+    /// response to the PrepareForShutdown or PrepareForSleep signal.
+    pub fn inhibit(&self, what: InhibitWhat, who: &str, why: &str,
+                   mode: InhibitMode) -> SystemdResult<Inhibitor> {
+        if what.is_empty() {
+            return Err(SystemdError::InvalidArg("nothing to inhibit".to_string()))
+        }
+        let args = [MessageItem::Str(what.to_string()),
+                    MessageItem::Str(who.to_string()),
+                    MessageItem::Str(why.to_string()),
+                    MessageItem::Str(mode.as_str().to_string())];
+        let fd = decode(self.call("Inhibit", &args)?)?;
+        Ok(Inhibitor{ fd, what, mode })
+    }
+
+    /// List the inhibitor locks held by all processes.
+    pub fn list_inhibitors(&self) -> SystemdResult<Vec<InhibitorInfo>> {
+        Ok(decode(self.call("ListInhibitors", &[])?)?)
+    }
+
+    /// The scheduled shutdown, if any.
+    pub fn scheduled_shutdown(&self)
+                              -> SystemdResult<Option<(ShutdownType, SystemTime)>> {
+        let (kind, usec): (String, u64) =
+            decode(vec![self.property("ScheduledShutdown")?])?;
+        Ok(kind.parse().ok().map(|kind| (kind, from_usec(usec))))
+    }
+}
+
+/// A session, as listed by logind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    /// The session id, e.g. "c1"
+    pub id: String,
+    /// The user owning the session
+    pub uid: u32,
+    /// The name of the user owning the session
+    pub user: String,
+    /// The seat the session is on, or empty for remote sessions
+    pub seat: String,
+    /// The session's object path
+    pub path: ObjectPath
+}
+decodable_struct!(SessionInfo { id, uid, user, seat, path });
+encodable_struct!(SessionInfo { id, uid, user, seat, path });
+
+/// A logged in user, as listed by logind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserInfo {
+    /// The user id
+    pub uid: u32,
+    /// The user name
+    pub name: String,
+    /// The user's object path
+    pub path: ObjectPath
+}
+decodable_struct!(UserInfo { uid, name, path });
+encodable_struct!(UserInfo { uid, name, path });
+
+/// A seat, as listed by logind.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeatInfo {
+    /// The seat id, e.g. "seat0"
+    pub id: String,
+    /// The seat's object path
+    pub path: ObjectPath
+}
+decodable_struct!(SeatInfo { id, path });
+
+/// A signal of the login manager.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManagerSignal {
+    /// A session was created, with its id and object path
+    SessionNew(String, ObjectPath),
+    /// A session was removed, with its id and object path
+    SessionRemoved(String, ObjectPath),
+    /// A user logged in, with their uid and object path
+    UserNew(u32, ObjectPath),
+    /// A user logged out, with their uid and object path
+    UserRemoved(u32, ObjectPath),
+    /// The system is about to shut down (true), or a shutdown was
+    /// cancelled (false); delay locks are released on true
+    PrepareForShutdown(bool),
+    /// The system is about to sleep (true), or has resumed (false)
+    PrepareForSleep(bool)
+}
+
+impl ManagerSignal {
+    /// Decode `signal`, or return None if it is not a login manager
+    /// signal.
+    pub fn from_signal(signal: &Signal) -> SystemdResult<Option<ManagerSignal>> {
+        if signal.interface.as_ref().map(|s| &s[..]) != Some(MANAGER_INTERFACE) {
+            return Ok(None)
+        }
+        let member = signal.member.as_ref().map(|s| &s[..]).unwrap_or("");
+        Ok(Some(match member {
+            "SessionNew" => {
+                let (id, path) = signal.decode()?;
+                ManagerSignal::SessionNew(id, path)
+            },
+            "SessionRemoved" => {
+                let (id, path) = signal.decode()?;
+                ManagerSignal::SessionRemoved(id, path)
+            },
+            "UserNew" => {
+                let (uid, path) = signal.decode()?;
+                ManagerSignal::UserNew(uid, path)
+            },
+            "UserRemoved" => {
+                let (uid, path) = signal.decode()?;
+                ManagerSignal::UserRemoved(uid, path)
+            },
+            "PrepareForShutdown" =>
+                ManagerSignal::PrepareForShutdown(signal.decode()?),
+            "PrepareForSleep" =>
+                ManagerSignal::PrepareForSleep(signal.decode()?),
+            _ => return Ok(None)
+        }))
+    }
+}
+
+/// Call `method` of `interface` on logind's object at `path`.
+fn call_object(conn: &Connection, path: &str, interface: &str, method: &str,
+               args: &[MessageItem]) -> SystemdResult<Vec<MessageItem>> {
+    conn.call_service(&conn.bus, SERVICE, path, interface, method, args,
+                      Ok)
+}
+
+/// Return property `name` of `interface` on logind's object at `path`.
+fn object_property<T: rustc_serialize::Decodable>(conn: &Connection, path: &str,
+                                                  interface: &str, name: &str)
+                                                  -> SystemdResult<T> {
+    let value = conn.get_service_property(SERVICE, path, interface, name)?;
+    Ok(decode(vec![value])?)
+}
+
+impl<'c> Manager<'c> {
+    /// Subscribe to the login manager's signals, which are then
+    /// returned by `Connection::next_signal`; decode them with
+    /// `ManagerSignal::from_signal`.
+    pub fn add_signal_match(&self) -> SystemdResult<()> {
+        self.conn.add_match_rule(&MatchRule::signal()
+                                 .sender(SERVICE)
+                                 .interface(MANAGER_INTERFACE))
+    }
+
+    /// List the sessions.
+    pub fn list_sessions(&self) -> SystemdResult<Vec<SessionInfo>> {
+        Ok(decode(self.call("ListSessions", &[])?)?)
+    }
+
+    /// List the logged in users.
+    pub fn list_users(&self) -> SystemdResult<Vec<UserInfo>> {
+        Ok(decode(self.call("ListUsers", &[])?)?)
+    }
+
+    /// List the seats.
+    pub fn list_seats(&self) -> SystemdResult<Vec<SeatInfo>> {
+        Ok(decode(self.call("ListSeats", &[])?)?)
+    }
+
+    fn path(&self, method: &str, arg: MessageItem) -> SystemdResult<ObjectPath> {
+        Ok(decode(self.call(method, &[arg])?)?)
+    }
+
+    /// The session with id `id`.
+    pub fn session(&self, id: &str) -> SystemdResult<Session<'c>> {
+        let path = self.path("GetSession", MessageItem::Str(id.to_string()))?;
+        Ok(Session{ conn: self.conn, path })
+    }
+
+    /// The session process `pid` belongs to.
+    pub fn session_by_pid(&self, pid: u32) -> SystemdResult<Session<'c>> {
+        let path = self.path("GetSessionByPID", MessageItem::UInt32(pid))?;
+        Ok(Session{ conn: self.conn, path })
+    }
+
+    /// The logged in user `uid`.
+    pub fn user(&self, uid: u32) -> SystemdResult<User<'c>> {
+        let path = self.path("GetUser", MessageItem::UInt32(uid))?;
+        Ok(User{ conn: self.conn, path })
+    }
+
+    /// The seat with id `id`.
+    pub fn seat(&self, id: &str) -> SystemdResult<Seat<'c>> {
+        let path = self.path("GetSeat", MessageItem::Str(id.to_string()))?;
+        Ok(Seat{ conn: self.conn, path })
+    }
+
+    /// End session `id`, killing its processes.
+    pub fn terminate_session(&self, id: &str) -> SystemdResult<()> {
+        self.call("TerminateSession", &[MessageItem::Str(id.to_string())])?;
+        Ok(())
+    }
+
+    /// End all sessions of user `uid`, killing their processes.
+    pub fn terminate_user(&self, uid: u32) -> SystemdResult<()> {
+        self.call("TerminateUser", &[MessageItem::UInt32(uid)])?;
+        Ok(())
+    }
+
+    /// Send `signal` to the processes of session `id`; `who` is
+    /// "leader" or "all".
+    pub fn kill_session(&self, id: &str, who: &str, signal: i32)
+                        -> SystemdResult<()> {
+        let args = [MessageItem::Str(id.to_string()),
+                    MessageItem::Str(who.to_string()),
+                    MessageItem::Int32(signal)];
+        self.call("KillSession", &args)?;
+        Ok(())
+    }
+
+    /// Send `signal` to all processes of user `uid`.
+    pub fn kill_user(&self, uid: u32, signal: i32) -> SystemdResult<()> {
+        self.call("KillUser", &[MessageItem::UInt32(uid),
+                                MessageItem::Int32(signal)])?;
+        Ok(())
+    }
+
+    /// Ask the screen lockers of all sessions to lock.
+    pub fn lock_sessions(&self) -> SystemdResult<()> {
+        self.call("LockSessions", &[])?;
+        Ok(())
+    }
+
+    /// Ask the screen lockers of all sessions to unlock.
+    pub fn unlock_sessions(&self) -> SystemdResult<()> {
+        self.call("UnlockSessions", &[])?;
+        Ok(())
+    }
+
+    /// Keep the `systemd --user` manager of user `uid` running when
+    /// they are not logged in, as `loginctl enable-linger` does, or stop
+    /// doing so.
+    pub fn set_user_linger(&self, uid: u32, enable: bool, interactive: bool)
+                           -> SystemdResult<()> {
+        let args = [MessageItem::UInt32(uid), MessageItem::Bool(enable),
+                    MessageItem::Bool(interactive)];
+        self.call("SetUserLinger", &args)?;
+        Ok(())
+    }
+}
+
+/// A session, called through its own object.
+pub struct Session<'c> {
+    conn: &'c Connection,
+    path: ObjectPath
+}
+
+impl<'c> Session<'c> {
+    /// The session's object path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return property `name` of the Session interface.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_service_property(SERVICE, &self.path, SESSION_INTERFACE, name)
+    }
+
+    /// The session id.
+    pub fn id(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "Id")
+    }
+
+    /// The uid of the user owning the session.
+    pub fn uid(&self) -> SystemdResult<u32> {
+        let (uid, _): (u32, ObjectPath) =
+            object_property(self.conn, &self.path, SESSION_INTERFACE, "User")?;
+        Ok(uid)
+    }
+
+    /// The session's state: "online", "active" or "closing".
+    pub fn state(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "State")
+    }
+
+    /// Whether the session is remote, e.g. over ssh.
+    pub fn remote(&self) -> SystemdResult<bool> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "Remote")
+    }
+
+    /// The session's type, e.g. "tty" or "x11".
+    pub fn session_type(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "Type")
+    }
+
+    /// The session's class, e.g. "user" or "greeter".
+    pub fn class(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "Class")
+    }
+
+    /// The PID of the session's leader process.
+    pub fn leader(&self) -> SystemdResult<u32> {
+        object_property(self.conn, &self.path, SESSION_INTERFACE, "Leader")
+    }
+
+    /// End the session, killing its processes.
+    pub fn terminate(&self) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, SESSION_INTERFACE, "Terminate", &[])?;
+        Ok(())
+    }
+
+    /// Ask the session's screen locker to lock.
+    pub fn lock(&self) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, SESSION_INTERFACE, "Lock", &[])?;
+        Ok(())
+    }
+
+    /// Ask the session's screen locker to unlock.
+    pub fn unlock(&self) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, SESSION_INTERFACE, "Unlock", &[])?;
+        Ok(())
+    }
+}
+
+/// A logged in user, called through their own object.
+pub struct User<'c> {
+    conn: &'c Connection,
+    path: ObjectPath
+}
+
+impl<'c> User<'c> {
+    /// The user's object path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return property `name` of the User interface.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_service_property(SERVICE, &self.path, USER_INTERFACE, name)
+    }
+
+    /// The user name.
+    pub fn name(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, USER_INTERFACE, "Name")
+    }
+
+    /// The user's state: "offline", "lingering", "online", "active"
+    /// or "closing".
+    pub fn state(&self) -> SystemdResult<String> {
+        object_property(self.conn, &self.path, USER_INTERFACE, "State")
+    }
+
+    /// Whether the user's manager is kept running when they are not
+    /// logged in.
+    pub fn linger(&self) -> SystemdResult<bool> {
+        object_property(self.conn, &self.path, USER_INTERFACE, "Linger")
+    }
+
+    /// The user's sessions, as (id, path).
+    pub fn sessions(&self) -> SystemdResult<Vec<(String, ObjectPath)>> {
+        object_property(self.conn, &self.path, USER_INTERFACE, "Sessions")
+    }
+
+    /// End all the user's sessions, killing their processes.
+    pub fn terminate(&self) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, USER_INTERFACE, "Terminate", &[])?;
+        Ok(())
+    }
+
+    /// Send `signal` to all the user's processes.
+    pub fn kill(&self, signal: i32) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, USER_INTERFACE, "Kill",
+                    &[MessageItem::Int32(signal)])?;
+        Ok(())
+    }
+}
+
+/// A seat, called through its own object.
+pub struct Seat<'c> {
+    conn: &'c Connection,
+    path: ObjectPath
+}
+
+impl<'c> Seat<'c> {
+    /// The seat's object path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Return property `name` of the Seat interface.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_service_property(SERVICE, &self.path, SEAT_INTERFACE, name)
+    }
+
+    /// The active session, as (id, path), with an empty id if none.
+    pub fn active_session(&self) -> SystemdResult<(String, ObjectPath)> {
+        object_property(self.conn, &self.path, SEAT_INTERFACE, "ActiveSession")
+    }
+
+    /// The sessions on the seat, as (id, path).
+    pub fn sessions(&self) -> SystemdResult<Vec<(String, ObjectPath)>> {
+        object_property(self.conn, &self.path, SEAT_INTERFACE, "Sessions")
+    }
+
+    /// End all sessions on the seat, killing their processes.
+    pub fn terminate(&self) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, SEAT_INTERFACE, "Terminate", &[])?;
+        Ok(())
+    }
+
+    /// Switch the seat to session `id`.
+    pub fn activate_session(&self, id: &str) -> SystemdResult<()> {
+        call_object(self.conn, &self.path, SEAT_INTERFACE, "ActivateSession",
+                    &[MessageItem::Str(id.to_string())])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::{from_usec, to_usec};
+    use std::time::{Duration, UNIX_EPOCH};
+    use dbus::arg::messageitem::MessageItem;
+    use mock::MockSystemd;
+    use {Connection, ObjectPath};
+
+    #[test]
+    fn power_management() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let login1 = conn.login1();
+        assert_eq!(Capability::Yes, login1.can_reboot().unwrap());
+        assert_eq!(Capability::NotApplicable, login1.can_hibernate().unwrap());
+        login1.reboot(true).unwrap();
+        assert_eq!(MessageItem::Bool(true), mock.calls()[2].args[0]);
+        assert_eq!(Capability::Yes, login1.can_halt().unwrap());
+        login1.halt(false).unwrap();
+        assert_eq!("Halt", mock.calls()[4].method);
+        assert_eq!(MessageItem::Bool(false), mock.calls()[4].args[0]);
+        mock.clear_calls();
+        let when = UNIX_EPOCH + Duration::from_secs(1500000000);
+        login1.schedule_shutdown(ShutdownType::DryReboot, when).unwrap();
+        assert_eq!(vec![MessageItem::Str("dry-reboot".to_string()),
+                        MessageItem::UInt64(1500000000000000)],
+                   mock.calls()[0].args);
+        assert!(login1.cancel_scheduled_shutdown().unwrap());
+        assert!(!login1.cancel_scheduled_shutdown().unwrap());
+    }
+
+    #[test]
+    fn scheduled_shutdown() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let login1 = conn.login1();
+        assert_eq!(None, login1.scheduled_shutdown().unwrap());
+        let when = UNIX_EPOCH + Duration::from_secs(1500000000);
+        login1.schedule_shutdown(ShutdownType::PowerOff, when).unwrap();
+        assert_eq!(Some((ShutdownType::PowerOff, when)),
+                   login1.scheduled_shutdown().unwrap());
+        login1.cancel_scheduled_shutdown().unwrap();
+        assert_eq!(None, login1.scheduled_shutdown().unwrap());
+    }
+
+    #[test]
+    fn inhibitor_lock() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let login1 = conn.login1();
+        assert!(login1.inhibit(InhibitWhat::empty(), "test", "testing",
+                               InhibitMode::Block).is_err());
+        let lock = login1.inhibit(InhibitWhat::SHUTDOWN | InhibitWhat::SLEEP, "test",
+                                  "testing", InhibitMode::Delay).unwrap();
+        assert_eq!(MessageItem::Str("shutdown:sleep".to_string()),
+                   mock.calls()[0].args[0]);
+        let held = login1.list_inhibitors().unwrap();
+        assert_eq!(1, held.len());
+        assert_eq!(InhibitWhat::SHUTDOWN | InhibitWhat::SLEEP, held[0].what);
+        assert_eq!(InhibitMode::Delay, held[0].mode);
+        assert_eq!("testing", held[0].why);
+        lock.release();
+        assert!(login1.list_inhibitors().unwrap().is_empty());
+    }
+
+    #[test]
+    fn inhibit_what() {
+        assert_eq!(InhibitWhat::IDLE | InhibitWhat::HANDLE_LID_SWITCH,
+                   InhibitWhat::from("idle:handle-lid-switch:unknown"));
+        assert_eq!("shutdown:handle-power-key",
+                   (InhibitWhat::SHUTDOWN | InhibitWhat::HANDLE_POWER_KEY).to_string());
+    }
+
+    #[test]
+    fn sessions() {
+        let mock = MockSystemd::start().unwrap();
+        mock.add_session("c1", 990, "builder", "");
+        mock.add_session("2", 1000, "operator", "seat0");
+        let conn = Connection::open(mock.bus()).unwrap();
+        let login1 = conn.login1();
+        login1.add_signal_match().unwrap();
+        let sessions = login1.list_sessions().unwrap();
+        assert_eq!(2, sessions.len());
+        assert_eq!("builder", sessions[0].user);
+        assert_eq!("/org/freedesktop/login1/session/c1", sessions[0].path);
+        assert_eq!(vec![990, 1000], login1.list_users().unwrap().iter()
+                   .map(|u| u.uid).collect::<Vec<_>>());
+        assert_eq!("/org/freedesktop/login1/session/c1",
+                   login1.session_by_pid(1).unwrap().path());
+        login1.set_user_linger(990, true, false).unwrap();
+        assert_eq!(vec![MessageItem::UInt32(990), MessageItem::Bool(true),
+                        MessageItem::Bool(false)],
+                   mock.calls().last().unwrap().args);
+        login1.terminate_session("c1").unwrap();
+        assert_eq!(1, login1.list_sessions().unwrap().len());
+        let signal = conn.next_signal(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(Some(ManagerSignal::SessionRemoved(
+            "c1".to_string(),
+            ObjectPath::new("/org/freedesktop/login1/session/c1").unwrap())),
+                   ManagerSignal::from_signal(&signal).unwrap());
+        mock.emit_prepare_for_sleep(true);
+        let signal = conn.next_signal(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(Some(ManagerSignal::PrepareForSleep(true)),
+                   ManagerSignal::from_signal(&signal).unwrap());
+    }
+
+    #[test]
+    fn usec_conversion() {
+        let when = UNIX_EPOCH + Duration::new(12, 345678000);
+        assert_eq!(12345678, to_usec(when).unwrap());
+        assert_eq!(when, from_usec(12345678));
+        assert!(to_usec(UNIX_EPOCH - Duration::from_secs(1)).is_err());
+        assert!("".parse::<ShutdownType>().is_err());
+        assert_eq!(ShutdownType::DryReboot, "dry-reboot".parse().unwrap());
+    }
+}
@@ -0,0 +1,607 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! DBus match rules.
+//!
+//! A `MatchRule` is built key by key, validated, and rendered to the
+//! rule string passed to `AddMatch` and `RemoveMatch`, with values
+//! quoted and escaped as the DBus specification requires.  Rule
+//! strings can be parsed back into a `MatchRule`, and a rule can be
+//! checked against a received signal locally.
+//!
+//! ```
+//! use systemd_dbus::match_rule::MatchRule;
+//!
+//! let rule = MatchRule::signal()
+//!     .interface("org.freedesktop.DBus.Properties")
+//!     .member("PropertiesChanged")
+//!     .path_namespace("/org/freedesktop/systemd1/unit");
+//! assert_eq!("type='signal',interface='org.freedesktop.DBus.Properties',\
+//!             member='PropertiesChanged',\
+//!             path_namespace='/org/freedesktop/systemd1/unit'",
+//!            rule.to_string());
+//! ```
+//!
+//! See:
+//!   http://dbus.freedesktop.org/doc/dbus-specification.html#message-bus-routing-match-rules
+
+use std::fmt;
+use std::str::FromStr;
+
+use dbus::arg::messageitem::MessageItem;
+
+use Signal;
+
+/// The highest argument index a match rule may refer to.
+pub const MAX_ARG_INDEX: u8 = 63;
+
+/// Errors in building or parsing a match rule.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MatchRuleError {
+    /// The rule string could not be parsed
+    Syntax(String),
+    /// The rule string contains a key that is not a match rule key
+    UnknownKey(String),
+    /// A key has an invalid value
+    InvalidValue(String, String),
+    /// Two keys may not be used in the same rule
+    Conflict(String, String)
+}
+
+impl fmt::Display for MatchRuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            MatchRuleError::Syntax(s) => write!(f, "syntax error: {}", s),
+            MatchRuleError::UnknownKey(k) => write!(f, "unknown key {}", k),
+            MatchRuleError::InvalidValue(k, v) =>
+                write!(f, "invalid value for {}: {}", k, v),
+            MatchRuleError::Conflict(a, b) =>
+                write!(f, "{} may not be used with {}", a, b)
+        }
+    }
+}
+
+/// Message types that a rule can match.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageType {
+    /// type='signal'
+    Signal,
+    /// type='method_call'
+    MethodCall,
+    /// type='method_return'
+    MethodReturn,
+    /// type='error'
+    Error
+}
+
+impl MessageType {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MessageType::Signal => "signal",
+            MessageType::MethodCall => "method_call",
+            MessageType::MethodReturn => "method_return",
+            MessageType::Error => "error"
+        }
+    }
+}
+
+/// Condition on a message argument.
+#[derive(Clone, Debug, PartialEq)]
+enum ArgMatch {
+    /// argN='…', the argument is a string equal to the value
+    Str(u8, String),
+    /// argNpath='…', the argument is a path-like string equal to, or
+    /// in a namespace with, the value
+    Path(u8, String),
+    /// arg0namespace='…', argument 0 is a bus or interface name in the
+    /// namespace of the value
+    Namespace(String)
+}
+
+/// A DBus match rule.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct MatchRule {
+    message_type: Option<MessageType>,
+    sender: Option<String>,
+    interface: Option<String>,
+    member: Option<String>,
+    path: Option<String>,
+    path_namespace: Option<String>,
+    destination: Option<String>,
+    args: Vec<ArgMatch>,
+    eavesdrop: Option<bool>
+}
+
+impl MatchRule {
+    /// Return an empty rule, which matches every message.
+    pub fn new() -> MatchRule {
+        Default::default()
+    }
+
+    /// Return a rule matching signals.
+    pub fn signal() -> MatchRule {
+        MatchRule::new().message_type(MessageType::Signal)
+    }
+
+    /// Match messages of type `t`.
+    pub fn message_type(mut self, t: MessageType) -> MatchRule {
+        self.message_type = Some(t);
+        self
+    }
+
+    /// Match messages sent by bus name `s`.
+    pub fn sender(mut self, s: &str) -> MatchRule {
+        self.sender = Some(s.to_string());
+        self
+    }
+
+    /// Match messages on interface `s`.
+    pub fn interface(mut self, s: &str) -> MatchRule {
+        self.interface = Some(s.to_string());
+        self
+    }
+
+    /// Match messages with method or signal name `s`.
+    pub fn member(mut self, s: &str) -> MatchRule {
+        self.member = Some(s.to_string());
+        self
+    }
+
+    /// Match messages to or from object path `s`.
+    pub fn path(mut self, s: &str) -> MatchRule {
+        self.path = Some(s.to_string());
+        self
+    }
+
+    /// Match messages to or from object path `s`, or any path below it.
+    pub fn path_namespace(mut self, s: &str) -> MatchRule {
+        self.path_namespace = Some(s.to_string());
+        self
+    }
+
+    /// Match messages sent to unique bus name `s`.
+    pub fn destination(mut self, s: &str) -> MatchRule {
+        self.destination = Some(s.to_string());
+        self
+    }
+
+    /// Match messages whose argument `n` is the string `s`.
+    pub fn arg(mut self, n: u8, s: &str) -> MatchRule {
+        self.args.push(ArgMatch::Str(n, s.to_string()));
+        self
+    }
+
+    /// Match messages whose argument `n` is a path equal to `s`, or
+    /// where one is a prefix of the other ending in '/'.
+    pub fn arg_path(mut self, n: u8, s: &str) -> MatchRule {
+        self.args.push(ArgMatch::Path(n, s.to_string()));
+        self
+    }
+
+    /// Match messages whose first argument is the bus or interface
+    /// name `s`, or a name below it, e.g. `org.freedesktop.systemd1`
+    /// matches `org.freedesktop.systemd1.Manager`.
+    pub fn arg0_namespace(mut self, s: &str) -> MatchRule {
+        self.args.push(ArgMatch::Namespace(s.to_string()));
+        self
+    }
+
+    /// Set whether the rule should also match messages addressed to
+    /// other connections.
+    pub fn eavesdrop(mut self, eavesdrop: bool) -> MatchRule {
+        self.eavesdrop = Some(eavesdrop);
+        self
+    }
+
+    /// Check the rule is acceptable to the bus.
+    pub fn validate(&self) -> Result<(), MatchRuleError> {
+        if self.path.is_some() && self.path_namespace.is_some() {
+            return Err(MatchRuleError::Conflict("path".to_string(),
+                                                "path_namespace".to_string()))
+        }
+        if let Some(ref s) = self.sender {
+            check("sender", s, is_bus_name(s))?;
+        }
+        if let Some(ref s) = self.interface {
+            check("interface", s, is_interface_name(s))?;
+        }
+        if let Some(ref s) = self.member {
+            check("member", s, is_member_name(s))?;
+        }
+        if let Some(ref s) = self.path {
+            check("path", s, is_object_path(s))?;
+        }
+        if let Some(ref s) = self.path_namespace {
+            check("path_namespace", s, is_object_path(s))?;
+        }
+        if let Some(ref s) = self.destination {
+            check("destination", s, is_bus_name(s))?;
+        }
+        let mut seen = vec![];
+        for arg in self.args.iter() {
+            let (key, n, ok) = match arg {
+                &ArgMatch::Str(n, _) => (format!("arg{}", n), n, true),
+                &ArgMatch::Path(n, _) => (format!("arg{}path", n), n, true),
+                ArgMatch::Namespace(s) =>
+                    ("arg0namespace".to_string(), 0, is_namespace(s))
+            };
+            if n > MAX_ARG_INDEX {
+                return Err(MatchRuleError::UnknownKey(key))
+            }
+            if !ok {
+                return Err(MatchRuleError::InvalidValue(key, arg_value(arg)
+                                                        .to_string()))
+            }
+            if let Some((_, other)) = seen.iter()
+                .find(|&&(m, _): &&(u8, String)| m == n) {
+                return Err(MatchRuleError::Conflict(other.clone(), key))
+            }
+            seen.push((n, key));
+        }
+        Ok(())
+    }
+
+    /// Check the rule against a received signal.
+    ///
+    /// `sender` is compared literally, so a rule on a well-known bus
+    /// name does not match signals, which carry the sender's unique
+    /// name.  Rules with a `destination` never match, as signals are
+    /// broadcast.
+    pub fn matches(&self, signal: &Signal) -> bool {
+        fn eq(rule: &Option<String>, value: &Option<String>) -> bool {
+            match (rule, value) {
+                (&None, _) => true,
+                (Some(r), Some(v)) => r == v,
+                (&Some(_), &None) => false
+            }
+        }
+        if let Some(t) = self.message_type {
+            if t != MessageType::Signal {
+                return false
+            }
+        }
+        if self.destination.is_some() {
+            return false
+        }
+        if !eq(&self.sender, &signal.sender) ||
+            !eq(&self.interface, &signal.interface) ||
+            !eq(&self.member, &signal.member) ||
+            !eq(&self.path, &signal.path) {
+            return false
+        }
+        if let Some(ref ns) = self.path_namespace {
+            match signal.path {
+                Some(ref path) if in_path_namespace(ns, path) => (),
+                _ => return false
+            }
+        }
+        self.args.iter().all(|arg| {
+            match arg {
+                &ArgMatch::Str(n, ref s) =>
+                    match signal.items.get(n as usize) {
+                        Some(MessageItem::Str(v)) => v == s,
+                        _ => false
+                    },
+                &ArgMatch::Path(n, ref s) =>
+                    match signal.items.get(n as usize) {
+                        Some(MessageItem::Str(v)) =>
+                            path_args_match(s, v),
+                        Some(MessageItem::ObjectPath(v)) =>
+                            path_args_match(s, v),
+                        _ => false
+                    },
+                ArgMatch::Namespace(ns) =>
+                    match signal.items.first() {
+                        Some(MessageItem::Str(v)) =>
+                            v == ns || (v.starts_with(&ns[..]) &&
+                                        v[ns.len()..].starts_with(".")),
+                        _ => false
+                    }
+            }
+        })
+    }
+}
+
+fn check(key: &str, value: &str, ok: bool) -> Result<(), MatchRuleError> {
+    if ok {
+        Ok(())
+    } else {
+        Err(MatchRuleError::InvalidValue(key.to_string(), value.to_string()))
+    }
+}
+
+fn arg_value(arg: &ArgMatch) -> &str {
+    match arg {
+        ArgMatch::Str(_, s) => s,
+        ArgMatch::Path(_, s) => s,
+        ArgMatch::Namespace(s) => s
+    }
+}
+
+/// Quote `value` for a rule string.  Apostrophes can not appear inside
+/// quotes, so are written as '\''.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace("'", "'\\''"))
+}
+
+impl fmt::Display for MatchRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let mut parts = vec![];
+        if let Some(t) = self.message_type {
+            parts.push(format!("type={}", quote(t.as_str())));
+        }
+        let keys = [("sender", &self.sender),
+                    ("interface", &self.interface),
+                    ("member", &self.member),
+                    ("path", &self.path),
+                    ("path_namespace", &self.path_namespace),
+                    ("destination", &self.destination)];
+        for &(key, value) in keys.iter() {
+            if let Some(ref v) = *value {
+                parts.push(format!("{}={}", key, quote(v)));
+            }
+        }
+        for arg in self.args.iter() {
+            parts.push(match arg {
+                &ArgMatch::Str(n, ref s) => format!("arg{}={}", n, quote(s)),
+                &ArgMatch::Path(n, ref s) =>
+                    format!("arg{}path={}", n, quote(s)),
+                ArgMatch::Namespace(s) =>
+                    format!("arg0namespace={}", quote(s))
+            });
+        }
+        if let Some(e) = self.eavesdrop {
+            parts.push(format!("eavesdrop={}", quote(if e {"true"} else {"false"})));
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+impl FromStr for MatchRule {
+    type Err = MatchRuleError;
+
+    /// Parse a rule string, as accepted by the bus.
+    fn from_str(s: &str) -> Result<MatchRule, MatchRuleError> {
+        let mut rule = MatchRule::new();
+        for (key, value) in split_rule(s)?.into_iter() {
+            rule = match &key[..] {
+                "type" => rule.message_type(match &value[..] {
+                    "signal" => MessageType::Signal,
+                    "method_call" => MessageType::MethodCall,
+                    "method_return" => MessageType::MethodReturn,
+                    "error" => MessageType::Error,
+                    _ => return Err(MatchRuleError::InvalidValue(key, value))
+                }),
+                "sender" => rule.sender(&value),
+                "interface" => rule.interface(&value),
+                "member" => rule.member(&value),
+                "path" => rule.path(&value),
+                "path_namespace" => rule.path_namespace(&value),
+                "destination" => rule.destination(&value),
+                "arg0namespace" => rule.arg0_namespace(&value),
+                "eavesdrop" => rule.eavesdrop(match &value[..] {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(MatchRuleError::InvalidValue(key, value))
+                }),
+                _ => match parse_arg_key(&key) {
+                    Some((n, false)) => rule.arg(n, &value),
+                    Some((n, true)) => rule.arg_path(n, &value),
+                    None => return Err(MatchRuleError::UnknownKey(key))
+                }
+            };
+        }
+        rule.validate()?;
+        Ok(rule)
+    }
+}
+
+/// Parse an argN or argNpath key, returning N and whether it is a path.
+fn parse_arg_key(key: &str) -> Option<(u8, bool)> {
+    if !key.starts_with("arg") {
+        return None
+    }
+    let rest = &key[3..];
+    let (digits, path) = if let Some(digits) = rest.strip_suffix("path") {
+        (digits, true)
+    } else {
+        (rest, false)
+    };
+    if digits.is_empty() || digits.len() > 2 ||
+        !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    match digits.parse::<u8>() {
+        Ok(n) if n <= MAX_ARG_INDEX => Some((n, path)),
+        _ => None
+    }
+}
+
+/// Split a rule string into unescaped key/value pairs.
+///
+/// Inside quotes every character is literal.  Outside quotes `\'` is
+/// an apostrophe, and `,` ends the value.
+fn split_rule(s: &str) -> Result<Vec<(String, String)>, MatchRuleError> {
+    let mut pairs = vec![];
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break
+        }
+        let mut key = String::new();
+        loop {
+            match chars.next() {
+                Some('=') => break,
+                Some(c) if c.is_alphanumeric() || c == '_' => key.push(c),
+                Some(c) => return Err(MatchRuleError::Syntax(
+                    format!("unexpected '{}' in key {}", c, key))),
+                None => return Err(MatchRuleError::Syntax(
+                    format!("key {} has no value", key)))
+            }
+        }
+        if key.is_empty() {
+            return Err(MatchRuleError::Syntax("empty key".to_string()))
+        }
+        let mut value = String::new();
+        let mut quoted = false;
+        loop {
+            match chars.next() {
+                Some('\'') => quoted = !quoted,
+                Some(c) if quoted => value.push(c),
+                Some(',') => break,
+                Some('\\') if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    value.push('\'');
+                },
+                Some(c) => value.push(c),
+                None if quoted => return Err(MatchRuleError::Syntax(
+                    format!("unterminated quote in value of {}", key))),
+                None => break
+            }
+        }
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+/// Whether `path` is `ns`, or an object path below it.
+fn in_path_namespace(ns: &str, path: &str) -> bool {
+    ns == "/" || path == ns ||
+        (path.starts_with(ns) && path[ns.len()..].starts_with("/"))
+}
+
+/// argNpath semantics: equal, or one ends in '/' and prefixes the other.
+fn path_args_match(rule: &str, arg: &str) -> bool {
+    rule == arg ||
+        (rule.ends_with("/") && arg.starts_with(rule)) ||
+        (arg.ends_with("/") && rule.starts_with(arg))
+}
+
+fn is_name_element(s: &str, allow_leading_digit: bool, allow_dash: bool)
+                   -> bool {
+    !s.is_empty() &&
+        (allow_leading_digit || !s.chars().next().unwrap().is_ascii_digit()) &&
+        s.chars().all(|c| (c.is_ascii() && c.is_alphanumeric()) || c == '_' ||
+                      (allow_dash && c == '-'))
+}
+
+/// Whether `s` is a valid object path.
+pub fn is_object_path(s: &str) -> bool {
+    if s == "/" {
+        return true
+    }
+    s.starts_with("/") && !s.ends_with("/") &&
+        s[1..].split('/').all(|e| is_name_element(e, true, false))
+}
+
+/// Whether `s` is a valid interface name.
+pub fn is_interface_name(s: &str) -> bool {
+    s.len() <= 255 && s.split('.').count() >= 2 &&
+        s.split('.').all(|e| is_name_element(e, false, false))
+}
+
+/// Whether `s` is a valid member (method or signal) name.
+pub fn is_member_name(s: &str) -> bool {
+    s.len() <= 255 && is_name_element(s, false, false)
+}
+
+/// Whether `s` is a valid unique or well-known bus name.
+pub fn is_bus_name(s: &str) -> bool {
+    if s == "org.freedesktop.DBus" {
+        return true
+    }
+    if s.len() > 255 || s.split('.').count() < 2 {
+        return false
+    }
+    if let Some(unique) = s.strip_prefix(':') {
+        unique.split('.').all(|e| is_name_element(e, true, true))
+    } else {
+        s.split('.').all(|e| is_name_element(e, false, true))
+    }
+}
+
+/// Whether `s` is a valid arg0namespace value: a bus or interface
+/// name, or a single element.
+fn is_namespace(s: &str) -> bool {
+    s.len() <= 255 && !s.starts_with(":") &&
+        s.split('.').all(|e| is_name_element(e, false, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::arg::messageitem::MessageItem;
+    use Signal;
+
+    fn unit_signal(path: &str, name: &str) -> Signal {
+        Signal{
+            sender: Some(":1.1".to_string()),
+            path: Some(path.to_string()),
+            interface: Some("org.freedesktop.systemd1.Manager".to_string()),
+            member: Some("UnitNew".to_string()),
+            items: vec![MessageItem::Str(name.to_string()),
+                        MessageItem::ObjectPath(path.to_string().into())]
+        }
+    }
+
+    #[test]
+    fn to_string_escapes_apostrophes() {
+        let rule = MatchRule::signal().arg(0, "it's");
+        assert_eq!("type='signal',arg0='it'\\''s'", rule.to_string());
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let rule = MatchRule::signal()
+            .interface("org.freedesktop.systemd1.Manager")
+            .path_namespace("/org/freedesktop/systemd1")
+            .arg(0, "a 'quoted', value")
+            .arg_path(1, "/org/freedesktop/")
+            .eavesdrop(false);
+        let parsed: MatchRule = rule.to_string().parse().unwrap();
+        assert_eq!(rule, parsed);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Err(MatchRuleError::UnknownKey("colour".to_string())),
+                   "colour='red'".parse::<MatchRule>());
+        assert_eq!(Err(MatchRuleError::UnknownKey("arg64".to_string())),
+                   "arg64='x'".parse::<MatchRule>());
+        assert!("member='x".parse::<MatchRule>().is_err());
+        assert_eq!(Err(MatchRuleError::Conflict("path".to_string(),
+                                                "path_namespace".to_string())),
+                   "path='/a',path_namespace='/b'".parse::<MatchRule>());
+        assert!("path='a/b'".parse::<MatchRule>().is_err());
+    }
+
+    #[test]
+    fn matches_signals() {
+        let signal = unit_signal("/org/freedesktop/systemd1/unit/a_2eservice",
+                                 "a.service");
+        assert!(MatchRule::signal().member("UnitNew").matches(&signal));
+        assert!(MatchRule::new().path_namespace("/org/freedesktop/systemd1")
+                .matches(&signal));
+        assert!(!MatchRule::new().path_namespace("/org/freedesktop/systemd")
+                .matches(&signal));
+        assert!(MatchRule::new().arg(0, "a.service").matches(&signal));
+        assert!(!MatchRule::new().arg(0, "b.service").matches(&signal));
+        assert!(MatchRule::new().arg_path(1, "/org/freedesktop/systemd1/")
+                .matches(&signal));
+        assert!(!MatchRule::new().member("JobNew").matches(&signal));
+    }
+}
@@ -0,0 +1,1156 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A mock systemd manager, for tests that should not touch the host.
+//!
+//! `MockSystemd::start` runs a private `dbus-daemon`, and registers a
+//! fake `org.freedesktop.systemd1`, and a fake `org.freedesktop.login1`
+//! for power management, inhibitor locks and sessions, on it.  The
+//! mock serves the units, jobs and unit files it has been given,
+//! records every call it receives, and emits signals on request.
+//! Connect to it with `Connection::open(mock.bus())`.
+//!
+//! ```
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::mock::MockSystemd;
+//!
+//! let mock = MockSystemd::start().unwrap();
+//! mock.add_unit("sshd.service", "OpenSSH Daemon", "active", "running");
+//! let conn = Connection::open(mock.bus()).unwrap();
+//! assert_eq!(1, conn.list_units().unwrap().len());
+//! assert_eq!("ListUnits", mock.calls()[0].method);
+//! ```
+//!
+//! Only available in the crate's own tests, or with the `mock` feature.
+
+use std::ffi::CString;
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+use dbus;
+use dbus::arg::messageitem::{MessageItem, MessageItemDict};
+use libc;
+
+use login1::{InhibitMode, InhibitWhat, InhibitorInfo, SessionInfo, UserInfo};
+use serialize::{array, decode, encode, TypeSignature, UnixFd};
+use unit_name::{bus_label_escape, UNIT_PATH_PREFIX};
+use {Bus, Job, ObjectPath, SystemdError, SystemdResult, UnitFile,
+     UnitFileChange, UnitFileChangeType, UnitFileState, UnitStatus};
+
+const SERVICE: &str = "org.freedesktop.systemd1";
+const PATH: &str = "/org/freedesktop/systemd1";
+const MANAGER: &str = "org.freedesktop.systemd1.Manager";
+const LOGIN1_SERVICE: &str = "org.freedesktop.login1";
+const LOGIN1_PATH: &str = "/org/freedesktop/login1";
+const LOGIN1_MANAGER: &str = "org.freedesktop.login1.Manager";
+const PROPERTIES: &str = "org.freedesktop.DBus.Properties";
+const UNIT: &str = "org.freedesktop.systemd1.Unit";
+const SERVICE_INTERFACE: &str = "org.freedesktop.systemd1.Service";
+const JOB: &str = "org.freedesktop.systemd1.Job";
+
+/// A method call received by the mock.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockCall {
+    /// The called method
+    pub method: String,
+    /// The call arguments
+    pub args: Vec<MessageItem>
+}
+
+/// A signal to be emitted by the mock.
+struct MockSignal {
+    path: String,
+    interface: String,
+    member: String,
+    items: Vec<MessageItem>
+}
+
+/// The state served by the mock.
+struct State {
+    units: Vec<UnitStatus>,
+    jobs: Vec<Job>,
+    unit_files: Vec<UnitFile>,
+    calls: Vec<MockCall>,
+    signals: Vec<MockSignal>,
+    scheduled_shutdown: Option<(String, u64)>,
+    inhibitors: Vec<MockInhibitor>,
+    sessions: Vec<MockSession>,
+    exits: Vec<MockExit>,
+    sources: Vec<MockSources>,
+    objects: Vec<String>,
+    unanswered: Vec<String>,
+    job_results: Vec<(String, String)>,
+    scopes: Vec<MockScope>,
+    next_job_id: u32
+}
+
+/// A transient scope, and the processes that keep it running.
+struct MockScope {
+    unit: String,
+    pids: Vec<u32>
+}
+
+/// How the main process of a transient service exited.
+struct MockExit {
+    unit: String,
+    code: i32,
+    status: i32
+}
+
+/// The files a unit was loaded from.
+struct MockSources {
+    unit: String,
+    fragment_path: String,
+    drop_in_paths: Vec<String>
+}
+
+/// A logind session served by the mock.
+struct MockSession {
+    id: String,
+    uid: u32,
+    user: String,
+    seat: String
+}
+
+/// An inhibitor lock taken from the mock logind.  The lock is held
+/// until the client closes its end of the pipe.
+struct MockInhibitor {
+    what: String,
+    who: String,
+    why: String,
+    mode: String,
+    fd: RawFd
+}
+
+impl MockInhibitor {
+    /// Whether the client has closed its end.
+    fn released(&self) -> bool {
+        let mut buf = [0u8; 1];
+        unsafe {
+            libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, 1) == 0
+        }
+    }
+}
+
+impl Drop for MockInhibitor {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// A fake systemd manager on a private bus.
+///
+/// The bus and the manager are shut down when this is dropped.
+pub struct MockSystemd {
+    address: String,
+    daemon: Child,
+    state: Arc<Mutex<State>>,
+    signals: Sender<MockSignal>,
+    stop: Arc<AtomicBool>,
+    server: Option<JoinHandle<()>>
+}
+
+/// Start a dbus-daemon listening on `address`, and wait until it
+/// accepts connections.
+fn start_daemon(address: &str) -> SystemdResult<Child> {
+    let mut daemon =
+        Command::new("dbus-daemon")
+            .arg("--session")
+            .arg("--nofork")
+            .arg(format!("--address={}", address))
+            .arg("--print-address")
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| SystemdError::UnspecifiedError(
+                format!("failed to start dbus-daemon: {}", e)))?;
+    let mut printed = String::new();
+    {
+        let stdout = daemon.stdout.as_mut().unwrap();
+        BufReader::new(stdout).read_line(&mut printed)
+            .map_err(|e| SystemdError::UnspecifiedError(
+                format!("failed to read dbus-daemon address: {}", e)))?;
+    }
+    Ok(daemon)
+}
+
+/// Start a thread serving the mock manager on `address`, returning
+/// once it owns the systemd bus names.
+fn start_server(address: &str, state: Arc<Mutex<State>>,
+                signals: Receiver<MockSignal>, stop: Arc<AtomicBool>)
+                -> SystemdResult<JoinHandle<()>> {
+    let (ready_tx, ready_rx) = channel();
+    let bus = Bus::Address(address.to_string());
+    let server = thread::spawn(move || {
+        let conn = match register(&bus) {
+            Ok(conn) => {
+                let _ = ready_tx.send(Ok(()));
+                conn
+            },
+            Err(e) => {
+                let _ = ready_tx.send(Err(e));
+                return
+            }
+        };
+        serve(&bus, conn, state, signals, stop)
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok(server),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(SystemdError::UnspecifiedError(
+            "mock systemd failed to start".to_string()))
+    }
+}
+
+/// Counter making the socket paths of mocks in one process unique.
+static NEXT_BUS: AtomicUsize = AtomicUsize::new(0);
+
+impl MockSystemd {
+    /// Start a private dbus-daemon, and a mock systemd manager on it.
+    pub fn start() -> SystemdResult<MockSystemd> {
+        let socket = env::temp_dir().join(format!(
+            "systemd-dbus-mock-{}-{}", process::id(),
+            NEXT_BUS.fetch_add(1, Ordering::SeqCst)));
+        let address = format!("unix:path={}", socket.display());
+        let daemon = start_daemon(&address)?;
+
+        let state = Arc::new(Mutex::new(State{
+            units: vec![],
+            jobs: vec![],
+            unit_files: vec![],
+            calls: vec![],
+            signals: vec![],
+            scheduled_shutdown: None,
+            inhibitors: vec![],
+            sessions: vec![],
+            exits: vec![],
+            sources: vec![],
+            objects: vec![],
+            unanswered: vec![],
+            job_results: vec![],
+            scopes: vec![],
+            next_job_id: 1
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (signals_tx, signals_rx) = channel();
+        let mut mock = MockSystemd{
+            address,
+            daemon,
+            state,
+            signals: signals_tx,
+            stop,
+            server: None
+        };
+        mock.server = Some(start_server(&mock.address, mock.state.clone(),
+                                        signals_rx, mock.stop.clone())?);
+        Ok(mock)
+    }
+
+    /// Stop the bus and the manager, dropping every client connection,
+    /// and start them again on the same address.  The manager's units
+    /// and recorded calls are kept.
+    pub fn restart(&mut self) -> SystemdResult<()> {
+        self.shutdown();
+        self.daemon = start_daemon(&self.address)?;
+        self.stop = Arc::new(AtomicBool::new(false));
+        let (signals_tx, signals_rx) = channel();
+        self.signals = signals_tx;
+        self.server = Some(start_server(&self.address, self.state.clone(),
+                                        signals_rx, self.stop.clone())?);
+        Ok(())
+    }
+
+    /// Stop the manager, then the bus.
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(server) = self.server.take() {
+            let _ = server.join();
+        }
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+
+    /// The address of the private bus.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// The private bus, for passing to `Connection::open`.
+    pub fn bus(&self) -> Bus {
+        Bus::Address(self.address.clone())
+    }
+
+    /// Add a loaded unit.
+    pub fn add_unit(&self, name: &str, description: &str,
+                    active_state: &str, sub_state: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.objects.push(unit_path(name));
+        state.units.push(UnitStatus{
+            name: name.to_string(),
+            description: description.to_string(),
+            load_state: "loaded".to_string(),
+            active_state: active_state.to_string(),
+            sub_state: sub_state.to_string(),
+            followed: "".to_string(),
+            path: ObjectPath(unit_path(name)),
+            job_id: 0,
+            job_type: "".to_string(),
+            job_path: ObjectPath("/".to_string())
+        });
+    }
+
+    /// Set the unit file and drop-ins that unit `name` was loaded from.
+    pub fn set_unit_sources(&self, name: &str, fragment_path: &str,
+                            drop_in_paths: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        state.sources.retain(|s| s.unit != name);
+        state.sources.push(MockSources{
+            unit: name.to_string(),
+            fragment_path: fragment_path.to_string(),
+            drop_in_paths: drop_in_paths.iter().map(|p| p.to_string()).collect()
+        });
+    }
+
+    /// Finish the start job of transient unit `name` with `result`,
+    /// e.g. "failed", rather than "done".
+    pub fn set_job_result(&self, name: &str, result: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.job_results.retain(|(unit, _)| unit != name);
+        state.job_results.push((name.to_string(), result.to_string()));
+    }
+
+    /// Add a queued job for the unit `name`, returning its id.
+    pub fn add_job(&self, name: &str, job_type: &str, job_state: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_job_id;
+        state.next_job_id += 1;
+        state.objects.push(job_path(id));
+        state.jobs.push(Job{
+            job_id: id,
+            name: name.to_string(),
+            job_type: job_type.to_string(),
+            job_state: job_state.to_string(),
+            job_path: ObjectPath(job_path(id)),
+            path: ObjectPath(unit_path(name))
+        });
+        id
+    }
+
+    /// Add an installed unit file.
+    pub fn add_unit_file(&self, path: &str, state: &str) {
+        self.state.lock().unwrap().unit_files.push(UnitFile{
+            name: path.to_string(),
+            state: UnitFileState::from(state)
+        });
+    }
+
+    /// Add a logind session.
+    pub fn add_session(&self, id: &str, uid: u32, user: &str, seat: &str) {
+        self.state.lock().unwrap().sessions.push(MockSession{
+            id: id.to_string(),
+            uid,
+            user: user.to_string(),
+            seat: seat.to_string()
+        });
+    }
+
+    /// Return the calls received so far, oldest first.
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Forget the calls received so far.
+    pub fn clear_calls(&self) {
+        self.state.lock().unwrap().calls.clear();
+    }
+
+    /// Record calls of `method`, but never reply to them, so callers
+    /// time out.
+    pub fn withhold_replies(&self, method: &str) {
+        self.state.lock().unwrap().unanswered.push(method.to_string());
+    }
+
+    /// Emit a signal from the mock.
+    pub fn emit(&self, path: &str, interface: &str, member: &str,
+                items: Vec<MessageItem>) {
+        let _ = self.signals.send(MockSignal{
+            path: path.to_string(),
+            interface: interface.to_string(),
+            member: member.to_string(),
+            items
+        });
+    }
+
+    /// Emit the manager's UnitNew signal for `name`.
+    pub fn emit_unit_new(&self, name: &str) {
+        self.emit(PATH, MANAGER, "UnitNew",
+                  vec![MessageItem::Str(name.to_string()),
+                       MessageItem::ObjectPath(unit_path(name).into())]);
+    }
+
+    /// Emit the login manager's PrepareForSleep signal.
+    pub fn emit_prepare_for_sleep(&self, start: bool) {
+        self.emit(LOGIN1_PATH, LOGIN1_MANAGER, "PrepareForSleep",
+                  vec![MessageItem::Bool(start)]);
+    }
+
+    /// Emit the manager's JobRemoved signal for job `id` of `name`.
+    pub fn emit_job_removed(&self, id: u32, name: &str, result: &str) {
+        self.emit(PATH, MANAGER, "JobRemoved",
+                  vec![MessageItem::UInt32(id),
+                       MessageItem::ObjectPath(job_path(id).into()),
+                       MessageItem::Str(name.to_string()),
+                       MessageItem::Str(result.to_string())]);
+    }
+
+    /// Set the active and sub state of unit `name`, and emit the
+    /// PropertiesChanged signal of its Unit interface.
+    pub fn emit_active_state(&self, name: &str, active_state: &str,
+                             sub_state: &str) {
+        let signal = set_active_state(&mut self.state.lock().unwrap(), name,
+                                      active_state, sub_state);
+        let _ = self.signals.send(signal);
+    }
+}
+
+impl Drop for MockSystemd {
+    fn drop(&mut self) {
+        self.shutdown();
+        if let Some(path) = self.address.split("path=").nth(1) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Set the active and sub state of unit `name`, returning the
+/// PropertiesChanged signal of its Unit interface.
+fn set_active_state(state: &mut State, name: &str, active_state: &str,
+                    sub_state: &str) -> MockSignal {
+    if let Some(unit) = state.units.iter_mut().find(|u| u.name == name) {
+        unit.active_state = active_state.to_string();
+        unit.sub_state = sub_state.to_string();
+    }
+    let changed = vec![
+        (MessageItem::Str("ActiveState".to_string()),
+         MessageItem::Variant(Box::new(MessageItem::Str(active_state.to_string())))),
+        (MessageItem::Str("SubState".to_string()),
+         MessageItem::Variant(Box::new(MessageItem::Str(sub_state.to_string()))))];
+    MockSignal{
+        path: unit_path(name),
+        interface: PROPERTIES.to_string(),
+        member: "PropertiesChanged".to_string(),
+        items: vec![MessageItem::Str(UNIT.to_string()),
+                    property_dict(changed),
+                    array(vec![], "s").expect("invalidated array")]
+    }
+}
+
+/// Whether process `pid` has exited, even if it has not been reaped.
+fn exited(pid: u32) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat.rsplit(") ").next()
+            .map_or(true, |fields| fields.starts_with('Z')),
+        Err(_) => true
+    }
+}
+
+/// Stop the scopes whose processes have all exited, like systemd does
+/// once their cgroup is empty, returning the signals to emit.
+fn end_scopes(state: &mut State) -> Vec<MockSignal> {
+    let (ended, running): (Vec<MockScope>, Vec<MockScope>) = state.scopes.drain(..)
+        .partition(|scope| scope.pids.iter().all(|&pid| exited(pid)));
+    state.scopes = running;
+    ended.into_iter()
+        .map(|scope| set_active_state(state, &scope.unit, "inactive", "dead"))
+        .collect()
+}
+
+/// Connect to `bus`, and claim the systemd bus name and object path.
+fn register(bus: &Bus) -> SystemdResult<dbus::ffidisp::Connection> {
+    let conn = bus.connect()?;
+    conn.register_name(SERVICE, 0)?;
+    conn.register_object_path(PATH)?;
+    conn.register_name(LOGIN1_SERVICE, 0)?;
+    conn.register_object_path(LOGIN1_PATH)?;
+    Ok(conn)
+}
+
+/// Whether `name` matches the shell glob `pattern`, which may contain
+/// `*` and `?`.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(&b'*'), _) =>
+            glob_match(&pattern[1..], name) ||
+            (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some(&b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false
+    }
+}
+
+/// The mock's object path for logind session `id`.
+fn session_path(id: &str) -> String {
+    format!("{}/session/{}", LOGIN1_PATH, bus_label_escape(id))
+}
+
+/// The mock's object path for unit `name`.
+fn unit_path(name: &str) -> String {
+    format!("{}/{}", UNIT_PATH_PREFIX, bus_label_escape(name))
+}
+
+/// The mock's object path for job `id`.
+fn job_path(id: u32) -> String {
+    format!("{}/job/{}", PATH, id)
+}
+
+/// Serve method calls, and send signals, until `stop` is set.
+fn serve(bus: &Bus, mut conn: dbus::ffidisp::Connection,
+         state: Arc<Mutex<State>>, signals: Receiver<MockSignal>,
+         stop: Arc<AtomicBool>) {
+    register_objects(&conn, &state);
+    while !stop.load(Ordering::SeqCst) {
+        let mut reexecuted = false;
+        for item in conn.iter(10) {
+            match item {
+                dbus::ffidisp::ConnectionItem::MethodCall(mut m) => {
+                    reexecuted = m.member().as_deref() == Some("Reexecute");
+                    let (reply, emitted, objects) = {
+                        let mut state = state.lock().unwrap();
+                        let reply = reply_to(&mut state, &mut m);
+                        (reply, state.signals.drain(..).collect::<Vec<_>>(),
+                         state.objects.drain(..).collect::<Vec<_>>())
+                    };
+                    for path in objects.iter() {
+                        let _ = conn.register_object_path(path);
+                    }
+                    for signal in emitted.into_iter() {
+                        send_signal(&conn, signal);
+                    }
+                    if let Some(reply) = reply {
+                        let _ = conn.send(reply);
+                    }
+                    if reexecuted {
+                        break
+                    }
+                },
+                dbus::ffidisp::ConnectionItem::Nothing => break,
+                _ => ()
+            }
+        }
+        if reexecuted {
+            // Like systemd, leave the bus without replying, and come
+            // back on a new connection.
+            drop(conn);
+            conn = match register(bus) {
+                Ok(conn) => conn,
+                Err(_) => return
+            };
+            register_objects(&conn, &state);
+        }
+        while let Ok(signal) = signals.try_recv() {
+            send_signal(&conn, signal);
+        }
+        let ended = end_scopes(&mut state.lock().unwrap());
+        for signal in ended.into_iter() {
+            send_signal(&conn, signal);
+        }
+    }
+}
+
+/// Register the object paths of the units and jobs in `state`.
+fn register_objects(conn: &dbus::ffidisp::Connection, state: &Mutex<State>) {
+    let state = state.lock().unwrap();
+    for path in state.units.iter().map(|u| &u.path).chain(
+        state.jobs.iter().map(|j| &j.job_path)) {
+        let _ = conn.register_object_path(&path.0);
+    }
+}
+
+fn send_signal(conn: &dbus::ffidisp::Connection, signal: MockSignal) {
+    let mut m = dbus::Message::new_signal(&signal.path, &signal.interface,
+                                          &signal.member).unwrap();
+    if !signal.items.is_empty() {
+        m.append_items(&signal.items);
+    }
+    let _ = conn.send(m);
+}
+
+/// The manager's Reloading signal.
+fn reloading(active: bool) -> MockSignal {
+    MockSignal{
+        path: PATH.to_string(),
+        interface: MANAGER.to_string(),
+        member: "Reloading".to_string(),
+        items: vec![MessageItem::Bool(active)]
+    }
+}
+
+/// The manager's state in the format of systemd's Dump.
+fn dump(state: &State) -> String {
+    state.units.iter()
+        .map(|u| format!("-> Unit {}:\n\tDescription: {}\n\tActive State: {}\n",
+                         u.name, u.description, u.active_state))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+/// The argument signatures of the methods that systemd rejects calls
+/// to unless they match exactly.
+const SIGNATURES: &[(&str, &str, &str)] = &[
+    (MANAGER, "StartUnit", "ss"),
+    (MANAGER, "StopUnit", "ss"),
+    (MANAGER, "RestartUnit", "ss"),
+    (MANAGER, "KillUnit", "ssi"),
+    (MANAGER, "ResetFailedUnit", "s"),
+    (MANAGER, "SetUnitProperties", "sba(sv)"),
+    (MANAGER, "StartTransientUnit", "ssa(sv)a(sa(sv))"),
+    (MANAGER, "EnableUnitFiles", "asbb"),
+    (MANAGER, "DisableUnitFiles", "asb"),
+    (MANAGER, "SetEnvironment", "as"),
+    (MANAGER, "UnsetEnvironment", "as"),
+    (MANAGER, "UnsetAndSetEnvironment", "asas")];
+
+/// Record the call `m`, and return the reply to send, if any.
+fn reply_to(state: &mut State, m: &mut dbus::Message) -> Option<dbus::Message> {
+    let path = m.path().map(|p| p.to_string());
+    let interface = m.interface().map(|i| i.to_string());
+    let method = m.member().map(|s| s.to_string()).unwrap_or_default();
+    let args = m.get_items();
+    state.calls.push(MockCall{ method: method.clone(), args: args.clone() });
+    if state.unanswered.contains(&method) || method == "Reexecute" {
+        return None
+    }
+    let signature: String = args.iter().map(|a| a.signature().to_string()).collect();
+    let expected = SIGNATURES.iter()
+        .find(|&&(i, name, _)| Some(i) == interface.as_deref() && name == method);
+    if let Some(&(_, _, expected)) = expected {
+        if signature != expected {
+            return Some(m.error(
+                &"org.freedesktop.DBus.Error.InvalidArgs".into(),
+                &CString::new(format!("Invalid arguments '{}' to call {}(), expecting '{}'",
+                                      signature, method, expected)).unwrap()))
+        }
+    }
+    let response = match interface.as_ref().map(|s| &s[..]) {
+        Some(LOGIN1_MANAGER) => respond_login1(state, &method, args),
+        Some(PROPERTIES) =>
+            respond_properties(state, &path.unwrap_or_default(), &method, args),
+        Some(JOB) => respond_job(state, &path.unwrap_or_default(), &method),
+        _ => respond(state, &method, args)
+    };
+    Some(match response {
+        Ok(items) => {
+            let mut reply = dbus::Message::new_method_return(m).unwrap();
+            if !items.is_empty() {
+                reply.append_items(&items);
+            }
+            reply
+        },
+        Err((name, message)) =>
+            m.error(&name.into(), &CString::new(message).unwrap())
+    })
+}
+
+type Response = Result<Vec<MessageItem>, (&'static str, String)>;
+
+/// Set the state of the unit files named in the first argument, and
+/// return the changes systemd would report.
+fn set_file_states(state: &mut State, args: &[MessageItem], file_state: &str,
+                   change_type: &str)
+                   -> Result<Vec<UnitFileChange>, (&'static str, String)> {
+    let names: Vec<String> = decode(args.iter().take(1).cloned().collect())
+        .map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+    let mut changes = vec![];
+    for name in names.iter() {
+        let file = match state.unit_files.iter_mut()
+            .find(|f| f.name == *name ||
+                  f.name.ends_with(&format!("/{}", name))) {
+            Some(file) => file,
+            None => return Err(("org.freedesktop.DBus.Error.FileNotFound",
+                                format!("No such file: {}", name)))
+        };
+        file.state = UnitFileState::from(file_state);
+        changes.push(UnitFileChange{
+            change_type: UnitFileChangeType::from(change_type),
+            link: format!("/etc/systemd/system/multi-user.target.wants/{}", name),
+            destination: file.name.clone()
+        });
+    }
+    Ok(changes)
+}
+
+fn invalid_args(e: SystemdError) -> (&'static str, String) {
+    ("org.freedesktop.DBus.Error.InvalidArgs", format!("{:?}", e))
+}
+
+fn encoded<T: ::rustc_serialize::Encodable + TypeSignature>(value: &T) -> Response {
+    encode(value)
+        .map(|item| vec![item])
+        .map_err(|e| invalid_args(SystemdError::EncoderError(e)))
+}
+
+/// Compute the reply items for a call of `method` with `args`.
+fn respond(state: &mut State, method: &str, args: Vec<MessageItem>)
+           -> Response {
+    let name = || -> Result<String, (&'static str, String)> {
+        match args.first() {
+            Some(MessageItem::Str(name)) => Ok(name.clone()),
+            _ => Err(invalid_args(SystemdError::InvalidArg(
+                "expected a unit name".to_string())))
+        }
+    };
+    match method {
+        "ListUnits" => encoded(&state.units),
+        "ListJobs" => encoded(&state.jobs),
+        "ListUnitFiles" => encoded(&state.unit_files),
+        "ListUnitFilesByPatterns" => {
+            let (states, patterns): (Vec<UnitFileState>, Vec<String>) =
+                decode(args.clone())
+                    .map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            let files: Vec<UnitFile> = state.unit_files.iter()
+                .filter(|f| states.is_empty() || states.contains(&f.state))
+                .filter(|f| patterns.is_empty() || patterns.iter().any(|p| {
+                    let name = f.name.rsplit('/').next().unwrap_or("");
+                    glob_match(p.as_bytes(), name.as_bytes())
+                }))
+                .cloned()
+                .collect();
+            encoded(&files)
+        },
+        "GetUnit" | "LoadUnit" => {
+            let name = name()?;
+            if method == "GetUnit" &&
+                !state.units.iter().any(|u| u.name == name) {
+                return Err(("org.freedesktop.systemd1.NoSuchUnit",
+                            format!("Unit {} not loaded.", name)))
+            }
+            Ok(vec![MessageItem::ObjectPath(unit_path(&name).into())])
+        },
+        "GetUnitByPID" => match state.units.first() {
+            Some(unit) => encoded(&unit.path),
+            None => Err(("org.freedesktop.systemd1.NoUnitForPID",
+                         "No unit for PID.".to_string()))
+        },
+        "GetJob" => {
+            let id: u32 = decode(args.clone())
+                .map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            match state.jobs.iter().find(|j| j.job_id == id) {
+                Some(job) => encoded(&job.job_path),
+                None => Err(("org.freedesktop.systemd1.NoSuchJob",
+                             format!("Job {} does not exist.", id)))
+            }
+        },
+        "GetUnitFileState" => {
+            let name = name()?;
+            match state.unit_files.iter()
+                .find(|f| f.name == name || f.name.ends_with(&format!("/{}", name))) {
+                Some(file) => encoded(&file.state),
+                None => Err(("org.freedesktop.DBus.Error.FileNotFound",
+                             format!("No such file: {}", name)))
+            }
+        },
+        "StartUnit" | "StopUnit" | "ReloadUnit" | "RestartUnit" |
+        "TryRestartUnit" | "ReloadOrRestartUnit" |
+        "ReloadOrTryRestartUnit" => {
+            name()?;
+            let id = state.next_job_id;
+            state.next_job_id += 1;
+            Ok(vec![MessageItem::ObjectPath(job_path(id).into())])
+        },
+        "EnableUnitFiles" | "ReenableUnitFiles" | "PresetUnitFiles" |
+        "EnableUnitFilesWithFlags" | "PresetUnitFilesWithMode" => {
+            let changes = set_file_states(state, &args, "enabled",
+                                          "symlink")?;
+            let mut reply = vec![MessageItem::Bool(true)];
+            reply.extend(encoded(&changes)?);
+            Ok(reply)
+        },
+        "DisableUnitFiles" | "DisableUnitFilesWithFlags" |
+        "UnmaskUnitFiles" | "RevertUnitFiles" =>
+            encoded(&set_file_states(state, &args, "disabled", "unlink")?),
+        "MaskUnitFiles" =>
+            encoded(&set_file_states(state, &args, "masked", "symlink")?),
+        "LinkUnitFiles" =>
+            encoded(&set_file_states(state, &args, "linked", "symlink")?),
+        "ClearJobs" => {
+            state.jobs.clear();
+            Ok(vec![])
+        },
+        "Reload" => {
+            // systemd replies once it has reloaded
+            state.signals.push(reloading(true));
+            state.signals.push(reloading(false));
+            Ok(vec![])
+        },
+        "CancelJob" | "ResetFailed" | "ResetFailedUnit" | "KillUnit" |
+        "Subscribe" | "Unsubscribe" |
+        "SetEnvironment" | "UnsetEnvironment" | "UnsetAndSetEnvironment" =>
+            Ok(vec![]),
+        "GetDefaultTarget" => Ok(vec![MessageItem::Str(
+            "graphical.target".to_string())]),
+        "StartTransientUnit" => {
+            let name = name()?;
+            match args.get(2) {
+                Some(MessageItem::Array(properties)) =>
+                    start_transient(state, name, properties),
+                _ => Err(invalid_args(SystemdError::InvalidArg(
+                    "expected unit properties".to_string())))
+            }
+        },
+        "Dump" => Ok(vec![MessageItem::Str(dump(state))]),
+        "DumpByFileDescriptor" => {
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+                return Err(("org.freedesktop.DBus.Error.Failed",
+                            "pipe failed".to_string()))
+            }
+            let (read, mut write) = (UnixFd::new(fds[0]),
+                                     unsafe { File::from_raw_fd(fds[1]) });
+            if write.write_all(dump(state).as_bytes()).is_err() {
+                return Err(("org.freedesktop.DBus.Error.Failed",
+                            "write failed".to_string()))
+            }
+            encoded(&read)
+        },
+        _ => Err(("org.freedesktop.DBus.Error.UnknownMethod",
+                  format!("Unknown method {} on {}", method, MANAGER)))
+    }
+}
+
+/// The value of `name` in the a(sv) `properties` of a transient unit.
+fn transient_property<'a>(properties: &'a [MessageItem], name: &str)
+                          -> Option<&'a MessageItem> {
+    properties.iter().filter_map(|p| match p {
+        MessageItem::Struct(fields) if fields.len() == 2 &&
+            fields[0] == MessageItem::Str(name.to_string()) => match &fields[1] {
+                MessageItem::Variant(value) => Some(&**value),
+                _ => None
+            },
+        _ => None
+    }).next()
+}
+
+/// Start transient unit `name`.  A service's command exits as soon as
+/// it starts: with status 1 if it is named false, and otherwise after
+/// writing its arguments to its standard output, like echo.  A scope
+/// keeps running until its processes exit.  The start job's result is
+/// "done" unless set by `set_job_result`.
+fn start_transient(state: &mut State, name: String, properties: &[MessageItem])
+                   -> Response {
+    let result = state.job_results.iter()
+        .find(|(unit, _)| *unit == name)
+        .map_or("done".to_string(), |(_, result)| result.clone());
+    let (active_state, sub_state) = if result != "done" {
+        ("failed", "failed")
+    } else if name.ends_with(".scope") {
+        let pids: Vec<u32> = match transient_property(properties, "PIDs") {
+            Some(pids) => decode(vec![pids.clone()]).map_err(
+                |e| invalid_args(SystemdError::DecoderError(e)))?,
+            None => vec![]
+        };
+        state.scopes.push(MockScope{ unit: name.clone(), pids });
+        ("active", "running")
+    } else {
+        let argv: Vec<String> = match transient_property(properties, "ExecStart") {
+            Some(MessageItem::Array(commands)) => match commands.first() {
+                Some(MessageItem::Struct(fields)) if fields.len() == 3 =>
+                    decode(vec![fields[1].clone()]).map_err(
+                        |e| invalid_args(SystemdError::DecoderError(e)))?,
+                _ => vec![]
+            },
+            _ => vec![]
+        };
+        if argv.is_empty() {
+            return Err(invalid_args(SystemdError::InvalidArg(
+                "expected ExecStart".to_string())))
+        }
+        let status = if argv[0].ends_with("false") { 1 } else { 0 };
+        if let Some(MessageItem::UnixFd(fd)) =
+            transient_property(properties, "StandardOutputFileDescriptor") {
+            let line = format!("{}\n", argv[1..].join(" "));
+            unsafe {
+                libc::write(fd.as_raw_fd(), line.as_ptr() as *const libc::c_void,
+                            line.len() as libc::size_t);
+            }
+        }
+        state.exits.push(MockExit{ unit: name.clone(), code: 1, status });
+        if status == 0 { ("active", "exited") } else { ("failed", "failed") }
+    };
+    let description = match transient_property(properties, "Description") {
+        Some(MessageItem::Str(d)) => d.clone(),
+        _ => "".to_string()
+    };
+    let path = unit_path(&name);
+    state.units.push(UnitStatus{
+        name: name.clone(),
+        description,
+        load_state: "loaded".to_string(),
+        active_state: active_state.to_string(),
+        sub_state: sub_state.to_string(),
+        followed: "".to_string(),
+        path: ObjectPath(path.clone()),
+        job_id: 0,
+        job_type: "".to_string(),
+        job_path: ObjectPath("/".to_string())
+    });
+    state.objects.push(path);
+    let id = state.next_job_id;
+    state.next_job_id += 1;
+    state.signals.push(MockSignal{
+        path: PATH.to_string(),
+        interface: MANAGER.to_string(),
+        member: "JobRemoved".to_string(),
+        items: vec![MessageItem::UInt32(id),
+                    MessageItem::ObjectPath(job_path(id).into()),
+                    MessageItem::Str(name),
+                    MessageItem::Str(result)]
+    });
+    Ok(vec![MessageItem::ObjectPath(job_path(id).into())])
+}
+
+/// The properties of the manager, jobs and units that the mock
+/// knows, by interface.
+const PROPERTY_NAMES: &[(&str, &str)] = &[
+    (MANAGER, "Version"), (MANAGER, "NNames"), (MANAGER, "NJobs"),
+    (MANAGER, "ExitCode"),
+    (LOGIN1_MANAGER, "ScheduledShutdown"),
+    (JOB, "Id"), (JOB, "Unit"), (JOB, "JobType"), (JOB, "State"),
+    (UNIT, "Id"), (UNIT, "Description"), (UNIT, "LoadState"),
+    (UNIT, "ActiveState"), (UNIT, "SubState"), (UNIT, "FragmentPath"),
+    (UNIT, "DropInPaths"), (UNIT, "InvocationID"),
+    (SERVICE_INTERFACE, "ExecMainCode"), (SERVICE_INTERFACE, "ExecMainStatus")];
+
+/// An a{sv} dict of property names and variant values.
+fn property_dict(properties: Vec<(MessageItem, MessageItem)>) -> MessageItem {
+    MessageItem::Dict(MessageItemDict::new(properties, "s".into(), "v".into())
+                      .expect("property dict"))
+}
+
+/// The value of a property, by interface and name, or None if the
+/// object has no such property.
+type PropertyValue<'a> = dyn Fn(&str, &str) -> Option<MessageItem> + 'a;
+
+/// Compute the reply to Properties `method` on the manager, login
+/// manager, job or unit at `path`.
+fn respond_properties(state: &State, path: &str, method: &str,
+                      args: Vec<MessageItem>) -> Response {
+    let value: Box<PropertyValue> = if path == PATH {
+        Box::new(move |interface: &str, name: &str| match (interface, name) {
+            (MANAGER, "Version") => Some(MessageItem::Str("mock".to_string())),
+            (MANAGER, "NNames") => Some(MessageItem::UInt32(state.units.len() as u32)),
+            (MANAGER, "NJobs") => Some(MessageItem::UInt32(state.jobs.len() as u32)),
+            (MANAGER, "ExitCode") => Some(MessageItem::Byte(0)),
+            _ => None
+        })
+    } else if path == LOGIN1_PATH {
+        Box::new(move |interface: &str, name: &str| match (interface, name) {
+            (LOGIN1_MANAGER, "ScheduledShutdown") => {
+                let (kind, usec) = state.scheduled_shutdown.clone()
+                    .unwrap_or_else(|| (String::new(), 0));
+                Some(MessageItem::Struct(vec![MessageItem::Str(kind),
+                                              MessageItem::UInt64(usec)]))
+            },
+            _ => None
+        })
+    } else if let Some(job) = state.jobs.iter().find(|j| j.job_path.0 == path) {
+        Box::new(move |interface: &str, name: &str| match (interface, name) {
+            (JOB, "Id") => Some(MessageItem::UInt32(job.job_id)),
+            (JOB, "Unit") => Some(MessageItem::Struct(vec![
+                MessageItem::Str(job.name.clone()),
+                MessageItem::ObjectPath(job.path.0.clone().into())])),
+            (JOB, "JobType") => Some(MessageItem::Str(job.job_type.clone())),
+            (JOB, "State") => Some(MessageItem::Str(job.job_state.clone())),
+            _ => None
+        })
+    } else if let Some(unit) = state.units.iter().find(|u| unit_path(&u.name) == path) {
+        let exit = state.exits.iter().find(|e| e.unit == unit.name);
+        let sources = state.sources.iter().find(|s| s.unit == unit.name);
+        Box::new(move |interface: &str, name: &str| match (interface, name) {
+            (UNIT, "Id") => Some(MessageItem::Str(unit.name.clone())),
+            (UNIT, "Description") => Some(MessageItem::Str(unit.description.clone())),
+            (UNIT, "LoadState") => Some(MessageItem::Str(unit.load_state.clone())),
+            (UNIT, "ActiveState") => Some(MessageItem::Str(unit.active_state.clone())),
+            (UNIT, "SubState") => Some(MessageItem::Str(unit.sub_state.clone())),
+            (UNIT, "FragmentPath") => Some(MessageItem::Str(
+                sources.map(|s| s.fragment_path.clone()).unwrap_or_default())),
+            (UNIT, "DropInPaths") => encode(
+                sources.map(|s| s.drop_in_paths.clone()).unwrap_or_default()).ok(),
+            (UNIT, "InvocationID") =>
+                array((0..16).map(MessageItem::Byte).collect(), "y").ok(),
+            (SERVICE_INTERFACE, "ExecMainCode") =>
+                Some(MessageItem::Int32(exit.map(|e| e.code).unwrap_or(0))),
+            (SERVICE_INTERFACE, "ExecMainStatus") =>
+                Some(MessageItem::Int32(exit.map(|e| e.status).unwrap_or(0))),
+            _ => None
+        })
+    } else {
+        return Err(("org.freedesktop.DBus.Error.UnknownObject",
+                    format!("Unknown object {}", path)))
+    };
+    match method {
+        "GetAll" => {
+            let interface: String =
+                decode(args).map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            let properties = PROPERTY_NAMES.iter()
+                .filter_map(|&(i, name)| {
+                    if i != interface {
+                        return None
+                    }
+                    value(i, name).map(|v| (MessageItem::Str(name.to_string()),
+                                            MessageItem::Variant(Box::new(v))))
+                })
+                .collect();
+            Ok(vec![property_dict(properties)])
+        },
+        "Get" => {
+            let (interface, name): (String, String) =
+                decode(args).map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            match value(&interface, &name) {
+                Some(v) => Ok(vec![MessageItem::Variant(Box::new(v))]),
+                None => Err(("org.freedesktop.DBus.Error.UnknownProperty",
+                             format!("Unknown property {}.{}", interface, name)))
+            }
+        },
+        _ => Err(("org.freedesktop.DBus.Error.UnknownMethod",
+                  format!("Unknown method {} on {}", method, PROPERTIES)))
+    }
+}
+
+/// Compute the reply to Job `method` on the job at `path`.
+fn respond_job(state: &mut State, path: &str, method: &str) -> Response {
+    let index = match state.jobs.iter().position(|j| j.job_path.0 == path) {
+        Some(index) => index,
+        None => return Err(("org.freedesktop.DBus.Error.UnknownObject",
+                            format!("Unknown object {}", path)))
+    };
+    match method {
+        "Cancel" => {
+            state.jobs.remove(index);
+            Ok(vec![])
+        },
+        _ => Err(("org.freedesktop.DBus.Error.UnknownMethod",
+                  format!("Unknown method {} on {}", method, JOB)))
+    }
+}
+
+/// Compute the reply items for a call of the login manager's `method`.
+fn respond_login1(state: &mut State, method: &str, args: Vec<MessageItem>)
+                  -> Response {
+    match method {
+        "CanPowerOff" | "CanReboot" | "CanHalt" | "CanSuspend" =>
+            Ok(vec![MessageItem::Str("yes".to_string())]),
+        "CanHibernate" | "CanHybridSleep" =>
+            Ok(vec![MessageItem::Str("na".to_string())]),
+        "PowerOff" | "Reboot" | "Halt" | "Suspend" | "Hibernate" |
+        "HybridSleep" =>
+            Ok(vec![]),
+        "ScheduleShutdown" => {
+            let scheduled: (String, u64) =
+                decode(args).map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            state.scheduled_shutdown = Some(scheduled);
+            Ok(vec![])
+        },
+        "CancelScheduledShutdown" =>
+            Ok(vec![MessageItem::Bool(state.scheduled_shutdown.take().is_some())]),
+        "Inhibit" => {
+            let (what, who, why, mode): (String, String, String, String) =
+                decode(args).map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            let mut fds = [0; 2];
+            if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 ||
+                unsafe { libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK) } != 0 {
+                return Err(("org.freedesktop.DBus.Error.Failed",
+                            "pipe failed".to_string()))
+            }
+            state.inhibitors.push(MockInhibitor{
+                what, who, why, mode, fd: fds[0]
+            });
+            encoded(&UnixFd::new(fds[1]))
+        },
+        "ListSessions" => {
+            let list: Vec<SessionInfo> = state.sessions.iter()
+                .map(|s| SessionInfo{
+                    id: s.id.clone(),
+                    uid: s.uid,
+                    user: s.user.clone(),
+                    seat: s.seat.clone(),
+                    path: ObjectPath(session_path(&s.id))
+                })
+                .collect();
+            encoded(&list)
+        },
+        "ListUsers" => {
+            let mut list: Vec<UserInfo> = vec![];
+            for s in state.sessions.iter() {
+                if !list.iter().any(|u| u.uid == s.uid) {
+                    list.push(UserInfo{
+                        uid: s.uid,
+                        name: s.user.clone(),
+                        path: ObjectPath(format!("{}/user/_{}", LOGIN1_PATH, s.uid))
+                    });
+                }
+            }
+            encoded(&list)
+        },
+        "GetSessionByPID" => match state.sessions.first() {
+            Some(session) => Ok(vec![MessageItem::ObjectPath(session_path(&session.id).into())]),
+            None => Err(("org.freedesktop.login1.NoSessionForPID",
+                         "PID does not belong to any known session".to_string()))
+        },
+        "TerminateSession" => {
+            let id: String =
+                decode(args).map_err(|e| invalid_args(SystemdError::DecoderError(e)))?;
+            match state.sessions.iter().position(|s| s.id == id) {
+                Some(i) => {
+                    state.sessions.remove(i);
+                    state.signals.push(MockSignal{
+                        path: LOGIN1_PATH.to_string(),
+                        interface: LOGIN1_MANAGER.to_string(),
+                        member: "SessionRemoved".to_string(),
+                        items: vec![MessageItem::Str(id.clone()),
+                                    MessageItem::ObjectPath(session_path(&id).into())]
+                    });
+                    Ok(vec![])
+                },
+                None => Err(("org.freedesktop.login1.NoSuchSession",
+                             format!("No session '{}' known", id)))
+            }
+        },
+        "TerminateUser" | "KillSession" | "KillUser" | "LockSessions" |
+        "UnlockSessions" | "SetUserLinger" => Ok(vec![]),
+        "ListInhibitors" => {
+            state.inhibitors.retain(|i| !i.released());
+            let (uid, pid) = unsafe { (libc::getuid(), libc::getpid()) };
+            let list: Vec<InhibitorInfo> = state.inhibitors.iter()
+                .map(|i| InhibitorInfo{
+                    what: InhibitWhat::from(i.what.as_str()),
+                    who: i.who.clone(),
+                    why: i.why.clone(),
+                    mode: InhibitMode::from(i.mode.as_str()),
+                    uid,
+                    pid: pid as u32
+                })
+                .collect();
+            encoded(&list)
+        },
+        _ => Err(("org.freedesktop.DBus.Error.UnknownMethod",
+                  format!("Unknown method {} on {}", method, LOGIN1_MANAGER)))
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reload or re-execute the manager, and wait until it is ready.
+//!
+//! `reload()` and `reexecute()` return when systemd has received the
+//! call, which may be before it has finished.  `reload_and_wait`
+//! waits for the manager's `Reloading(false)` signal.
+//! `reexecute_and_wait` waits for the new manager to take the
+//! org.freedesktop.systemd1 name and answer calls, reconnecting as
+//! needed.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use systemd_dbus::{Backoff, Connection};
+//!
+//! let mut conn = Connection::new().unwrap();
+//! conn.reload_and_wait(Duration::from_secs(90)).unwrap();
+//! conn.reexecute_and_wait(&Backoff::default(), Duration::from_secs(90)).unwrap();
+//! conn.start_unit("app.service".to_string(), systemd_dbus::Mode::Replace).unwrap();
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dbus::arg::messageitem::MessageItem;
+
+use match_rule::MatchRule;
+use serialize::decode;
+use {Backoff, Connection, DBusCallable, Signal, SystemdError, SystemdResult,
+     DBUS, SYSTEMD};
+
+/// The Reloading signal, sent with true when the manager starts
+/// reloading and false when it has finished.
+fn reloading_rule() -> MatchRule {
+    MatchRule::signal()
+        .interface(SYSTEMD.interface)
+        .member("Reloading")
+}
+
+/// The argument of `signal`, if it is the manager's Reloading signal.
+fn reloading(signal: &Signal) -> Option<bool> {
+    if signal.interface.as_ref().map(|s| &s[..]) != Some(SYSTEMD.interface) ||
+        signal.member.as_ref().map(|s| &s[..]) != Some("Reloading") {
+        return None
+    }
+    signal.decode::<bool>().ok()
+}
+
+/// Whether `error` is how a call fails when systemd re-executes
+/// without replying: the bus reports NoReply once the manager leaves it.
+fn is_lost_reply(error: &SystemdError) -> bool {
+    matches!(*error, SystemdError::NoReply | SystemdError::Disconnected)
+}
+
+impl Connection {
+    /// Reload the manager's configuration, and wait up to `timeout`
+    /// for the reload to finish.  Signals that arrive while waiting are
+    /// held for `next_signal` if they match a rule of the caller's.
+    pub fn reload_and_wait(&self, timeout: Duration) -> SystemdResult<()> {
+        let rule = reloading_rule();
+        self.add_match_rule(&rule)?;
+        let subscribed = self.subscribed.get();
+        let result = self.reload_watched(timeout);
+        if !subscribed {
+            let _ = self.unsubscribe();
+        }
+        let _ = self.remove_match_rule(&rule);
+        result
+    }
+
+    fn reload_watched(&self, timeout: Duration) -> SystemdResult<()> {
+        let start = Instant::now();
+        // systemd only sends Reloading to subscribed clients
+        if !self.subscribed.get() {
+            self.subscribe()?;
+        }
+        // Make sure the bus has added the match rule before reloading
+        self.sync_signal_bus()?;
+        self.with_timeout(timeout).reload()?;
+        let remaining = match timeout.checked_sub(start.elapsed()) {
+            Some(remaining) => remaining,
+            None => return Err(SystemdError::Timeout)
+        };
+        match self.watch_signal(remaining, |s| reloading(s) == Some(false))? {
+            Some(_) => Ok(()),
+            None => Err(SystemdError::Timeout)
+        }
+    }
+
+    /// Re-execute the manager, and wait up to `timeout` for the new
+    /// manager to answer calls.  The connection is reconnected with
+    /// `backoff`, and any systemd subscription restored.
+    pub fn reexecute_and_wait(&mut self, backoff: &Backoff, timeout: Duration)
+                              -> SystemdResult<()> {
+        let start = Instant::now();
+        let old_owner = self.manager_owner()?;
+        match self.reexecute() {
+            Ok(()) => (),
+            Err(ref e) if is_lost_reply(e) => (),
+            Err(e) => return Err(e)
+        }
+        self.reconnect(backoff)?;
+        for delay in backoff.delays() {
+            if start.elapsed() >= timeout {
+                break
+            }
+            match self.manager_owner() {
+                Ok(ref owner) if *owner != old_owner => {
+                    // The new manager only handles calls once it has
+                    // finished starting up.
+                    let remaining = timeout.checked_sub(start.elapsed())
+                        .unwrap_or(Duration::from_secs(0));
+                    self.ping_manager(remaining)?;
+                    // reconnect subscribed before the new manager was
+                    // on the bus
+                    if self.subscribed.get() {
+                        self.subscribe()?;
+                    }
+                    return Ok(())
+                },
+                _ => thread::sleep(delay)
+            }
+        }
+        Err(SystemdError::Timeout)
+    }
+
+    /// The unique name of the bus connection owning the manager's name.
+    fn manager_owner(&self) -> SystemdResult<String> {
+        let args = [MessageItem::Str(SYSTEMD.service.to_string())];
+        let reply = self.bus.call(DBUS.method("GetNameOwner").unwrap(),
+                                  &args, self.timeout)?;
+        Ok(decode(reply)?)
+    }
+
+    /// Call the manager, returning when it has replied, or failing
+    /// after `timeout`.
+    fn ping_manager(&self, timeout: Duration) -> SystemdResult<()> {
+        self.call_service_timeout(&self.bus, SYSTEMD.service, SYSTEMD.path,
+                                  "org.freedesktop.DBus.Peer", "Ping", &[],
+                                  timeout, |_| Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use mock::MockSystemd;
+    use {Backoff, Connection};
+
+    #[test]
+    fn reload_and_wait_test() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        conn.reload_and_wait(Duration::from_secs(5)).unwrap();
+        let methods: Vec<String> = mock.calls().into_iter()
+            .map(|c| c.method)
+            .collect();
+        assert_eq!(vec!["Subscribe", "Reload", "Unsubscribe"], methods);
+    }
+
+    #[test]
+    fn reexecute_and_wait_test() {
+        let mock = MockSystemd::start().unwrap();
+        let mut conn = Connection::open(mock.bus()).unwrap();
+        conn.subscribe().unwrap();
+        let old_owner = conn.manager_owner().unwrap();
+        let backoff = Backoff{
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+            attempts: Some(50)
+        };
+        conn.reexecute_and_wait(&backoff, Duration::from_secs(5)).unwrap();
+        assert!(conn.manager_owner().unwrap() != old_owner);
+        let methods: Vec<String> = mock.calls().into_iter()
+            .map(|c| c.method)
+            .skip_while(|m| m != "Reexecute")
+            .collect();
+        assert_eq!(Some(&"Subscribe".to_string()), methods.last());
+        assert!(conn.subscribed.get());
+    }
+}
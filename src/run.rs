@@ -0,0 +1,854 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Run a command in a transient unit, like `systemd-run --wait`.
+//!
+//! `run_command` starts the command as a transient service with the
+//! given user, working directory, environment, stdio and resource
+//! limits, waits for it to exit and returns how it exited.  With
+//! `RunOptions::scope()` the command is instead started by this
+//! process and moved into a transient scope, so it still runs under
+//! the scope's resource limits.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::os::unix::io::IntoRawFd;
+//! use std::time::Duration;
+//! use systemd_dbus::{Connection, UnixFd};
+//! use systemd_dbus::run::RunOptions;
+//!
+//! let conn = Connection::new().unwrap();
+//! let log = File::create("/var/log/build/step-1.log").unwrap();
+//! let opts = RunOptions::service()
+//!     .user("builder")
+//!     .working_directory("/srv/build")
+//!     .env("CARGO_HOME", "/srv/build/.cargo")
+//!     .stdout(UnixFd::new(log.into_raw_fd()))
+//!     .memory_limit(2 << 30)
+//!     .tasks_max(512)
+//!     .timeout(Duration::from_secs(3600));
+//! let exit = conn.run_command(&["/usr/bin/make", "all"], opts).unwrap();
+//! assert!(exit.success());
+//! ```
+//!
+//! See:
+//!   http://www.freedesktop.org/software/systemd/man/systemd-run.html
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use match_rule::MatchRule;
+use serialize::{decode, UnixFd};
+use unit_name::{UnitName, UnitType};
+use watch::ActiveState;
+use {Connection, Mode, ObjectPath, Signal, SystemdError, SystemdResult,
+     UnitProperty, SYSTEMD};
+
+static SERVICE_INTERFACE : &str = "org.freedesktop.systemd1.Service";
+
+/// How long to wait for a signal before checking the unit's state
+/// anyway.
+const POLL_INTERVAL_MS: u64 = 1000;
+
+/// Distinguishes the generated names of units run by this process.
+static RUN_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// How the main process of a command exited.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExitCode {
+    /// The process exited, with the exit status as status
+    Exited,
+    /// The process was killed, with the signal as status
+    Killed,
+    /// The process was killed and dumped core, with the signal as status
+    Dumped,
+    /// The process never ran, or a code not known to this crate
+    Other(i32)
+}
+
+impl ExitCode {
+    /// Parse the ExecMainCode property, the `si_code` of the SIGCHLD
+    /// for the process.
+    pub fn from_code(code: i32) -> ExitCode {
+        match code {
+            1 => ExitCode::Exited,
+            2 => ExitCode::Killed,
+            3 => ExitCode::Dumped,
+            other => ExitCode::Other(other)
+        }
+    }
+}
+
+/// The exit of a command run by `run_command`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExitStatus {
+    /// How the process exited
+    pub code: ExitCode,
+    /// The exit status, or the signal that killed the process
+    pub status: i32
+}
+
+impl ExitStatus {
+    /// Whether the process exited with status 0.
+    pub fn success(&self) -> bool {
+        self.code == ExitCode::Exited && self.status == 0
+    }
+}
+
+/// An ExecStart entry: the path, the argv and whether to ignore a
+/// failure exit.
+#[derive(Debug)]
+struct ExecCommand {
+    path: String,
+    argv: Vec<String>,
+    ignore_failure: bool
+}
+encodable_struct!(ExecCommand { path, argv, ignore_failure });
+
+/// How to run a command with `run_command`.
+#[derive(Debug)]
+pub struct RunOptions {
+    scope: bool,
+    name: Option<String>,
+    description: Option<String>,
+    user: Option<String>,
+    working_directory: Option<String>,
+    environment: Vec<String>,
+    stdin: Option<UnixFd>,
+    stdout: Option<UnixFd>,
+    stderr: Option<UnixFd>,
+    limits: Vec<(&'static str, u64)>,
+    properties: Vec<UnitProperty>,
+    timeout: Option<Duration>
+}
+
+impl RunOptions {
+    /// Run the command as a transient service, started by systemd.
+    pub fn service() -> RunOptions {
+        RunOptions{
+            scope: false,
+            name: None,
+            description: None,
+            user: None,
+            working_directory: None,
+            environment: vec![],
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            limits: vec![],
+            properties: vec![],
+            timeout: None
+        }
+    }
+
+    /// Run the command as a child of this process, in a transient
+    /// scope.  The child is held until systemd has moved it into the
+    /// scope, so the command only ever runs in it.  `user` is not
+    /// supported.
+    pub fn scope() -> RunOptions {
+        RunOptions{ scope: true, ..RunOptions::service() }
+    }
+
+    /// The unit name, including the .service or .scope suffix.  By
+    /// default a name starting with "run-" is generated.
+    pub fn name(mut self, name: &str) -> RunOptions {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// The unit description.  By default, the command line.
+    pub fn description(mut self, description: &str) -> RunOptions {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    /// Run the command as `user`, a user name or uid.
+    pub fn user(mut self, user: &str) -> RunOptions {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Run the command in `dir`.
+    pub fn working_directory(mut self, dir: &str) -> RunOptions {
+        self.working_directory = Some(dir.to_string());
+        self
+    }
+
+    /// Set environment variable `key` to `value` for the command.
+    pub fn env(mut self, key: &str, value: &str) -> RunOptions {
+        self.environment.push(format!("{}={}", key, value));
+        self
+    }
+
+    /// Connect the command's standard input to `fd`.
+    pub fn stdin(mut self, fd: UnixFd) -> RunOptions {
+        self.stdin = Some(fd);
+        self
+    }
+
+    /// Connect the command's standard output to `fd`.
+    pub fn stdout(mut self, fd: UnixFd) -> RunOptions {
+        self.stdout = Some(fd);
+        self
+    }
+
+    /// Connect the command's standard error to `fd`.
+    pub fn stderr(mut self, fd: UnixFd) -> RunOptions {
+        self.stderr = Some(fd);
+        self
+    }
+
+    /// Limit the unit's memory use to `bytes` (MemoryMax).
+    pub fn memory_limit(mut self, bytes: u64) -> RunOptions {
+        self.limits.push(("MemoryMax", bytes));
+        self
+    }
+
+    /// Limit the unit's CPU time to `percent` of one CPU (CPUQuota).
+    pub fn cpu_quota(mut self, percent: u64) -> RunOptions {
+        self.limits.push(("CPUQuotaPerSecUSec", percent * 10000));
+        self
+    }
+
+    /// Limit the number of tasks in the unit (TasksMax).
+    pub fn tasks_max(mut self, tasks: u64) -> RunOptions {
+        self.limits.push(("TasksMax", tasks));
+        self
+    }
+
+    /// Set any other unit property.
+    pub fn property(mut self, property: UnitProperty) -> RunOptions {
+        self.properties.push(property);
+        self
+    }
+
+    /// Give up waiting, and stop the command, after `timeout`.  By
+    /// default `run_command` waits until the command exits.
+    pub fn timeout(mut self, timeout: Duration) -> RunOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The unit name to run as, with the suffix for the unit type.
+    fn unit_name(&self) -> SystemdResult<UnitName> {
+        let (suffix, unit_type) = if self.scope {
+            ("scope", UnitType::Scope)
+        } else {
+            ("service", UnitType::Service)
+        };
+        let name = match self.name {
+            Some(ref name) => UnitName::new(name)?,
+            None => UnitName::new(&format!(
+                "run-{}-{}.{}", process::id(),
+                RUN_COUNTER.fetch_add(1, Ordering::SeqCst), suffix))?
+        };
+        if name.unit_type() != unit_type {
+            return Err(SystemdError::InvalidArg(
+                format!("{} is not a .{}", name.as_str(), suffix)))
+        }
+        Ok(name)
+    }
+
+    /// The properties shared by services and scopes.
+    fn common_properties(&self, cmd: &[&str]) -> SystemdResult<Vec<UnitProperty>> {
+        let description = match self.description {
+            Some(ref d) => d.clone(),
+            None => cmd.join(" ")
+        };
+        let mut properties = vec![UnitProperty::new("Description", description)?];
+        for &(name, value) in self.limits.iter() {
+            properties.push(UnitProperty::new(name, value)?);
+        }
+        Ok(properties)
+    }
+}
+
+/// Whether a unit started with RemainAfterExit has finished running.
+fn finished(state: &ActiveState, sub_state: &str) -> bool {
+    match state {
+        &ActiveState::Failed | &ActiveState::Inactive => true,
+        &ActiveState::Active => sub_state == "exited",
+        _ => false
+    }
+}
+
+/// The result of `job`, if `signal` is the JobRemoved signal for it.
+fn job_removed(signal: &Signal, job: &ObjectPath) -> Option<String> {
+    if signal.interface.as_ref().map(|s| &s[..]) != Some(SYSTEMD.interface) ||
+        signal.member.as_ref().map(|s| &s[..]) != Some("JobRemoved") {
+        return None
+    }
+    match signal.decode::<(u32, ObjectPath, String, String)>() {
+        Ok((_, ref path, _, result)) if path == job => Some(result),
+        _ => None
+    }
+}
+
+/// Whether `signal` is the JobRemoved signal for `job`, or a change to
+/// the properties of unit `name`.
+fn about(signal: &Signal, name: &UnitName, job: &ObjectPath) -> bool {
+    job_removed(signal, job).is_some() ||
+        signal.path.as_ref().map(|p| &p[..]) == Some(name.object_path().as_str())
+}
+
+/// Fail unless the start job of unit `name` finished with `result`
+/// "done".
+fn check_job(name: &UnitName, result: String) -> SystemdResult<()> {
+    if result == "done" {
+        Ok(())
+    } else {
+        Err(SystemdError::JobFailed(name.as_str().to_string(), result))
+    }
+}
+
+/// The ExitStatus of a child process.
+fn exit_status(status: process::ExitStatus) -> ExitStatus {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => ExitStatus{ code: ExitCode::Exited, status: code },
+        (None, Some(signal)) if status.core_dumped() =>
+            ExitStatus{ code: ExitCode::Dumped, status: signal },
+        (None, Some(signal)) => ExitStatus{ code: ExitCode::Killed, status: signal },
+        (None, None) => ExitStatus{ code: ExitCode::Other(0), status: 0 }
+    }
+}
+
+/// The time to wait for the next signal, or None once `deadline` has
+/// passed.
+fn wait_time(deadline: Option<Instant>) -> Option<Duration> {
+    let poll = Duration::from_millis(POLL_INTERVAL_MS);
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                None
+            } else {
+                Some(cmp::min(deadline - now, poll))
+            }
+        },
+        None => Some(poll)
+    }
+}
+
+impl Connection {
+    /// Run `cmd`, a path and its arguments, in a transient unit and
+    /// wait for it to exit.  Signals that arrive while waiting are held
+    /// for `next_signal` if they match a rule of the caller's.
+    pub fn run_command(&self, cmd: &[&str], opts: RunOptions)
+                       -> SystemdResult<ExitStatus> {
+        if cmd.is_empty() {
+            return Err(SystemdError::InvalidArg("empty command".to_string()))
+        }
+        let name = opts.unit_name()?;
+        if opts.scope {
+            self.run_scope(cmd, opts, name)
+        } else {
+            self.run_service(cmd, opts, name)
+        }
+    }
+
+    fn run_service(&self, cmd: &[&str], opts: RunOptions, name: UnitName)
+                   -> SystemdResult<ExitStatus> {
+        let mut properties = opts.common_properties(cmd)?;
+        properties.push(UnitProperty::new("ExecStart", vec![ExecCommand{
+            path: cmd[0].to_string(),
+            argv: cmd.iter().map(|s| s.to_string()).collect(),
+            ignore_failure: false
+        }])?);
+        // Keep the unit, and its exit status, until it has been read
+        properties.push(UnitProperty::new("RemainAfterExit", true)?);
+        if let Some(ref user) = opts.user {
+            properties.push(UnitProperty::new("User", user)?);
+        }
+        if let Some(ref dir) = opts.working_directory {
+            properties.push(UnitProperty::new("WorkingDirectory", dir)?);
+        }
+        if !opts.environment.is_empty() {
+            properties.push(UnitProperty::new("Environment",
+                                              &opts.environment)?);
+        }
+        let stdio = [("StandardInputFileDescriptor", &opts.stdin),
+                     ("StandardOutputFileDescriptor", &opts.stdout),
+                     ("StandardErrorFileDescriptor", &opts.stderr)];
+        for &(property, fd) in stdio.iter() {
+            if let Some(fd) = fd {
+                properties.push(UnitProperty::new(property, fd)?);
+            }
+        }
+        properties.extend(opts.properties);
+        let timeout = opts.timeout;
+        self.watching(&name, || self.run_watched(&name, properties, timeout))
+    }
+
+    /// Call `f` with the JobRemoved signals and the unit `name`'s
+    /// PropertiesChanged signals matched, and systemd subscribed to.
+    fn watching<T, F>(&self, name: &UnitName, f: F) -> SystemdResult<T>
+        where F: FnOnce() -> SystemdResult<T>
+    {
+        let rules = [MatchRule::signal()
+                     .interface(SYSTEMD.interface)
+                     .member("JobRemoved"),
+                     MatchRule::signal()
+                     .interface("org.freedesktop.DBus.Properties")
+                     .member("PropertiesChanged")
+                     .path(&name.object_path())];
+        for rule in rules.iter() {
+            self.add_match_rule(rule)?;
+        }
+        let subscribed = self.subscribed.get();
+        // systemd only sends JobRemoved to subscribed clients
+        let result = if subscribed { Ok(()) } else { self.subscribe() }
+            .and_then(|()| self.sync_signal_bus())
+            .and_then(|()| f());
+        if !subscribed {
+            let _ = self.unsubscribe();
+        }
+        for rule in rules.iter() {
+            let _ = self.remove_match_rule(rule);
+        }
+        result
+    }
+
+    fn run_watched(&self, name: &UnitName, properties: Vec<UnitProperty>,
+                   timeout: Option<Duration>) -> SystemdResult<ExitStatus> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let job = self.start_transient_unit(name.as_str().to_string(),
+                                            Mode::Fail, properties, vec![])?;
+        let unit = self.unit(name.as_str())?;
+        // Until the start job is done the unit may still be inactive
+        let mut started = false;
+        loop {
+            let wait = match wait_time(deadline) {
+                Some(wait) => wait,
+                None => {
+                    let _ = self.stop_unit(name.as_str().to_string(), Mode::Replace);
+                    return Err(SystemdError::Timeout)
+                }
+            };
+            let removed = self.watch_signal(wait, |s| about(s, name, &job))?
+                .and_then(|ref signal| job_removed(signal, &job));
+            if let Some(result) = removed {
+                if let Err(e) = check_job(name, result) {
+                    let _ = self.reset_failed_unit(name.as_str().to_string());
+                    return Err(e)
+                }
+                started = true;
+            }
+            if started && finished(&unit.active_state()?,
+                                   &unit.sub_state()?) {
+                break
+            }
+        }
+        let code: i32 = decode(vec![self.get_property(
+            unit.path(), SERVICE_INTERFACE, "ExecMainCode")?])?;
+        let status: i32 = decode(vec![self.get_property(
+            unit.path(), SERVICE_INTERFACE, "ExecMainStatus")?])?;
+        // Let systemd unload the unit
+        let _ = match unit.active_state()? {
+            ActiveState::Failed => self.reset_failed_unit(name.as_str().to_string()),
+            _ => self.stop_unit(name.as_str().to_string(), Mode::Replace).map(|_| ())
+        };
+        Ok(ExitStatus{ code: ExitCode::from_code(code), status })
+    }
+
+    fn run_scope(&self, cmd: &[&str], mut opts: RunOptions, name: UnitName)
+                 -> SystemdResult<ExitStatus> {
+        if opts.user.is_some() {
+            return Err(SystemdError::InvalidArg(
+                "user can only be set for services".to_string()))
+        }
+        let mut properties = opts.common_properties(cmd)?;
+        properties.append(&mut opts.properties);
+        let mut command = Command::new(cmd[0]);
+        command.args(&cmd[1..]);
+        for var in opts.environment.iter() {
+            let eq = var.find('=').unwrap();
+            command.env(&var[..eq], &var[eq + 1..]);
+        }
+        if let Some(ref dir) = opts.working_directory {
+            command.current_dir(dir);
+        }
+        if let Some(fd) = opts.stdin.take() {
+            command.stdin(unsafe { Stdio::from_raw_fd(fd.into_fd()) });
+        }
+        if let Some(fd) = opts.stdout.take() {
+            command.stdout(unsafe { Stdio::from_raw_fd(fd.into_fd()) });
+        }
+        if let Some(fd) = opts.stderr.take() {
+            command.stderr(unsafe { Stdio::from_raw_fd(fd.into_fd()) });
+        }
+        let mut child = HeldChild::spawn(command).map_err(|e| SystemdError::UnspecifiedError(
+            format!("running {}: {}", cmd[0], e)))?;
+        let timeout = opts.timeout;
+        let result = self.watching(&name, || {
+            properties.push(UnitProperty::new("PIDs", vec![child.pid])?);
+            self.scope_watched(cmd[0], &mut child, &name, properties, timeout)
+        });
+        if result.is_err() {
+            // Kill whatever the command left running in the scope;
+            // dropping the child then kills and reaps it.
+            let _ = self.stop_unit(name.as_str().to_string(), Mode::Replace);
+        }
+        result
+    }
+
+    /// Move `child` into scope `name`, release it, and wait for it to
+    /// exit.
+    fn scope_watched(&self, program: &str, child: &mut HeldChild, name: &UnitName,
+                     properties: Vec<UnitProperty>, timeout: Option<Duration>)
+                     -> SystemdResult<ExitStatus> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let job = self.start_transient_unit(name.as_str().to_string(),
+                                            Mode::Fail, properties, vec![])?;
+        let mut started = None;
+        loop {
+            let wait = match wait_time(deadline) {
+                Some(wait) => wait,
+                None => return Err(SystemdError::Timeout)
+            };
+            // The scope's PropertiesChanged signals wake this up when
+            // its processes exit
+            let removed = self.watch_signal(wait, |s| about(s, name, &job))?
+                .and_then(|ref signal| job_removed(signal, &job));
+            if let Some(result) = removed {
+                check_job(name, result)?;
+                started = Some(child.release().map_err(|e| SystemdError::UnspecifiedError(
+                    format!("running {}: {}", program, e)))?);
+            }
+            let process = match started {
+                Some(ref mut process) => process,
+                None => continue
+            };
+            let exited = process.try_wait().map_err(|e| SystemdError::UnspecifiedError(
+                format!("waiting for {}: {}", program, e)))?;
+            if let Some(status) = exited {
+                return Ok(exit_status(status))
+            }
+        }
+    }
+}
+
+/// Return the read and write ends of a new close-on-exec pipe.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error())
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// A child process that is held after fork, before it runs its
+/// command, until it is released.  This gives systemd time to move it
+/// into a scope.
+///
+/// `Command::spawn` only returns once the child has run its command,
+/// so it is called on a thread of its own, and the child reports its
+/// pid through a pipe.  Dropping a child that was never released stops
+/// it from running its command at all.
+struct HeldChild {
+    pid: u32,
+    gate: Option<File>,
+    spawner: Option<JoinHandle<io::Result<Child>>>,
+    child: Option<Child>
+}
+
+impl HeldChild {
+    /// Fork a child for `command`, and hold it.
+    fn spawn(mut command: Command) -> io::Result<HeldChild> {
+        let (gate_rx, gate_tx) = pipe()?;
+        let (mut pid_rx, pid_tx) = pipe()?;
+        let fds = (gate_rx.as_raw_fd(), gate_tx.as_raw_fd(),
+                   pid_rx.as_raw_fd(), pid_tx.as_raw_fd());
+        unsafe { command.pre_exec(move || hold(fds)); }
+        // The child's ends of the pipes stay open until it has forked
+        let spawner = thread::spawn(move || {
+            let child = command.spawn();
+            drop((gate_rx, pid_tx));
+            child
+        });
+        let mut pid = [0u8; 4];
+        match pid_rx.read_exact(&mut pid) {
+            Ok(()) => Ok(HeldChild{
+                pid: i32::from_ne_bytes(pid) as u32,
+                gate: Some(gate_tx),
+                spawner: Some(spawner),
+                child: None
+            }),
+            Err(e) => {
+                drop(gate_tx);
+                match spawner.join() {
+                    Ok(Err(e)) => Err(e),
+                    _ => Err(e)
+                }
+            }
+        }
+    }
+
+    /// Let the child run its command, and return it.
+    fn release(&mut self) -> io::Result<&mut Child> {
+        if let Some(mut gate) = self.gate.take() {
+            gate.write_all(&[1])?;
+        }
+        if let Some(spawner) = self.spawner.take() {
+            let child = spawner.join().unwrap_or_else(|_| Err(io::Error::new(
+                io::ErrorKind::Other, "spawning thread panicked")))?;
+            self.child = Some(child);
+        }
+        self.child.as_mut().ok_or_else(|| io::Error::new(
+            io::ErrorKind::Other, "child was not started"))
+    }
+}
+
+impl Drop for HeldChild {
+    /// Stop a held child from running its command, or kill a released
+    /// one, and reap it.
+    fn drop(&mut self) {
+        self.gate.take();
+        if let Some(spawner) = self.spawner.take() {
+            self.child = spawner.join().ok().and_then(|child| child.ok());
+        }
+        if let Some(ref mut child) = self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Run in the forked child before it runs its command: report the
+/// child's pid, then block until the parent releases it.  Only
+/// async-signal-safe calls may be made here.
+fn hold((gate_rx, gate_tx, pid_rx, pid_tx): (libc::c_int, libc::c_int,
+                                             libc::c_int, libc::c_int))
+        -> io::Result<()> {
+    unsafe {
+        // Closing the parent's ends lets the reads see them closed
+        libc::close(gate_tx);
+        libc::close(pid_rx);
+        let pid = libc::getpid();
+        let size = mem::size_of::<libc::pid_t>();
+        if libc::write(pid_tx, &pid as *const libc::pid_t as *const libc::c_void,
+                       size) != size as isize {
+            return Err(io::Error::last_os_error())
+        }
+        let mut go = 0u8;
+        loop {
+            match libc::read(gate_rx, &mut go as *mut u8 as *mut libc::c_void, 1) {
+                1 => return Ok(()),
+                n if n < 0 && io::Error::last_os_error().kind() ==
+                    io::ErrorKind::Interrupted => continue,
+                // The parent gave up without releasing the child
+                _ => return Err(io::Error::from_raw_os_error(libc::ECANCELED))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use dbus::arg::messageitem::MessageItem;
+    use mock::MockSystemd;
+    use serialize::{self, UnixFd};
+    use Connection;
+
+    fn property<'a>(properties: &'a MessageItem, name: &str) -> Option<&'a MessageItem> {
+        match properties {
+            MessageItem::Array(items) => items.iter().filter_map(|p| match p {
+                MessageItem::Struct(f) if f[0] == MessageItem::Str(name.to_string()) =>
+                    match &f[1] {
+                        MessageItem::Variant(v) => Some(&**v),
+                        _ => None
+                    },
+                _ => None
+            }).next(),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn run_service() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let mut fds = [0; 2];
+        assert_eq!(0, unsafe { ::libc::pipe(fds.as_mut_ptr()) });
+        let mut output = unsafe { File::from_raw_fd(fds[0]) };
+        let opts = RunOptions::service()
+            .name("step-1.service")
+            .user("builder")
+            .env("HOME", "/srv/build")
+            .stdout(UnixFd::new(fds[1]))
+            .memory_limit(1 << 30);
+        let exit = conn.run_command(&["/bin/echo", "hello"], opts).unwrap();
+        assert_eq!(ExitStatus{ code: ExitCode::Exited, status: 0 }, exit);
+        // The mock's record of the call holds a copy of the write end
+        let mut echoed = [0; 6];
+        output.read_exact(&mut echoed).unwrap();
+        assert_eq!(b"hello\n", &echoed);
+
+        let start = mock.calls().into_iter()
+            .find(|c| c.method == "StartTransientUnit")
+            .unwrap();
+        assert_eq!(MessageItem::Str("step-1.service".to_string()), start.args[0]);
+        assert_eq!(Some(&MessageItem::Str("builder".to_string())),
+                   property(&start.args[2], "User"));
+        assert_eq!(Some(&MessageItem::UInt64(1 << 30)),
+                   property(&start.args[2], "MemoryMax"));
+        assert_eq!(Some(&serialize::array(
+            vec![MessageItem::Str("HOME=/srv/build".to_string())], "s").unwrap()),
+                   property(&start.args[2], "Environment"));
+        assert!(mock.calls().iter().any(|c| c.method == "StopUnit"));
+
+        let exit = conn.run_command(&["/bin/false"], RunOptions::service()).unwrap();
+        assert_eq!(ExitStatus{ code: ExitCode::Exited, status: 1 }, exit);
+        assert!(!exit.success());
+        assert!(mock.calls().iter().any(|c| c.method == "ResetFailedUnit"));
+    }
+
+    #[test]
+    fn run_service_holds_signals() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        // Signals matched only by run_command's own rules are not kept
+        conn.run_command(&["/bin/true"], RunOptions::service()).unwrap();
+        assert!(conn.next_signal(Duration::from_millis(100)).unwrap().is_none());
+
+        conn.systemd_signals().unwrap();
+        mock.emit_unit_new("other.service");
+        // Let UnitNew arrive first, so run_command reads it
+        ::std::thread::sleep(Duration::from_millis(100));
+        conn.run_command(&["/bin/true"], RunOptions::service()).unwrap();
+        let mut members = vec![];
+        while let Some(signal) = conn.next_signal(Duration::from_millis(500)).unwrap() {
+            members.push(signal.member.unwrap());
+        }
+        assert!(members.iter().any(|m| m == "UnitNew"));
+        // JobRemoved matched no rule of the caller's
+        assert!(!members.iter().any(|m| m == "JobRemoved"));
+    }
+
+    #[test]
+    fn run_service_job_failed() {
+        let mock = MockSystemd::start().unwrap();
+        mock.set_job_result("broken.service", "dependency");
+        let conn = Connection::open(mock.bus()).unwrap();
+        let opts = RunOptions::service().name("broken.service");
+        match conn.run_command(&["/bin/true"], opts) {
+            Err(SystemdError::JobFailed(name, result)) => {
+                assert_eq!("broken.service", name);
+                assert_eq!("dependency", result);
+            },
+            other => panic!("expected JobFailed, got {:?}", other)
+        }
+        assert!(mock.calls().iter().any(|c| c.method == "ResetFailedUnit"));
+    }
+
+    #[test]
+    fn run_scope_job_failed() {
+        let mock = MockSystemd::start().unwrap();
+        mock.set_job_result("broken.scope", "failed");
+        let conn = Connection::open(mock.bus()).unwrap();
+        let opts = RunOptions::scope().name("broken.scope");
+        let marker = format!("/tmp/systemd-dbus-held-{}", process::id());
+        match conn.run_command(&["/bin/touch", &marker], opts) {
+            Err(SystemdError::JobFailed(name, result)) => {
+                assert_eq!("broken.scope", name);
+                assert_eq!("failed", result);
+            },
+            other => panic!("expected JobFailed, got {:?}", other)
+        }
+        // The command was held, and never ran outside the scope
+        assert!(!::std::path::Path::new(&marker).exists());
+        assert!(mock.calls().iter().any(|c| c.method == "StopUnit"));
+    }
+
+    #[test]
+    fn run_scope_timeout() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let opts = RunOptions::scope().name("slow.scope")
+            .timeout(Duration::from_millis(200));
+        let start = Instant::now();
+        match conn.run_command(&["/bin/sleep", "60"], opts) {
+            Err(SystemdError::Timeout) => (),
+            other => panic!("expected a timeout, got {:?}", other)
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let stop = mock.calls().into_iter()
+            .find(|c| c.method == "StopUnit")
+            .unwrap();
+        assert_eq!(MessageItem::Str("slow.scope".to_string()), stop.args[0]);
+    }
+
+    #[test]
+    fn exit_status_of_a_signal() {
+        // SIGABRT, with and without the core dump flag
+        assert_eq!(ExitStatus{ code: ExitCode::Killed, status: 6 },
+                   exit_status(process::ExitStatus::from_raw(6)));
+        assert_eq!(ExitStatus{ code: ExitCode::Dumped, status: 6 },
+                   exit_status(process::ExitStatus::from_raw(0x80 | 6)));
+    }
+
+    #[test]
+    fn transient_unit_signature() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let properties = vec![UnitProperty::new("Description", "idle").unwrap()];
+        conn.start_transient_unit("idle.scope".to_string(), Mode::Fail,
+                                  properties, vec![]).unwrap();
+        // An empty aux array typed as `as`, rather than a(sa(sv))
+        let args = [MessageItem::Str("idle-2.scope".to_string()),
+                    MessageItem::Str("fail".to_string()),
+                    serialize::array(vec![], "(sv)").unwrap(),
+                    serialize::array(vec![], "s").unwrap()];
+        assert!(conn.call(&conn.bus, "StartTransientUnit", &args, |_| Ok(()))
+                .is_err());
+    }
+
+    #[test]
+    fn run_scope() {
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let opts = RunOptions::scope().name("step-2.scope").tasks_max(16);
+        let start = Instant::now();
+        let exit = conn.run_command(&["/bin/sh", "-c", "sleep 0.2; exit 3"], opts)
+            .unwrap();
+        assert_eq!(ExitStatus{ code: ExitCode::Exited, status: 3 }, exit);
+        // Woken by the scope stopping, rather than by polling
+        assert!(start.elapsed() < Duration::from_millis(POLL_INTERVAL_MS));
+        let call = mock.calls().into_iter()
+            .find(|c| c.method == "StartTransientUnit")
+            .unwrap();
+        assert_eq!(Some(&MessageItem::UInt64(16)), property(&call.args[2], "TasksMax"));
+        assert!(property(&call.args[2], "PIDs").is_some());
+        // The mock stops the scope once the command has exited
+        let unit = conn.unit("step-2.scope").unwrap();
+        assert_eq!(ActiveState::Inactive, unit.active_state().unwrap());
+        assert!(conn.run_command(&["/bin/true"], RunOptions::scope().user("nobody"))
+                .is_err());
+        assert!(conn.run_command(&["/bin/true"],
+                                 RunOptions::service().name("a.scope")).is_err());
+    }
+}
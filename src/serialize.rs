@@ -17,15 +17,71 @@
 //! Serialize between Rustc Encodable and Decodable types, and
 //! Vec<dbus::MessageItem>.
 
-use std::{num,string};
-use std::ascii::AsciiExt;
-
-use dbus::MessageItem;
+use std::convert::TryFrom;
+use std::string;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use dbus;
+use dbus::arg::OwnedFd;
+use dbus::arg::messageitem::{MessageItem, MessageItemArray};
+use libc;
 use rustc_serialize::{self, Decodable, Encodable};
 
 use self::DecoderError::*;
 use self::EncoderError::*;
 
+/// Implement `Encodable` and `TypeSignature` for a struct, as a DBus
+/// struct of the named fields, in the order given, e.g.
+/// `encodable_struct!(UnitFile { name, state });`.
+macro_rules! encodable_struct {
+    ($t:ident { $($f:ident),+ }) => (
+        impl ::serialize::TypeSignature for $t {
+            fn signature() -> String {
+                let mut signature = "(".to_string();
+                $(signature.push_str(
+                    &::serialize::field_signature(|s: &$t| &s.$f));)+
+                signature.push(')');
+                signature
+            }
+        }
+
+        impl ::rustc_serialize::Encodable for $t {
+            fn encode<S: ::rustc_serialize::Encoder>(&self, s: &mut S)
+                                                     -> Result<(), S::Error> {
+                let fields = [$(stringify!($f)),+];
+                s.emit_struct(stringify!($t), fields.len(), |s| {
+                    let mut idx = 0..;
+                    $(s.emit_struct_field(stringify!($f), idx.next().unwrap(),
+                                          |s| self.$f.encode(s))?;)+
+                    Ok(())
+                })
+            }
+        }
+    )
+}
+
+/// Implement `Decodable` for a struct, from a DBus struct of the
+/// named fields, in the order given, e.g.
+/// `decodable_struct!(UnitFile { name, state });`.
+macro_rules! decodable_struct {
+    ($t:ident { $($f:ident),+ }) => (
+        impl ::rustc_serialize::Decodable for $t {
+            fn decode<D: ::rustc_serialize::Decoder>(d: &mut D)
+                                                     -> Result<$t, D::Error> {
+                let fields = [$(stringify!($f)),+];
+                d.read_struct(stringify!($t), fields.len(), |d| {
+                    let mut idx = 0..;
+                    Ok($t{
+                        $($f: d.read_struct_field(
+                            stringify!($f), idx.next().unwrap(),
+                            ::rustc_serialize::Decodable::decode)?),+
+                    })
+                })
+            }
+        }
+    )
+}
+
 
 /// Error type for serialization
 #[derive(Clone, PartialEq, Debug)]
@@ -47,10 +103,7 @@ pub struct Decoder {
 
 impl Decoder {
     fn pop(&mut self) -> MessageItem {
-        if self.stack.is_empty() {
-            assert!(false, "Nothing to pop");
-        }
-        self.stack.pop().unwrap()
+        self.stack.pop().expect("Nothing to pop")
     }
 
     /// Return a new Decoder instance which will parse the
@@ -64,22 +117,270 @@ impl Decoder {
 /// Result type for decoding dbus messages
 pub type DecodeResult<T> = Result<T, DecoderError>;
 
-macro_rules! expect {
-    ($e:expr, $t:ident) => ({
-        match $e {
-            MessageItem::$t(v) => Ok(v),
-            other => {
-                Err(ExpectedError(stringify!($t).to_string(),
-                                  format!("{:?}", other)))
+/// The struct name `ObjectPath` encodes itself as, so that the encoder
+/// and decoder can use the object path wire type.
+#[doc(hidden)]
+pub const OBJECT_PATH_STRUCT: &str = "__dbus_ObjectPath";
+
+/// The struct name `Signature` encodes itself as, so that the encoder
+/// and decoder can use the signature wire type.
+#[doc(hidden)]
+pub const SIGNATURE_STRUCT: &str = "__dbus_Signature";
+
+/// The struct name `UnixFd` encodes itself as, so that the encoder
+/// and decoder can use the unix fd wire type.
+#[doc(hidden)]
+pub const UNIX_FD_STRUCT: &str = "__dbus_UnixFd";
+
+/// The struct name `Variant` encodes itself as, so that the encoder
+/// can use the variant wire type.
+#[doc(hidden)]
+pub const VARIANT_STRUCT: &str = "__dbus_Variant";
+
+/// A file descriptor, passed over DBus as a unix fd ('h'), e.g. the
+/// lock returned by logind's Inhibit.  The descriptor is closed when
+/// this is dropped.  Encoding sends a duplicate, so this stays open.
+#[derive(Debug)]
+pub struct UnixFd(OwnedFd);
+
+impl UnixFd {
+    /// Take ownership of `fd`.
+    pub fn new(fd: RawFd) -> UnixFd {
+        UnixFd(unsafe { OwnedFd::new(fd) })
+    }
+
+    /// Give up ownership of the descriptor, without closing it.
+    pub fn into_fd(self) -> RawFd {
+        self.0.into_fd()
+    }
+}
+
+impl AsRawFd for UnixFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl Decodable for UnixFd {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D) -> Result<UnixFd, D::Error> {
+        let fd = d.read_struct(UNIX_FD_STRUCT, 1, |d| {
+            d.read_struct_field("fd", 0, |d| d.read_i32())
+        })?;
+        Ok(UnixFd::new(fd))
+    }
+}
+
+impl Encodable for UnixFd {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct(UNIX_FD_STRUCT, 1, |s| {
+            s.emit_struct_field("fd", 0, |s| s.emit_i32(self.as_raw_fd()))
+        })
+    }
+}
+
+/// A value passed over DBus as a variant ('v'), e.g. the value of a
+/// unit property given to StartTransientUnit.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant(MessageItem);
+
+impl Variant {
+    /// Encode `value` as the content of a variant.
+    pub fn new<T: Encodable + TypeSignature>(value: T) -> EncodeResult<Variant> {
+        encode(value).map(Variant)
+    }
+
+    /// The encoded content.
+    pub fn value(&self) -> &MessageItem {
+        &self.0
+    }
+}
+
+impl Encodable for Variant {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct(VARIANT_STRUCT, 2, |s| {
+            s.emit_struct_field("signature", 0,
+                                |s| s.emit_str(&self.0.signature()))?;
+            s.emit_struct_field("value", 1, |s| emit_item(s, &self.0))
+        })
+    }
+}
+
+/// Emit an already encoded `item` to `s`, so that encoding with
+/// `Encoder` gives back `item`.  Dict entries can not be emitted, as
+/// `Encoder` does not support maps.
+fn emit_item<S: rustc_serialize::Encoder>(s: &mut S, item: &MessageItem)
+                                          -> Result<(), S::Error> {
+    match item {
+        MessageItem::Str(v) => s.emit_str(v),
+        &MessageItem::Bool(v) => s.emit_bool(v),
+        &MessageItem::Byte(v) => s.emit_u8(v),
+        &MessageItem::Int16(v) => s.emit_i16(v),
+        &MessageItem::Int32(v) => s.emit_i32(v),
+        &MessageItem::Int64(v) => s.emit_i64(v),
+        &MessageItem::UInt16(v) => s.emit_u16(v),
+        &MessageItem::UInt32(v) => s.emit_u32(v),
+        &MessageItem::UInt64(v) => s.emit_u64(v),
+        &MessageItem::Double(v) => s.emit_f64(v),
+        MessageItem::ObjectPath(v) => s.emit_struct(OBJECT_PATH_STRUCT, 1, |s| {
+            s.emit_struct_field("path", 0, |s| s.emit_str(v))
+        }),
+        MessageItem::Signature(v) => s.emit_struct(SIGNATURE_STRUCT, 1, |s| {
+            s.emit_struct_field("signature", 0, |s| s.emit_str(v))
+        }),
+        MessageItem::UnixFd(fd) => s.emit_struct(UNIX_FD_STRUCT, 1, |s| {
+            s.emit_struct_field("fd", 0, |s| s.emit_i32(fd.as_raw_fd()))
+        }),
+        MessageItem::Variant(v) => s.emit_struct(VARIANT_STRUCT, 2, |s| {
+            s.emit_struct_field("signature", 0, |s| s.emit_str(&v.signature()))?;
+            s.emit_struct_field("value", 1, |s| emit_item(s, v))
+        }),
+        MessageItem::Array(items) => s.emit_seq(items.len(), |s| {
+            for (i, item) in items.iter().enumerate() {
+                s.emit_seq_elt(i, |s| emit_item(s, item))?;
+            }
+            Ok(())
+        }),
+        MessageItem::Struct(items) => s.emit_struct("", items.len(), |s| {
+            for (i, item) in items.iter().enumerate() {
+                s.emit_struct_field("", i, |s| emit_item(s, item))?;
+            }
+            Ok(())
+        }),
+        MessageItem::Dict(entries) => s.emit_map(entries.len(), |s| {
+            for (i, (k, v)) in entries.iter().enumerate() {
+                s.emit_map_elt_key(i, |s| emit_item(s, k))?;
+                s.emit_map_elt_val(i, |s| emit_item(s, v))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The DBus type signature of a Rust type, e.g. "as" for `Vec<String>`.
+///
+/// `encode` uses this to give arrays their element type, which can not
+/// be inferred from the elements of an empty array.
+pub trait TypeSignature {
+    /// The signature of a single complete type.
+    fn signature() -> String;
+}
+
+/// Implement `TypeSignature` for types with a fixed signature, e.g.
+/// `type_signature!(Mode => "s");`.
+macro_rules! type_signature {
+    ($($t:ty => $s:expr),+) => (
+        $(impl ::serialize::TypeSignature for $t {
+            fn signature() -> String {
+                $s.to_string()
+            }
+        })+
+    )
+}
+
+type_signature!(bool => "b", u8 => "y", i8 => "y", i16 => "n", u16 => "q",
+                i32 => "i", u32 => "u", isize => "i", usize => "u",
+                i64 => "x", u64 => "t", f32 => "d", f64 => "d",
+                str => "s", String => "s", UnixFd => "h", Variant => "v");
+
+impl<T: TypeSignature + ?Sized> TypeSignature for &T {
+    fn signature() -> String {
+        T::signature()
+    }
+}
+
+impl<T: TypeSignature> TypeSignature for [T] {
+    fn signature() -> String {
+        format!("a{}", T::signature())
+    }
+}
+
+impl<T: TypeSignature> TypeSignature for Vec<T> {
+    fn signature() -> String {
+        format!("a{}", T::signature())
+    }
+}
+
+/// The signature of the field of `S` that `field` returns, for use by
+/// `encodable_struct!`.
+#[doc(hidden)]
+pub fn field_signature<S, T, F>(_field: F) -> String
+    where T: TypeSignature + ?Sized, F: Fn(&S) -> &T
+{
+    T::signature()
+}
+
+/// Parse one complete type of signature `b` starting at `*i`.
+fn complete_type(b: &[u8], i: &mut usize, depth: usize) -> bool {
+    fn is_basic(c: u8) -> bool {
+        b"ybnqiuxtdhsog".contains(&c)
+    }
+
+    if depth > 64 || *i >= b.len() {
+        return false
+    }
+    let c = b[*i];
+    *i += 1;
+    match c {
+        b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' |
+        b'h' | b's' | b'o' | b'g' | b'v' => true,
+        b'a' => {
+            if *i < b.len() && b[*i] == b'{' {
+                *i += 1;
+                if *i >= b.len() || !is_basic(b[*i]) {
+                    return false
+                }
+                *i += 1;
+                if !complete_type(b, i, depth + 1) || *i >= b.len() ||
+                    b[*i] != b'}' {
+                    return false
+                }
+                *i += 1;
+                true
+            } else {
+                complete_type(b, i, depth + 1)
+            }
+        },
+        b'(' => {
+            let mut fields = 0;
+            while *i < b.len() && b[*i] != b')' {
+                if !complete_type(b, i, depth + 1) {
+                    return false
+                }
+                fields += 1;
             }
+            *i += 1;
+            fields > 0 && *i <= b.len()
+        },
+        _ => false
+    }
+}
+
+/// Split `signature` into its complete types, or None if it is not a
+/// valid signature.
+fn complete_types(signature: &str) -> Option<Vec<&str>> {
+    let b = signature.as_bytes();
+    let mut types = vec![];
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        if !complete_type(b, &mut i, 0) {
+            return None
         }
-    })
+        types.push(&signature[start..i]);
+    }
+    Some(types)
+}
+
+/// Whether `s` is a valid DBus type signature: a sequence of complete
+/// types of at most 255 characters.
+pub fn is_signature(s: &str) -> bool {
+    s.len() <= 255 && complete_types(s).is_some()
 }
 
-macro_rules! expect2 {
+macro_rules! expect {
     ($e:expr, $t:ident) => ({
         match $e {
-            MessageItem::$t(v,w) => Ok((v,w)),
+            MessageItem::$t(v) => Ok(v),
             other => {
                 Err(ExpectedError(stringify!($t).to_string(),
                                   format!("{:?}", other)))
@@ -92,27 +393,31 @@ macro_rules! read_int {
     ($name:ident, $ty:ty) => {
         fn $name(&mut self) -> DecodeResult<$ty> {
             match self.pop() {
-                MessageItem::Int16(f) => match num::cast(f) {
+                MessageItem::Byte(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
-                MessageItem::Int32(f) => match num::cast(f) {
+                MessageItem::Int16(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
-                MessageItem::Int64(f) => match num::cast(f) {
+                MessageItem::Int32(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
-                MessageItem::UInt16(f) => match num::cast(f) {
+                MessageItem::Int64(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
-                MessageItem::UInt32(f) => match num::cast(f) {
+                MessageItem::UInt16(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
-                MessageItem::UInt64(f) => match num::cast(f) {
+                MessageItem::UInt32(f) => match <$ty>::try_from(f).ok() {
+                    Some(f) => Ok(f),
+                    None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
+                },
+                MessageItem::UInt64(f) => match <$ty>::try_from(f).ok() {
                     Some(f) => Ok(f),
                     None => Err(ExpectedError("Number".to_string(), format!("{}", f))),
                 },
@@ -127,7 +432,6 @@ impl rustc_serialize::Decoder for Decoder {
     type Error = DecoderError;
 
     fn read_nil(&mut self) -> DecodeResult<()> {
-        assert!(false, "not implemented");
         Err(NotImplemented("nil".to_string()))
     }
 
@@ -164,26 +468,19 @@ impl rustc_serialize::Decoder for Decoder {
     }
 
     fn read_char(&mut self) -> DecodeResult<char> {
-        let s = try!(self.read_str());
+        let s = self.read_str()?;
         {
             let mut it = s.chars();
-            match (it.next(), it.next()) {
-                // exactly one character
-                (Some(c), None) => return Ok(c),
-                _ => ()
+            // exactly one character
+            if let (Some(c), None) = (it.next(), it.next()) {
+                return Ok(c)
             }
         }
-        Err(ExpectedError("single character string".to_string(), format!("{}", s)))
+        Err(ExpectedError("single character string".to_string(), s.to_string()))
     }
 
     fn read_str(&mut self) -> DecodeResult<string::String> {
-        // println!("decode str");
-        match self.pop() {
-            MessageItem::Str(v) => Ok(v),
-            MessageItem::ObjectPath(v) => Ok(v),
-            other => Err(ExpectedError("Str".to_string(),
-                                       format!("{:?}", other)))
-        }
+        expect!(self.pop(), Str)
     }
 
     fn read_enum<T, F>(&mut self, _name: &str, f: F) -> DecodeResult<T> where
@@ -202,7 +499,7 @@ impl rustc_serialize::Decoder for Decoder {
                 return Err(ExpectedError("String or Object".to_string(), format!("{:?}", v)))
             }
         };
-        let idx = match names.iter().position(|n| *n == &name[]) {
+        let idx = match names.iter().position(|n| *n == &name[..]) {
             Some(idx) => idx,
             None => return Err(UnknownVariantError(name))
         };
@@ -232,12 +529,25 @@ impl rustc_serialize::Decoder for Decoder {
         self.read_enum_variant_arg(idx, f)
     }
 
-    fn read_struct<T, F>(&mut self, _name: &str, _len: usize, f: F) -> DecodeResult<T> where
+    fn read_struct<T, F>(&mut self, name: &str, _len: usize, f: F) -> DecodeResult<T> where
         F: FnOnce(&mut Decoder) -> DecodeResult<T>,
     {
-        let s=try!(expect!(self.pop(), Struct));
+        let wire = match name {
+            OBJECT_PATH_STRUCT =>
+                Some(MessageItem::Str(expect!(self.pop(), ObjectPath)?.to_string())),
+            SIGNATURE_STRUCT =>
+                Some(MessageItem::Str(expect!(self.pop(), Signature)?.to_string())),
+            // Ownership of the descriptor passes to the decoded UnixFd
+            UNIX_FD_STRUCT =>
+                Some(MessageItem::Int32(expect!(self.pop(), UnixFd)?.into_fd())),
+            _ => None
+        };
+        if let Some(v) = wire {
+            return f(&mut Decoder::new(vec![v]))
+        }
+        let s=expect!(self.pop(), Struct)?;
         let mut decoder = Decoder::new(s);
-        let value = try!(f(&mut decoder));
+        let value = f(&mut decoder)?;
         Ok(value)
     }
 
@@ -248,13 +558,24 @@ impl rustc_serialize::Decoder for Decoder {
                                -> DecodeResult<T> where
         F: FnOnce(&mut Decoder) -> DecodeResult<T>
     {
-        let value = try!(f(self));
+        let value = f(self)?;
         Ok(value)
     }
 
     fn read_tuple<T, F>(&mut self, tuple_len: usize, f: F) -> DecodeResult<T> where
         F: FnOnce(&mut Decoder) -> DecodeResult<T>,
     {
+        match self.pop() {
+            MessageItem::Struct(s) => {
+                if s.len() != tuple_len {
+                    return Err(ExpectedError(format!("Tuple{}", tuple_len),
+                                             format!("Tuple{}", s.len())))
+                }
+                let mut decoder = Decoder::new(s);
+                return f(&mut decoder)
+            },
+            value => self.stack.push(value)
+        }
         self.read_seq(move |d, len| {
             if len == tuple_len {
                 f(d)
@@ -292,19 +613,20 @@ impl rustc_serialize::Decoder for Decoder {
     fn read_option<T, F>(&mut self, mut f: F) -> DecodeResult<T> where
         F: FnMut(&mut Decoder, bool) -> DecodeResult<T>,
     {
-        match self.pop() {
-            value => { self.stack.push(value); f(self, true) }
-        }
+        let value = self.pop();
+        self.stack.push(value);
+        f(self, true)
     }
 
     fn read_seq<T, F>(&mut self, f: F) -> DecodeResult<T> where
         F: FnOnce(&mut Decoder, usize) -> DecodeResult<T>,
     {
-        let (array, len) = try!(expect2!(self.pop(), Array));
+        let array = expect!(self.pop(), Array)?.into_vec();
+        let len = array.len();
         for v in array.into_iter().rev() {
             self.stack.push(v);
         }
-        f(self, num::cast(len).unwrap())
+        f(self, len)
     }
 
     fn read_seq_elt<T, F>(&mut self, _idx: usize, f: F) -> DecodeResult<T> where
@@ -316,7 +638,6 @@ impl rustc_serialize::Decoder for Decoder {
     fn read_map<T, F>(&mut self, _: F) -> DecodeResult<T> where
         F: FnOnce(&mut Decoder, usize) -> DecodeResult<T>,
     {
-        assert!(false,"not implemented");
         Err(NotImplemented("map".to_string()))
     }
 
@@ -337,8 +658,16 @@ impl rustc_serialize::Decoder for Decoder {
     }
 }
 
-/// Convenience function to decode a vector of MessageItem
+/// Convenience function to decode a vector of MessageItem.
+///
+/// A reply with several out arguments, e.g. `(b, a(sss))`, is decoded
+/// as a struct or tuple of those arguments.
 pub fn decode<T: Decodable>(items: Vec<MessageItem>) -> DecodeResult<T> {
+    let items = if items.len() > 1 {
+        vec![MessageItem::Struct(items)]
+    } else {
+        items
+    };
     let mut decoder = Decoder::new(items);
     Decodable::decode(&mut decoder)
 }
@@ -358,9 +687,66 @@ pub type EncodeResult<T> = Result<T, EncoderError>;
 
 #[derive(Clone, PartialEq, Debug)]
 enum EncoderValue {
-    Scalar(Option<MessageItem>),
-    Array(Vec<MessageItem>),
-    Struct(Vec<MessageItem>)
+    Scalar(Option<Value>),
+    Array(Vec<Value>),
+    Struct(Vec<Value>)
+}
+
+/// An encoded value.  The element type of its arrays is only known
+/// once the signature of the whole value is given to `typed`.
+#[derive(Clone, PartialEq, Debug)]
+enum Value {
+    Item(MessageItem),
+    Array(Vec<Value>),
+    Struct(Vec<Value>),
+    /// A variant, and the signature of its content
+    Variant(String, Box<Value>)
+}
+
+impl Value {
+    /// The value as a MessageItem of DBus type `signature`.
+    fn typed(self, signature: &str) -> EncodeResult<MessageItem> {
+        let mismatch = |value: &Value| InternalEncodeError(
+            format!("can not encode {:?} as {}", value, signature));
+        match self {
+            Value::Item(item) => {
+                if &*item.signature() == signature {
+                    Ok(item)
+                } else {
+                    Err(mismatch(&Value::Item(item)))
+                }
+            },
+            Value::Array(values) => {
+                let element = match signature.strip_prefix('a') {
+                    Some(element) if !element.starts_with('{') => element,
+                    _ => return Err(mismatch(&Value::Array(values)))
+                };
+                let items = values.into_iter()
+                    .map(|value| value.typed(element))
+                    .collect::<EncodeResult<Vec<_>>>()?;
+                array(items, element)
+            },
+            Value::Struct(values) => {
+                let fields = signature.strip_prefix('(')
+                    .and_then(|s| s.strip_suffix(')'))
+                    .and_then(complete_types);
+                match fields {
+                    Some(ref fields) if fields.len() == values.len() =>
+                        values.into_iter().zip(fields.iter())
+                        .map(|(value, field)| value.typed(field))
+                        .collect::<EncodeResult<Vec<_>>>()
+                        .map(MessageItem::Struct),
+                    _ => Err(mismatch(&Value::Struct(values)))
+                }
+            },
+            Value::Variant(content, value) => {
+                if signature != "v" {
+                    return Err(mismatch(&Value::Variant(content, value)))
+                }
+                Ok(MessageItem::Variant(Box::new(value.typed(&content)?)))
+            }
+        }
+    }
 }
 
 /// Encoder object, to encode to Vec<MessageItem>
@@ -375,8 +761,12 @@ impl Encoder {
     }
 
     fn emit(&mut self, v: MessageItem) -> EncodeResult<()> {
-        match &mut self.r {
-            &mut EncoderValue::Scalar(ref mut x) => {
+        self.emit_value(Value::Item(v))
+    }
+
+    fn emit_value(&mut self, v: Value) -> EncodeResult<()> {
+        match self.r {
+            EncoderValue::Scalar(ref mut x) => {
                 if x.is_none() {
                     *x = Some(v);
                     Ok(())
@@ -384,41 +774,54 @@ impl Encoder {
                     Err(InternalEncodeError("Item already has a value".to_string()))
                 }
             },
-            &mut EncoderValue::Array(ref mut x) => {
+            EncoderValue::Array(ref mut x) => {
                 x.push(v);
                 Ok(())
             },
-            &mut EncoderValue::Struct(ref mut x) => {
+            EncoderValue::Struct(ref mut x) => {
                 x.push(v);
                 Ok(())
             }
         }
     }
 
-    fn value(&mut self) -> EncodeResult<MessageItem> {
+    fn value(self) -> EncodeResult<Value> {
         match self.r {
-            EncoderValue::Scalar(ref mut v) =>
-                match v {
-                    &mut None =>
-                        Err(InternalEncodeError("no value set".to_string())),
-                    &mut Some(ref v) =>
-                        Ok(v.clone())},
-            EncoderValue::Struct(ref mut v) =>
-                Ok(MessageItem::Struct(v.clone())),
-            EncoderValue::Array(ref mut v) =>
-                Ok(MessageItem::Array(v.clone(), v.len() as i32))
+            EncoderValue::Scalar(v) =>
+                v.ok_or_else(|| InternalEncodeError("no value set".to_string())),
+            EncoderValue::Struct(v) => Ok(Value::Struct(v)),
+            EncoderValue::Array(v) => Ok(Value::Array(v))
         }
+    }
 
+    /// The value of a scalar that must be an already typed item.
+    fn item(self) -> EncodeResult<MessageItem> {
+        match self.value()? {
+            Value::Item(item) => Ok(item),
+            other => Err(InternalEncodeError(
+                format!("expected a basic value, not {:?}", other)))
+        }
     }
 }
 
-/// Utility function to encode an Encodable instance to a MessageItem
-pub fn encode<T: Encodable>(x: T) -> EncodeResult<MessageItem> {
+/// An array of `items`, whose elements all have the DBus type
+/// `element`, e.g. "s" for an array of strings.
+pub fn array(items: Vec<MessageItem>, element: &str) -> EncodeResult<MessageItem> {
+    let signature = dbus::Signature::new(format!("a{}", element))
+        .map_err(InternalEncodeError)?;
+    MessageItemArray::new(items, signature)
+        .map(MessageItem::Array)
+        .map_err(|e| InternalEncodeError(format!("{:?}", e)))
+}
+
+/// Utility function to encode an Encodable instance to a MessageItem,
+/// of the DBus type given by its `TypeSignature`.
+pub fn encode<T: Encodable + TypeSignature>(x: T) -> EncodeResult<MessageItem> {
     let mut encoder = Encoder::new();
     {
-        try!(x.encode(&mut encoder));
+        x.encode(&mut encoder)?;
     }
-    encoder.value()
+    encoder.value()?.typed(&T::signature())
 }
 
 impl rustc_serialize::Encoder for Encoder {
@@ -491,9 +894,7 @@ impl rustc_serialize::Encoder for Encoder {
     fn emit_enum<F>(&mut self, _name: &str, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        println!("Emit enum {}", _name);
-        f(self).unwrap();
-        Ok(())
+        f(self)
     }
 
     fn emit_enum_variant<F>(&mut self,
@@ -504,16 +905,12 @@ impl rustc_serialize::Encoder for Encoder {
                             -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        println!("Emit enum variant {}", name);
-        self.emit(MessageItem::Str(
-            name.to_ascii_lowercase().to_string())).unwrap();
-        Ok(())
+        self.emit(MessageItem::Str(name.to_ascii_lowercase().to_string()))
     }
 
     fn emit_enum_variant_arg<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        println!("Emit enum variant arg");
         Err(EncodeNotImplemented("Encode not implemented for enum variant arg".to_string()))
     }
 
@@ -524,10 +921,9 @@ impl rustc_serialize::Encoder for Encoder {
                                    f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        println!("Emit enum struct variant {}", _name);
         let mut encoder=Encoder{r: EncoderValue::Struct(vec![])};
-        try!(f(&mut encoder));
-        self.emit(try!(encoder.value()))
+        f(&mut encoder)?;
+        self.emit_value(encoder.value()?)
     }
 
     fn emit_enum_struct_variant_field<F>(&mut self,
@@ -536,25 +932,64 @@ impl rustc_serialize::Encoder for Encoder {
                                          f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
-        println!("Emit enum struct variant field {}", _name);
-        f(self).unwrap();
-        Ok(())
+        f(self)
     }
 
 
-    fn emit_struct<F>(&mut self, _: &str, _: usize, f: F) -> EncodeResult<()> where
+    fn emit_struct<F>(&mut self, name: &str, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
+        if name == OBJECT_PATH_STRUCT || name == SIGNATURE_STRUCT {
+            let mut encoder = Encoder::new();
+            f(&mut encoder)?;
+            let v = match encoder.item()? {
+                MessageItem::Str(v) => v,
+                other => return Err(InternalEncodeError(
+                    format!("{} must be a string, not {:?}", name, other)))
+            };
+            return self.emit(if name == OBJECT_PATH_STRUCT {
+                MessageItem::ObjectPath(dbus::Path::new(v).map_err(InternalEncodeError)?)
+            } else {
+                MessageItem::Signature(dbus::Signature::new(v).map_err(InternalEncodeError)?)
+            })
+        }
+        if name == VARIANT_STRUCT {
+            let mut encoder = Encoder{r: EncoderValue::Struct(vec![])};
+            f(&mut encoder)?;
+            let mut fields = match encoder.value()? {
+                Value::Struct(fields) => fields.into_iter(),
+                _ => unreachable!()
+            };
+            return match (fields.next(), fields.next(), fields.next()) {
+                (Some(Value::Item(MessageItem::Str(signature))), Some(value), None) =>
+                    self.emit_value(Value::Variant(signature, Box::new(value))),
+                _ => Err(InternalEncodeError(
+                    format!("{} must be a signature and a value", name)))
+            }
+        }
+        if name == UNIX_FD_STRUCT {
+            let mut encoder = Encoder::new();
+            f(&mut encoder)?;
+            let fd = match encoder.item()? {
+                MessageItem::Int32(fd) => fd,
+                other => return Err(InternalEncodeError(
+                    format!("{} must be an i32, not {:?}", name, other)))
+            };
+            let dup = unsafe { libc::dup(fd) };
+            if dup < 0 {
+                return Err(InternalEncodeError(format!("can not dup fd {}", fd)))
+            }
+            return self.emit(MessageItem::UnixFd(unsafe { OwnedFd::new(dup) }))
+        }
         let mut encoder=Encoder{r: EncoderValue::Struct(vec![])};
-        try!(f(&mut encoder));
-        self.emit(try!(encoder.value()))
+        f(&mut encoder)?;
+        self.emit_value(encoder.value()?)
     }
 
     fn emit_struct_field<F>(&mut self, _: &str, _: usize, f: F) -> EncodeResult<()> where
         F: FnOnce(&mut Encoder) -> EncodeResult<()>
     {
-        f(self).unwrap();
-        Ok(())
+        f(self)
     }
 
     fn emit_tuple<F>(&mut self, _: usize, _: F) -> EncodeResult<()> where
@@ -601,8 +1036,8 @@ impl rustc_serialize::Encoder for Encoder {
         F: FnOnce(&mut Encoder) -> EncodeResult<()>,
     {
         let mut encoder=Encoder{r: EncoderValue::Array(vec![])};
-        try!(f(&mut encoder));
-        self.emit(try!(encoder.value()))
+        f(&mut encoder)?;
+        self.emit_value(encoder.value()?)
     }
 
     fn emit_seq_elt<F>(&mut self, _: usize, f: F) -> EncodeResult<()> where
@@ -638,10 +1073,12 @@ impl rustc_serialize::Encoder for Encoder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use dbus::MessageItem;
+    use std::os::unix::io::AsRawFd;
+    use dbus::arg::messageitem::MessageItem;
 
-    #[derive(RustcDecodable, RustcEncodable)]
     struct IntField {i: i64}
+    encodable_struct!(IntField { i });
+    decodable_struct!(IntField { i });
 
     // #[test]
     // fn test_no_fields() {
@@ -656,17 +1093,129 @@ mod tests {
         assert_eq!(MessageItem::Struct(vec![MessageItem::Int64(42)]), v);
     }
 
-    #[derive(RustcDecodable, RustcEncodable)]
+    #[derive(Debug, PartialEq)]
+    struct TwoFields {b: bool, v: Vec<String>}
+    encodable_struct!(TwoFields { b, v });
+    decodable_struct!(TwoFields { b, v });
+
+    #[test]
+    fn decode_byte() {
+        assert_eq!(7u8, decode::<u8>(vec![MessageItem::Byte(7)]).unwrap());
+        let bytes = array(vec![MessageItem::Byte(1), MessageItem::Byte(2)], "y").unwrap();
+        assert_eq!(vec![1u8, 2], decode::<Vec<u8>>(vec![bytes]).unwrap());
+    }
+
+    #[test]
+    fn decode_multiple_items_as_struct() {
+        let items = vec![MessageItem::Bool(true),
+                         array(vec![MessageItem::Str("a".to_string()),
+                                    MessageItem::Str("b".to_string())], "s").unwrap()];
+        assert_eq!(TwoFields{b: true, v: vec!["a".to_string(), "b".to_string()]},
+                   decode::<TwoFields>(items.clone()).unwrap());
+        assert_eq!((true, vec!["a".to_string(), "b".to_string()]),
+                   decode::<(bool, Vec<String>)>(items).unwrap());
+    }
+
+    #[test]
+    fn signatures() {
+        for s in ["", "s", "a(sss)", "a{sv}", "(ba(sss))", "aai"].iter() {
+            assert!(is_signature(s), "{}", s);
+        }
+        for s in ["a", "()", "a{vs}", "(s", "s)", "a{s}", "z"].iter() {
+            assert!(!is_signature(s), "{}", s);
+        }
+    }
+
+    #[test]
+    fn encode_string_slice_as_array() {
+        let files: &[&str] = &["a.service", "b.service"];
+        assert_eq!(array(vec![MessageItem::Str("a.service".to_string()),
+                              MessageItem::Str("b.service".to_string())], "s").unwrap(),
+                   encode(files).unwrap());
+    }
+
+    #[test]
+    fn empty_arrays_are_typed() {
+        let empty: Vec<IntField> = vec![];
+        assert_eq!(array(vec![], "(x)").unwrap(), encode(&empty).unwrap());
+        let nested = vec![TwoFields{b: true, v: vec![]},
+                          TwoFields{b: false, v: vec!["a".to_string()]}];
+        assert_eq!("a(bas)", &*encode(&nested).unwrap().signature());
+        let properties: Vec<Variant> = vec![];
+        assert_eq!("av", &*encode(properties).unwrap().signature());
+    }
+
+    #[test]
+    fn type_signatures() {
+        assert_eq!("(x)", IntField::signature());
+        assert_eq!("(bas)", TwoFields::signature());
+        assert_eq!("aas", <Vec<&[&str]>>::signature());
+        assert_eq!("s", <&str>::signature());
+    }
+
+    #[test]
+    fn variant_of_empty_array() {
+        let empty: Vec<u32> = vec![];
+        let variant = Variant::new(&empty).unwrap();
+        assert_eq!(MessageItem::Variant(Box::new(array(vec![], "u").unwrap())),
+                   encode(&variant).unwrap());
+    }
+
+    #[test]
+    fn unix_fd() {
+        let mut fds = [0; 2];
+        assert_eq!(0, unsafe { ::libc::pipe(fds.as_mut_ptr()) });
+        let read = UnixFd::new(fds[0]);
+        let _write = UnixFd::new(fds[1]);
+        let item = encode(&read).unwrap();
+        let sent = match item {
+            MessageItem::UnixFd(ref fd) => fd.as_raw_fd(),
+            ref other => panic!("expected a UnixFd, not {:?}", other)
+        };
+        assert!(sent != read.as_raw_fd());
+        let received: UnixFd = decode(vec![item]).unwrap();
+        assert_eq!(sent, received.as_raw_fd());
+        assert!(decode::<UnixFd>(vec![MessageItem::Int32(0)]).is_err());
+    }
+
+    #[test]
+    fn variant() {
+        let files: &[&str] = &["a.service"];
+        let value = array(
+            vec![MessageItem::Str("a.service".to_string())], "s").unwrap();
+        let variant = Variant::new(files).unwrap();
+        assert_eq!(&value, variant.value());
+        assert_eq!(MessageItem::Variant(Box::new(value)),
+                   encode(&variant).unwrap());
+        let nested = Variant::new(Variant::new(&IntField{i: 3}).unwrap()).unwrap();
+        assert_eq!(MessageItem::Variant(Box::new(MessageItem::Variant(Box::new(
+            MessageItem::Struct(vec![MessageItem::Int64(3)]))))),
+                   encode(&nested).unwrap());
+    }
+
     enum TestEnum{
         A,
         B
     }
 
+    impl Encodable for TestEnum {
+        fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                               -> Result<(), S::Error> {
+            s.emit_enum("TestEnum", |s| match *self {
+                TestEnum::A => s.emit_enum_variant("A", 0, 0, |_| Ok(())),
+                TestEnum::B => s.emit_enum_variant("B", 1, 0, |_| Ok(()))
+            })
+        }
+    }
+
+    type_signature!(TestEnum => "s");
+
     #[test]
     fn encode_enum() {
         let e = TestEnum::A;
         let v = encode(&e).unwrap();
-        assert_eq!(MessageItem::Str("A".to_string()), v);
+        assert_eq!(MessageItem::Str("a".to_string()), v);
+        assert_eq!(MessageItem::Str("b".to_string()), encode(&TestEnum::B).unwrap());
     }
 
 }
@@ -0,0 +1,74 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tracing of DBus calls.
+//!
+//! Nothing is traced by default.  Install a `Tracer` on a
+//! `Connection` with `set_tracer` to receive a `CallSpan` for every
+//! DBus method call made through that connection.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use dbus::arg::messageitem::MessageItem;
+
+use SystemdError;
+
+/// A completed DBus method call.
+pub struct CallSpan<'s> {
+    /// The bus name the call was sent to
+    pub service: &'s str,
+    /// The object path the call was sent to
+    pub path: &'s str,
+    /// The interface of the called method
+    pub interface: &'s str,
+    /// The name of the called method
+    pub method: &'s str,
+    /// The encoded arguments of the call
+    pub args: &'s [MessageItem],
+    /// Time from sending the call until the reply was decoded
+    pub elapsed: Duration,
+    /// The reply items, or the error the call failed with
+    pub result: Result<&'s [MessageItem], &'s SystemdError>
+}
+
+impl<'s> fmt::Display for CallSpan<'s> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let ms = self.elapsed.as_secs() * 1000 +
+            self.elapsed.subsec_millis() as u64;
+        write!(f, "{}.{}{:?} {}ms ",
+               self.interface, self.method, self.args, ms)?;
+        match self.result {
+            Ok(reply) => write!(f, "ok {:?}", reply),
+            Err(e) => write!(f, "error {:?}", e)
+        }
+    }
+}
+
+/// Receiver for DBus call spans.
+pub trait Tracer: Send + Sync {
+    /// Called once for each completed DBus method call.
+    fn call(&self, span: &CallSpan);
+}
+
+/// A Tracer that writes one line per call to stderr.
+#[derive(Debug, Copy, Clone)]
+pub struct StderrTracer;
+
+impl Tracer for StderrTracer {
+    fn call(&self, span: &CallSpan) {
+        let _ = writeln!(&mut io::stderr(), "systemd-dbus: {}", span);
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A proxy for one unit's object.
+//!
+//! `Connection::unit` computes the unit's object path from its name,
+//! so no `GetUnit` call is needed before calling the unit.  systemd
+//! loads the unit when its object is first called.
+
+use dbus::arg::messageitem::MessageItem;
+
+use serialize::{decode, encode};
+use unit_name::UnitName;
+use watch::ActiveState;
+use {Connection, Mode, ObjectPath, SystemdResult};
+
+static UNIT_INTERFACE : &str = "org.freedesktop.systemd1.Unit";
+
+/// A unit, called through its own object rather than the manager.
+pub struct Unit<'c> {
+    conn: &'c Connection,
+    name: UnitName,
+    path: ObjectPath
+}
+
+impl Connection {
+    /// A proxy for unit `name`.  Fails if `name` is not a valid unit
+    /// name; whether the unit exists is only known once it is called.
+    pub fn unit(&self, name: &str) -> SystemdResult<Unit<'_>> {
+        let name = UnitName::new(name)?;
+        Ok(Unit{ conn: self, path: name.object_path(), name })
+    }
+}
+
+impl<'c> Unit<'c> {
+    /// The unit's name.
+    pub fn name(&self) -> &UnitName {
+        &self.name
+    }
+
+    /// The unit's object path.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The connection the unit is called through.
+    pub fn connection(&self) -> &'c Connection {
+        self.conn
+    }
+
+    /// Return property `name` of the Unit interface.
+    pub fn property(&self, name: &str) -> SystemdResult<MessageItem> {
+        self.conn.get_property(&self.path, UNIT_INTERFACE, name)
+    }
+
+    /// Return all properties of the Unit interface.
+    pub fn properties(&self) -> SystemdResult<Vec<(String, MessageItem)>> {
+        self.conn.get_all_properties(&self.path, UNIT_INTERFACE)
+    }
+
+    /// The unit's active state.
+    pub fn active_state(&self) -> SystemdResult<ActiveState> {
+        let state: String = decode(vec![self.property("ActiveState")?])?;
+        Ok(ActiveState::from(state.as_str()))
+    }
+
+    /// The unit's sub state, e.g. "running".
+    pub fn sub_state(&self) -> SystemdResult<String> {
+        Ok(decode(vec![self.property("SubState")?])?)
+    }
+
+    /// The unit's load state, e.g. "loaded" or "not-found".
+    pub fn load_state(&self) -> SystemdResult<String> {
+        Ok(decode(vec![self.property("LoadState")?])?)
+    }
+
+    /// Start the unit, returning the job.
+    pub fn start(&self, mode: Mode) -> SystemdResult<ObjectPath> {
+        self.job("Start", mode)
+    }
+
+    /// Stop the unit, returning the job.
+    pub fn stop(&self, mode: Mode) -> SystemdResult<ObjectPath> {
+        self.job("Stop", mode)
+    }
+
+    /// Reload the unit, returning the job.
+    pub fn reload(&self, mode: Mode) -> SystemdResult<ObjectPath> {
+        self.job("Reload", mode)
+    }
+
+    /// Restart the unit, returning the job.
+    pub fn restart(&self, mode: Mode) -> SystemdResult<ObjectPath> {
+        self.job("Restart", mode)
+    }
+
+    /// Restart the unit if it is running, returning the job.
+    pub fn try_restart(&self, mode: Mode) -> SystemdResult<ObjectPath> {
+        self.job("TryRestart", mode)
+    }
+
+    /// Reset the unit's failed state.
+    pub fn reset_failed(&self) -> SystemdResult<()> {
+        self.conn.call_on(&self.conn.bus, &self.path, UNIT_INTERFACE,
+                          "ResetFailed", &[], |_| Ok(()))
+    }
+
+    fn job(&self, method: &str, mode: Mode) -> SystemdResult<ObjectPath> {
+        let args = [encode(mode)?];
+        self.conn.call_on(&self.conn.bus, &self.path, UNIT_INTERFACE, method,
+                          &args, |res| Ok(decode::<ObjectPath>(res)?))
+    }
+}
@@ -0,0 +1,493 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Unit names: validation, templates and escaping.
+//!
+//! `escape`, `escape_path` and their inverses implement the algorithm
+//! of `systemd-escape`, so arbitrary strings and paths can be made
+//! into unit names.
+//!
+//! ```
+//! use systemd_dbus::unit_name::{UnitName, UnitType};
+//!
+//! let mount = UnitName::from_path("/mnt/data-vol", UnitType::Mount).unwrap();
+//! assert_eq!("mnt-data\\x2dvol.mount", mount.as_str());
+//!
+//! let template: UnitName = "getty@.service".parse().unwrap();
+//! let getty = template.with_instance("tty1").unwrap();
+//! assert_eq!("getty@tty1.service", getty.as_str());
+//! assert_eq!(Some("tty1"), getty.instance());
+//! assert_eq!("/org/freedesktop/systemd1/unit/getty_40tty1_2eservice",
+//!            getty.object_path());
+//! ```
+//!
+//! See:
+//!   http://www.freedesktop.org/software/systemd/man/systemd.unit.html
+//!   http://www.freedesktop.org/software/systemd/man/systemd-escape.html
+
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use rustc_serialize;
+
+use ObjectPath;
+
+/// The longest valid unit name.
+pub const MAX_LENGTH: usize = 255;
+
+/// The object path under which systemd exports units.
+pub const UNIT_PATH_PREFIX: &str = "/org/freedesktop/systemd1/unit";
+
+/// Errors in unit names.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnitNameError {
+    /// The name is longer than MAX_LENGTH
+    TooLong(String),
+    /// The name does not end with a known unit type suffix
+    InvalidSuffix(String),
+    /// The name, or its prefix or instance, is empty or contains an
+    /// invalid character
+    InvalidName(String),
+    /// An instance was given for a name that is not a template
+    NotATemplate(String),
+    /// A path that can not be escaped, e.g. one containing ".."
+    InvalidPath(String),
+    /// An invalid escape sequence
+    InvalidEscape(String)
+}
+
+impl fmt::Display for UnitNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            UnitNameError::TooLong(s) => write!(f, "unit name too long: {}", s),
+            UnitNameError::InvalidSuffix(s) =>
+                write!(f, "unknown unit type: {}", s),
+            UnitNameError::InvalidName(s) =>
+                write!(f, "invalid unit name: {}", s),
+            UnitNameError::NotATemplate(s) =>
+                write!(f, "not a template unit: {}", s),
+            UnitNameError::InvalidPath(s) => write!(f, "invalid path: {}", s),
+            UnitNameError::InvalidEscape(s) =>
+                write!(f, "invalid escape in {}", s)
+        }
+    }
+}
+
+/// The type of a unit, given by its name's suffix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnitType {
+    /// .service
+    Service,
+    /// .socket
+    Socket,
+    /// .target
+    Target,
+    /// .device
+    Device,
+    /// .mount
+    Mount,
+    /// .automount
+    Automount,
+    /// .swap
+    Swap,
+    /// .timer
+    Timer,
+    /// .path
+    Path,
+    /// .slice
+    Slice,
+    /// .scope
+    Scope
+}
+
+static UNIT_TYPES: [UnitType; 11] = [
+    UnitType::Service, UnitType::Socket, UnitType::Target, UnitType::Device,
+    UnitType::Mount, UnitType::Automount, UnitType::Swap, UnitType::Timer,
+    UnitType::Path, UnitType::Slice, UnitType::Scope];
+
+impl UnitType {
+    /// The suffix of names of this type, without the dot.
+    pub fn suffix(&self) -> &'static str {
+        match *self {
+            UnitType::Service => "service",
+            UnitType::Socket => "socket",
+            UnitType::Target => "target",
+            UnitType::Device => "device",
+            UnitType::Mount => "mount",
+            UnitType::Automount => "automount",
+            UnitType::Swap => "swap",
+            UnitType::Timer => "timer",
+            UnitType::Path => "path",
+            UnitType::Slice => "slice",
+            UnitType::Scope => "scope"
+        }
+    }
+
+    /// The unit type with `suffix`, without the dot.
+    pub fn from_suffix(suffix: &str) -> Option<UnitType> {
+        UNIT_TYPES.iter().find(|t| t.suffix() == suffix).copied()
+    }
+}
+
+/// A valid unit name, e.g. "sshd.service" or "getty@tty1.service".
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UnitName {
+    name: String
+}
+
+impl UnitName {
+    /// Validate `name`.
+    pub fn new(name: &str) -> Result<UnitName, UnitNameError> {
+        let invalid = || UnitNameError::InvalidName(name.to_string());
+        if name.len() > MAX_LENGTH {
+            return Err(UnitNameError::TooLong(name.to_string()))
+        }
+        let dot = name.rfind('.').ok_or_else(&invalid)?;
+        if UnitType::from_suffix(&name[dot + 1..]).is_none() {
+            return Err(UnitNameError::InvalidSuffix(name.to_string()))
+        }
+        let stem = &name[..dot];
+        let (prefix, instance) = match stem.find('@') {
+            Some(at) => (&stem[..at], Some(&stem[at + 1..])),
+            None => (stem, None)
+        };
+        if prefix.is_empty() || !prefix.chars().all(is_valid_char) ||
+            !instance.map(|i| i.chars().all(|c| c == '@' || is_valid_char(c)))
+            .unwrap_or(true) {
+            return Err(invalid())
+        }
+        Ok(UnitName{ name: name.to_string() })
+    }
+
+    /// The unit name for `path`, escaped as `systemd-escape --path`
+    /// does, e.g. "dev-sda1.device" for "/dev/sda1".
+    pub fn from_path(path: &str, unit_type: UnitType)
+                     -> Result<UnitName, UnitNameError> {
+        let escaped = escape_path(path)?;
+        UnitName::new(&format!("{}.{}", escaped, unit_type.suffix()))
+    }
+
+    /// The name of the unit with object path `path`, e.g.
+    /// "foo.service" for "/org/freedesktop/systemd1/unit/foo_2eservice".
+    pub fn from_object_path(path: &str) -> Result<UnitName, UnitNameError> {
+        let invalid = || UnitNameError::InvalidName(path.to_string());
+        if !path.starts_with(UNIT_PATH_PREFIX) ||
+            !path[UNIT_PATH_PREFIX.len()..].starts_with("/") {
+            return Err(invalid())
+        }
+        let label = &path[UNIT_PATH_PREFIX.len() + 1..];
+        let name = bus_label_unescape(label).ok_or_else(invalid)?;
+        UnitName::new(&name)
+    }
+
+    /// The object path systemd exports the unit at, so a unit can be
+    /// called without looking up its path with GetUnit.
+    pub fn object_path(&self) -> ObjectPath {
+        ObjectPath(format!("{}/{}", UNIT_PATH_PREFIX, bus_label_escape(&self.name)))
+    }
+
+    /// The name as a string.
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+
+    /// The unit type, from the suffix.
+    pub fn unit_type(&self) -> UnitType {
+        UnitType::from_suffix(&self.name[self.dot() + 1..]).unwrap()
+    }
+
+    /// The part of the name before any '@' and the suffix.
+    pub fn prefix(&self) -> &str {
+        let stem = &self.name[..self.dot()];
+        match stem.find('@') {
+            Some(at) => &stem[..at],
+            None => stem
+        }
+    }
+
+    /// The escaped instance of an instantiated template unit,
+    /// e.g. "tty1" for "getty@tty1.service".
+    pub fn instance(&self) -> Option<&str> {
+        let stem = &self.name[..self.dot()];
+        stem.find('@')
+            .map(|at| &stem[at + 1..])
+            .and_then(|i| if i.is_empty() { None } else { Some(i) })
+    }
+
+    /// Whether this is a template, e.g. "getty@.service".
+    pub fn is_template(&self) -> bool {
+        self.name[..self.dot()].ends_with("@") && self.instance().is_none()
+    }
+
+    /// The template of an instance, e.g. "getty@.service" for
+    /// "getty@tty1.service".
+    pub fn template(&self) -> Option<UnitName> {
+        self.instance().map(|_| UnitName{
+            name: format!("{}@.{}", self.prefix(), self.unit_type().suffix())
+        })
+    }
+
+    /// Instantiate this template with `instance`, which is escaped as
+    /// `systemd-escape --template` does.
+    pub fn with_instance(&self, instance: &str) -> Result<UnitName, UnitNameError> {
+        if !self.is_template() {
+            return Err(UnitNameError::NotATemplate(self.name.clone()))
+        }
+        UnitName::new(&format!("{}@{}.{}", self.prefix(), escape(instance),
+                               self.unit_type().suffix()))
+    }
+
+    fn dot(&self) -> usize {
+        self.name.rfind('.').unwrap()
+    }
+}
+
+impl FromStr for UnitName {
+    type Err = UnitNameError;
+
+    fn from_str(s: &str) -> Result<UnitName, UnitNameError> {
+        UnitName::new(s)
+    }
+}
+
+impl Deref for UnitName {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for UnitName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl rustc_serialize::Decodable for UnitName {
+    fn decode<D: rustc_serialize::Decoder>(d: &mut D)
+                                           -> Result<UnitName, D::Error> {
+        let name = d.read_str()?;
+        UnitName::new(&name).map_err(|e| d.error(&e.to_string()))
+    }
+}
+
+impl rustc_serialize::Encodable for UnitName {
+    fn encode<S: rustc_serialize::Encoder>(&self, s: &mut S)
+                                           -> Result<(), S::Error> {
+        s.emit_str(&self.name)
+    }
+}
+
+type_signature!(UnitName => "s");
+
+/// Characters allowed in a unit name prefix or instance, besides '@'
+/// in an instance.
+fn is_valid_char(c: char) -> bool {
+    c.is_ascii() && (c.is_alphanumeric() || c == ':' || c == '-' ||
+                     c == '_' || c == '.' || c == '\\')
+}
+
+/// Escape `s` for use in a unit name, as `systemd-escape` does:
+/// '/' becomes '-', and '-', '\', a leading '.' and anything else that
+/// is not alphanumeric, ':', '_' or '.' becomes \xNN.
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for (i, b) in s.bytes().enumerate() {
+        let c = b as char;
+        if c == '/' {
+            escaped.push('-');
+        } else if (i == 0 && c == '.') || c == '-' || c == '\\' ||
+            !is_valid_char(c) {
+            escaped.push_str(&format!("\\x{:02x}", b));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+/// Escape `path` for use in a unit name, as `systemd-escape --path`
+/// does: redundant slashes are removed, the root becomes "-", and the
+/// path may not contain "." or ".." components.
+pub fn escape_path(path: &str) -> Result<String, UnitNameError> {
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    if components.iter().any(|c| *c == "." || *c == "..") {
+        return Err(UnitNameError::InvalidPath(path.to_string()))
+    }
+    if components.is_empty() {
+        return Ok("-".to_string())
+    }
+    Ok(escape(&components.join("/")))
+}
+
+/// Reverse `escape`: '-' becomes '/' and \xNN the byte it stands for.
+pub fn unescape(s: &str) -> Result<String, UnitNameError> {
+    let invalid = || UnitNameError::InvalidEscape(s.to_string());
+    let bytes = s.as_bytes();
+    let mut unescaped = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'-' => unescaped.push(b'/'),
+            b'\\' => {
+                if i + 4 > bytes.len() || bytes[i + 1] != b'x' {
+                    return Err(invalid())
+                }
+                let hex = ::std::str::from_utf8(&bytes[i + 2..i + 4])
+                    .map_err(|_| invalid())?;
+                unescaped.push(u8::from_str_radix(hex, 16)
+                               .map_err(|_| invalid())?);
+                i += 3;
+            },
+            b => unescaped.push(b)
+        }
+        i += 1;
+    }
+    String::from_utf8(unescaped).map_err(|_| invalid())
+}
+
+/// Reverse `escape_path`, giving an absolute path.
+pub fn unescape_path(s: &str) -> Result<String, UnitNameError> {
+    if s == "-" {
+        return Ok("/".to_string())
+    }
+    let path = unescape(s)?;
+    if path.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+        return Err(UnitNameError::InvalidPath(path))
+    }
+    Ok(format!("/{}", path))
+}
+
+/// Escape `s` as one element of an object path, as sd-bus does:
+/// bytes other than ASCII letters and digits, and a leading digit,
+/// become _xx, and the empty string becomes "_".
+pub fn bus_label_escape(s: &str) -> String {
+    if s.is_empty() {
+        return "_".to_string()
+    }
+    let mut escaped = String::new();
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            b'a'..=b'z' | b'A'..=b'Z' => escaped.push(b as char),
+            b'0'..=b'9' if i > 0 => escaped.push(b as char),
+            b => escaped.push_str(&format!("_{:02x}", b))
+        }
+    }
+    escaped
+}
+
+/// Reverse `bus_label_escape`, or None if `label` is not a valid
+/// escaped label.
+pub fn bus_label_unescape(label: &str) -> Option<String> {
+    if label == "_" {
+        return Some(String::new())
+    }
+    let bytes = label.as_bytes();
+    let mut unescaped = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'_' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            let hex = match ::std::str::from_utf8(hex) {
+                Ok(hex) => hex,
+                Err(_) => return None
+            };
+            match u8::from_str_radix(hex, 16) {
+                Ok(b) => unescaped.push(b),
+                Err(_) => return None
+            }
+            i += 3;
+        } else if bytes[i].is_ascii_alphanumeric() {
+            unescaped.push(bytes[i]);
+            i += 1;
+        } else {
+            return None
+        }
+    }
+    String::from_utf8(unescaped).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate() {
+        let name = UnitName::new("sshd.service").unwrap();
+        assert_eq!(UnitType::Service, name.unit_type());
+        assert_eq!("sshd", name.prefix());
+        assert_eq!(None, name.instance());
+        assert_eq!(UnitNameError::InvalidSuffix("sshd.daemon".to_string()),
+                   UnitName::new("sshd.daemon").unwrap_err());
+        assert!(UnitName::new("sshd").is_err());
+        assert!(UnitName::new(".service").is_err());
+        assert!(UnitName::new("a b.service").is_err());
+        assert!(UnitName::new("@x.service").is_err());
+        let long = format!("{}.service", (0..250).map(|_| "a").collect::<String>());
+        assert_eq!(UnitNameError::TooLong(long.clone()),
+                   UnitName::new(&long).unwrap_err());
+    }
+
+    #[test]
+    fn templates() {
+        let template = UnitName::new("container@.service").unwrap();
+        assert!(template.is_template());
+        assert_eq!(None, template.template());
+        let instance = template.with_instance("my/app").unwrap();
+        assert_eq!("container@my-app.service", instance.as_str());
+        assert!(!instance.is_template());
+        assert_eq!(Some(template), instance.template());
+        assert_eq!(UnitNameError::NotATemplate("container@my-app.service"
+                                               .to_string()),
+                   instance.with_instance("x").unwrap_err());
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!("Hallo\\x20Welt\\x21-da\\x2dist", escape("Hallo Welt!/da-ist"));
+        assert_eq!("\\x2efoo.bar", escape(".foo.bar"));
+        assert_eq!("Hallo Welt!/da-ist",
+                   unescape("Hallo\\x20Welt\\x21-da\\x2dist").unwrap());
+        assert_eq!("dev-disk-by\\x2dlabel-data", escape_path("/dev//disk/by-label/data/")
+                   .unwrap());
+        assert_eq!("-", escape_path("/").unwrap());
+        assert!(escape_path("/mnt/../etc").is_err());
+        assert_eq!("/dev/disk/by-label/data",
+                   unescape_path("dev-disk-by\\x2dlabel-data").unwrap());
+        assert_eq!("/", unescape_path("-").unwrap());
+        assert!(unescape("\\x4").is_err());
+        assert_eq!("mnt-data\\x2dvol.mount",
+                   UnitName::from_path("/mnt/data-vol", UnitType::Mount)
+                   .unwrap().as_str());
+    }
+
+    #[test]
+    fn object_paths() {
+        let name = UnitName::new("dbus-org.freedesktop.login1.service").unwrap();
+        let path = name.object_path();
+        assert_eq!("/org/freedesktop/systemd1/unit/\
+                    dbus_2dorg_2efreedesktop_2elogin1_2eservice", path);
+        assert_eq!(name, UnitName::from_object_path(&path).unwrap());
+        assert_eq!("_31x", bus_label_escape("1x"));
+        assert_eq!("_", bus_label_escape(""));
+        assert_eq!(Some("1x".to_string()), bus_label_unescape("_31x"));
+        assert_eq!(None, bus_label_unescape("a_2"));
+        assert!(UnitName::from_object_path("/org/freedesktop/systemd1/job/1")
+                .is_err());
+        assert!(UnitName::from_object_path("/org/freedesktop/systemd1/unitx")
+                .is_err());
+    }
+}
@@ -0,0 +1,723 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parse and serialize systemd unit files.
+//!
+//! A `Document` keeps the raw text of every line it was parsed from,
+//! so serializing an unmodified document gives back exactly the input,
+//! comments and ordering included.  Only the entries that are changed
+//! through the API are rewritten.
+//!
+//! ```
+//! use systemd_dbus::unitfile::Document;
+//!
+//! let mut doc: Document = "# managed\n[Service]\nExecStart=/bin/true\n"
+//!     .parse().unwrap();
+//! doc.section_mut("Service").unwrap().set("Restart", "always").unwrap();
+//! assert_eq!("# managed\n[Service]\nExecStart=/bin/true\nRestart=always\n",
+//!            doc.to_string());
+//! ```
+//!
+//! See:
+//!   http://www.freedesktop.org/software/systemd/man/systemd.syntax.html
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Errors in parsing or changing a unit file.
+#[derive(Debug)]
+pub enum UnitFileError {
+    /// A line could not be parsed.  Lines are numbered from 1.
+    Syntax(usize, String),
+    /// A value contains an invalid escape sequence
+    InvalidEscape(String),
+    /// A key or value can not be written to a unit file
+    InvalidValue(String, String),
+    /// The unit file could not be read
+    Io(io::Error)
+}
+
+impl fmt::Display for UnitFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &UnitFileError::Syntax(line, ref s) =>
+                write!(f, "line {}: {}", line, s),
+            UnitFileError::InvalidEscape(s) =>
+                write!(f, "invalid escape in {}", s),
+            UnitFileError::InvalidValue(k, v) =>
+                write!(f, "invalid value for {}: {}", k, v),
+            UnitFileError::Io(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// An assignment of a value to a key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    key: String,
+    value: String,
+    raw: String
+}
+
+impl Entry {
+    /// The key, e.g. "ExecStart".
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The value, with continuation lines joined and surrounding
+    /// whitespace removed.  Escapes are left as written, since
+    /// systemd only interprets them for some keys.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// The value with escapes replaced by the characters they stand for.
+    pub fn unescaped(&self) -> Result<String, UnitFileError> {
+        unescape(&self.value)
+    }
+
+    /// The text the entry was parsed from, including continuation
+    /// lines and comments within them.
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+}
+
+/// A line, or in the case of an entry with continuations, lines, of
+/// a unit file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    /// A line containing only whitespace
+    Blank(String),
+    /// A comment, starting with '#' or ';'
+    Comment(String),
+    /// A key=value assignment
+    Entry(Entry)
+}
+
+impl Item {
+    fn raw(&self) -> &str {
+        match self {
+            Item::Blank(s) => s,
+            Item::Comment(s) => s,
+            Item::Entry(e) => &e.raw
+        }
+    }
+
+    fn raw_mut(&mut self) -> &mut String {
+        match *self {
+            Item::Blank(ref mut s) => s,
+            Item::Comment(ref mut s) => s,
+            Item::Entry(ref mut e) => &mut e.raw
+        }
+    }
+}
+
+/// A section, e.g. [Unit], and the lines up to the next section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Section {
+    name: String,
+    header: String,
+    items: Vec<Item>
+}
+
+impl Section {
+    /// A new empty section.
+    pub fn new(name: &str) -> Section {
+        Section{
+            name: name.to_string(),
+            header: format!("[{}]\n", name),
+            items: vec![]
+        }
+    }
+
+    /// The section name, without brackets.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The lines of the section, in order.
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// The entries of the section, in order.
+    pub fn entries(&self) -> Vec<&Entry> {
+        self.items.iter().filter_map(|item| match item {
+            Item::Entry(e) => Some(e),
+            _ => None
+        }).collect()
+    }
+
+    /// The value of the last assignment to `key`.  This is empty if
+    /// the key was last reset with an empty assignment.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries().into_iter().rev()
+            .find(|e| e.key == key)
+            .map(|e| e.value())
+    }
+
+    /// The values assigned to a list-valued `key`, such as ExecStartPre,
+    /// in order.  An empty assignment resets the list.
+    pub fn values(&self, key: &str) -> Vec<&str> {
+        let mut values = vec![];
+        for entry in self.entries().into_iter().filter(|e| e.key == key) {
+            if entry.value.is_empty() {
+                values.clear();
+            } else {
+                values.push(entry.value());
+            }
+        }
+        values
+    }
+
+    /// Append an assignment of `value` to `key`.  `value` is written as
+    /// given, less surrounding whitespace; use `escape` for values that
+    /// systemd unescapes, or that must keep that whitespace.
+    pub fn add(&mut self, key: &str, value: &str) -> Result<(), UnitFileError> {
+        let entry = new_entry(key, value)?;
+        terminate(self.items.last_mut().map(|i| i.raw_mut())
+                  .unwrap_or(&mut self.header));
+        self.items.push(Item::Entry(entry));
+        Ok(())
+    }
+
+    /// Replace the assignments to `key` with a single assignment of
+    /// `value`, in place of the first, or at the end of the section if
+    /// there were none.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), UnitFileError> {
+        let entry = new_entry(key, value)?;
+        let first = self.items.iter().position(|item| is_entry(item, key));
+        match first {
+            Some(i) => {
+                let terminated = self.items[i].raw().ends_with("\n");
+                self.items[i] = Item::Entry(entry);
+                if !terminated {
+                    self.items[i].raw_mut().pop();
+                }
+                let mut n = 0;
+                self.items.retain(|item| {
+                    n += 1;
+                    n == i + 1 || !is_entry(item, key)
+                });
+                Ok(())
+            },
+            None => self.add(key, &entry.value)
+        }
+    }
+
+    /// Remove all assignments to `key`.
+    pub fn remove(&mut self, key: &str) {
+        self.items.retain(|item| !is_entry(item, key));
+    }
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", self.header)?;
+        for item in self.items.iter() {
+            write!(f, "{}", item.raw())?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed unit file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Document {
+    preamble: Vec<Item>,
+    sections: Vec<Section>
+}
+
+impl Document {
+    /// A new empty document.
+    pub fn new() -> Document {
+        Document{ preamble: vec![], sections: vec![] }
+    }
+
+    /// Read and parse the unit file at `path`.
+    pub fn read(path: &Path) -> Result<Document, UnitFileError> {
+        let mut text = String::new();
+        (File::open(path)
+             .and_then(|mut f| f.read_to_string(&mut text))
+             .map_err(UnitFileError::Io))?;
+        text.parse()
+    }
+
+    /// The blank lines and comments before the first section.
+    pub fn preamble(&self) -> &[Item] {
+        &self.preamble
+    }
+
+    /// The sections, in order.
+    pub fn sections(&self) -> &[Section] {
+        &self.sections
+    }
+
+    /// The first section called `name`.  systemd merges sections with
+    /// the same name, but they are kept apart here to preserve the
+    /// layout of the file.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    /// The first section called `name`, for changing.
+    pub fn section_mut(&mut self, name: &str) -> Option<&mut Section> {
+        self.sections.iter_mut().find(|s| s.name == name)
+    }
+
+    /// Append a new empty section called `name`, separated from the
+    /// previous one by a blank line.
+    pub fn add_section(&mut self, name: &str) -> &mut Section {
+        match self.sections.last_mut() {
+            Some(section) => {
+                terminate(section.items.last_mut().map(|i| i.raw_mut())
+                          .unwrap_or(&mut section.header));
+                section.items.push(Item::Blank("\n".to_string()));
+            },
+            None => if let Some(item) = self.preamble.last_mut() {
+                terminate(item.raw_mut());
+            }
+        }
+        self.sections.push(Section::new(name));
+        self.sections.last_mut().unwrap()
+    }
+
+    /// The value of the last assignment to `key` in the sections
+    /// called `section`.
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections.iter().rev()
+            .filter(|s| s.name == section)
+            .filter_map(|s| s.get(key))
+            .next()
+    }
+
+    /// The values assigned to a list-valued `key` in the sections
+    /// called `section`, in order.  An empty assignment resets the list.
+    pub fn values(&self, section: &str, key: &str) -> Vec<&str> {
+        let mut values = vec![];
+        for s in self.sections.iter().filter(|s| s.name == section) {
+            for entry in s.entries().into_iter().filter(|e| e.key == key) {
+                if entry.value.is_empty() {
+                    values.clear();
+                } else {
+                    values.push(entry.value());
+                }
+            }
+        }
+        values
+    }
+
+    /// Append the lines of `other`, as systemd applies a drop-in after
+    /// the unit file.
+    pub fn append(&mut self, other: Document) {
+        let items = match self.sections.last_mut() {
+            Some(section) => {
+                terminate(section.items.last_mut().map(|i| i.raw_mut())
+                          .unwrap_or(&mut section.header));
+                &mut section.items
+            },
+            None => {
+                if let Some(item) = self.preamble.last_mut() {
+                    terminate(item.raw_mut());
+                }
+                &mut self.preamble
+            }
+        };
+        items.extend(other.preamble);
+        self.sections.extend(other.sections);
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        for item in self.preamble.iter() {
+            write!(f, "{}", item.raw())?;
+        }
+        for section in self.sections.iter() {
+            write!(f, "{}", section)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Document {
+    type Err = UnitFileError;
+
+    fn from_str(s: &str) -> Result<Document, UnitFileError> {
+        let mut doc = Document::new();
+        let lines = split_lines(s);
+        let mut i = 0;
+        while i < lines.len() {
+            let number = i + 1;
+            let line = lines[i];
+            let text = line.trim();
+            i += 1;
+            let item = if text.is_empty() {
+                Item::Blank(line.to_string())
+            } else if is_comment(text) {
+                Item::Comment(line.to_string())
+            } else if text.starts_with("[") {
+                if !text.ends_with("]") || text.len() < 3 {
+                    return Err(UnitFileError::Syntax(
+                        number, format!("invalid section header {}", text)))
+                }
+                doc.sections.push(Section{
+                    name: text[1..text.len() - 1].to_string(),
+                    header: line.to_string(),
+                    items: vec![]
+                });
+                continue
+            } else {
+                let eq = match text.find('=') {
+                    Some(eq) => eq,
+                    None => return Err(UnitFileError::Syntax(
+                        number, format!("missing '=' in {}", text)))
+                };
+                let key = text[..eq].trim();
+                if key.is_empty() {
+                    return Err(UnitFileError::Syntax(
+                        number, format!("missing key in {}", text)))
+                }
+                let mut raw = line.to_string();
+                let mut value = text[eq + 1..].to_string();
+                // A trailing backslash continues the value on the next
+                // line, skipping comments, and is replaced by a space.
+                while continues(&value) {
+                    value.pop();
+                    value.push(' ');
+                    while i < lines.len() && is_comment(lines[i].trim()) {
+                        raw.push_str(lines[i]);
+                        i += 1;
+                    }
+                    if i == lines.len() {
+                        break
+                    }
+                    raw.push_str(lines[i]);
+                    value.push_str(lines[i].trim_end());
+                    i += 1;
+                }
+                Item::Entry(Entry{
+                    key: key.to_string(),
+                    value: value.trim().to_string(),
+                    raw
+                })
+            };
+            match doc.sections.last_mut() {
+                Some(section) => section.items.push(item),
+                None => match item {
+                    Item::Entry(_) => return Err(UnitFileError::Syntax(
+                        number, "assignment outside of a section".to_string())),
+                    item => doc.preamble.push(item)
+                }
+            }
+        }
+        Ok(doc)
+    }
+}
+
+/// Escape `s` so that systemd's unescaping gives back `s`.  Values
+/// are trimmed when they are parsed, so whitespace at either end of
+/// `s` is escaped too.
+pub fn escape(s: &str) -> String {
+    let start = s.len() - s.trim_start().len();
+    let end = start + s.trim().len();
+    let mut escaped = String::new();
+    for (i, c) in s.char_indices() {
+        let edge = i < start || i >= end;
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\'' => escaped.push_str("\\'"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if (c as u32) < 0x20 || c == '\x7f' =>
+                escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            ' ' if edge => escaped.push_str("\\s"),
+            c if edge && c.is_whitespace() =>
+                escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+/// Replace the C-style escapes that systemd understands in `s`:
+/// \a \b \f \n \r \t \v \\ \" \' \s, \xNN, octal \NNN, \uNNNN and
+/// \UNNNNNNNN.  \xNN and \NNN stand for a byte, so a multi-byte
+/// character can be written as the escapes of its UTF-8 encoding.
+pub fn unescape(s: &str) -> Result<String, UnitFileError> {
+    let invalid = || UnitFileError::InvalidEscape(s.to_string());
+    let mut unescaped = vec![];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push_char(&mut unescaped, c);
+            continue
+        }
+        let c = match chars.next() {
+            Some('a') => '\x07',
+            Some('b') => '\x08',
+            Some('f') => '\x0c',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some('v') => '\x0b',
+            Some('s') => ' ',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('\'') => '\'',
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                if digits.len() != 2 {
+                    return Err(invalid())
+                }
+                let byte = u8::from_str_radix(&digits, 16)
+                    .map_err(|_| invalid())?;
+                unescaped.push(byte);
+                continue
+            },
+            Some(e @ 'u') | Some(e @ 'U') => {
+                let len = if e == 'u' { 4 } else { 8 };
+                let digits: String = chars.by_ref().take(len).collect();
+                if digits.len() != len {
+                    return Err(invalid())
+                }
+                let code = u32::from_str_radix(&digits, 16)
+                    .map_err(|_| invalid())?;
+                ::std::char::from_u32(code).ok_or_else(&invalid)?
+            },
+            Some(d @ '0'..='7') => {
+                let rest: String = chars.by_ref().take(2).collect();
+                let digits = format!("{}{}", d, rest);
+                let code = u32::from_str_radix(&digits, 8)
+                    .map_err(|_| invalid())?;
+                if digits.len() != 3 || code > 0xff {
+                    return Err(invalid())
+                }
+                unescaped.push(code as u8);
+                continue
+            },
+            _ => return Err(invalid())
+        };
+        push_char(&mut unescaped, c);
+    }
+    String::from_utf8(unescaped).map_err(|_| invalid())
+}
+
+/// Append the UTF-8 encoding of `c` to `bytes`.
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    let mut buf = [0; 4];
+    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+}
+
+/// Whether `value` ends in a backslash that is not itself escaped,
+/// and so continues on the next line.
+fn continues(value: &str) -> bool {
+    value.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1
+}
+
+/// Split `s` into lines, keeping the line ends.
+fn split_lines(s: &str) -> Vec<&str> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if c == '\n' {
+            lines.push(&s[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < s.len() {
+        lines.push(&s[start..]);
+    }
+    lines
+}
+
+fn is_comment(text: &str) -> bool {
+    text.starts_with("#") || text.starts_with(";")
+}
+
+fn is_entry(item: &Item, key: &str) -> bool {
+    match item {
+        Item::Entry(e) => e.key == key,
+        _ => false
+    }
+}
+
+/// End `raw` with a newline, so that a line can follow it.
+fn terminate(raw: &mut String) {
+    if !raw.ends_with("\n") {
+        raw.push('\n');
+    }
+}
+
+fn new_entry(key: &str, value: &str) -> Result<Entry, UnitFileError> {
+    if key.is_empty() || key.contains('=') || key.starts_with("[") ||
+        is_comment(key) || key.chars().any(|c| c.is_whitespace()) {
+        return Err(UnitFileError::InvalidValue(key.to_string(),
+                                               "invalid key".to_string()))
+    }
+    let value = value.trim();
+    if value.contains('\n') || continues(value) {
+        return Err(UnitFileError::InvalidValue(key.to_string(),
+                                               value.to_string()))
+    }
+    Ok(Entry{
+        key: key.to_string(),
+        value: value.to_string(),
+        raw: format!("{}={}\n", key, value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static UNIT: &str = "\
+# Generated by deploy
+; do not edit
+
+[Unit]
+Description=My app
+After=network.target
+
+[Service]
+Environment=A=1
+ExecStartPre=/bin/echo one
+ExecStartPre=
+ExecStartPre=/bin/echo two
+ExecStart=/usr/bin/app \\
+    --flag \\
+# a comment in a continuation
+    --other
+  Restart = on-failure
+[Install]
+WantedBy=multi-user.target";
+
+    #[test]
+    fn round_trip() {
+        let doc: Document = UNIT.parse().unwrap();
+        assert_eq!(UNIT, doc.to_string());
+        assert_eq!(3, doc.preamble().len());
+        assert_eq!(vec!["Unit", "Service", "Install"],
+                   doc.sections().iter().map(|s| s.name()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn values() {
+        let doc: Document = UNIT.parse().unwrap();
+        let service = doc.section("Service").unwrap();
+        assert_eq!(Some("A=1"), service.get("Environment"));
+        assert_eq!(Some("on-failure"), service.get("Restart"));
+        assert_eq!(Some("/usr/bin/app      --flag      --other"),
+                   service.get("ExecStart"));
+        assert_eq!(vec!["/bin/echo two"], service.values("ExecStartPre"));
+        assert_eq!(Some("multi-user.target"), doc.get("Install", "WantedBy"));
+    }
+
+    #[test]
+    fn changes() {
+        let mut doc: Document = UNIT.parse().unwrap();
+        {
+            let service = doc.section_mut("Service").unwrap();
+            service.set("ExecStartPre", "/bin/true").unwrap();
+            service.remove("Environment");
+            assert!(service.add("Bad Key", "x").is_err());
+        }
+        doc.section_mut("Install").unwrap().add("Alias", "app.service").unwrap();
+        doc.add_section("X-Extra").add("Key", "value").unwrap();
+        let text = doc.to_string();
+        assert!(text.contains("[Service]\nExecStartPre=/bin/true\nExecStart="));
+        assert!(text.ends_with("WantedBy=multi-user.target\nAlias=app.service\n\
+                                \n[X-Extra]\nKey=value\n"));
+        assert_eq!(doc, text.parse().unwrap());
+    }
+
+    #[test]
+    fn append() {
+        let mut doc: Document = "[Service]\nLimitNOFILE=1024\nExecStartPre=/a"
+            .parse().unwrap();
+        doc.append("# override\n[Service]\nLimitNOFILE=65536\n\
+                    ExecStartPre=\nExecStartPre=/b\n".parse().unwrap());
+        assert_eq!("[Service]\nLimitNOFILE=1024\nExecStartPre=/a\n# override\n\
+                    [Service]\nLimitNOFILE=65536\nExecStartPre=\nExecStartPre=/b\n",
+                   doc.to_string());
+        assert_eq!(Some("65536"), doc.get("Service", "LimitNOFILE"));
+        assert_eq!(vec!["/b"], doc.values("Service", "ExecStartPre"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!("Key=value\n".parse::<Document>().is_err());
+        assert!("[Unit\n".parse::<Document>().is_err());
+        match "[Unit]\nnot an assignment\n".parse::<Document>() {
+            Err(UnitFileError::Syntax(2, _)) => (),
+            other => panic!("unexpected {:?}", other)
+        }
+    }
+
+    #[test]
+    fn escapes() {
+        let s = "a \"b\"\tc\\d\x01";
+        assert_eq!(s, unescape(&escape(s)).unwrap());
+        assert_eq!("a b\u{e9}A", unescape("a\\sb\\u00e9\\101").unwrap());
+        assert!(unescape("\\q").is_err());
+        assert!(unescape("\\x4").is_err());
+    }
+
+    #[test]
+    fn escape_surrounding_whitespace() {
+        assert_eq!("\\sa b\\s\\s", escape(" a b  "));
+        assert_eq!("\\u3000a", escape("\u{3000}a"));
+        for s in ["  padded ", " ", "\u{a0}x\u{2003}"].iter() {
+            let mut doc = Document::new();
+            doc.add_section("Service").set("Environment", &escape(s)).unwrap();
+            let doc: Document = doc.to_string().parse().unwrap();
+            let value = doc.get("Service", "Environment").unwrap();
+            assert_eq!(*s, unescape(value).unwrap());
+        }
+    }
+
+    #[test]
+    fn set_trims_the_raw_value() {
+        let mut doc = Document::new();
+        doc.add_section("Service").set("Nice", "  5 ").unwrap();
+        assert_eq!("[Service]\nNice=5\n", doc.to_string());
+        let entry = doc.section("Service").unwrap().entries()[0];
+        assert_eq!("Nice=5\n", entry.raw());
+        assert_eq!("5", entry.value());
+    }
+
+    #[test]
+    fn byte_escapes() {
+        assert_eq!("\u{e9}", unescape("\\303\\251").unwrap());
+        assert_eq!("\u{e9}", unescape("\\xc3\\xa9").unwrap());
+        assert!(unescape("\\351").is_err());
+    }
+
+    #[test]
+    fn continuation_parity() {
+        let doc: Document = "[Service]\nA=x\\\\\nB=y\\\\\\\nz\n".parse().unwrap();
+        assert_eq!(Some("x\\\\"), doc.get("Service", "A"));
+        assert_eq!(Some("y\\\\ z"), doc.get("Service", "B"));
+        let mut doc = Document::new();
+        doc.add_section("Service").set("A", "x\\\\").unwrap();
+        assert!(doc.add_section("Service").set("B", "y\\").is_err());
+    }
+}
@@ -0,0 +1,688 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed builders for service, socket and timer units.
+//!
+//! Each builder renders a `unitfile::Document`, quoting and escaping
+//! values as systemd expects, and `Connection::install_unit` writes it
+//! atomically to a unit directory and reloads systemd.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::units::{Restart, ServiceUnit, UnitLocation};
+//!
+//! let service = ServiceUnit::new()
+//!     .description("My app")
+//!     .after("network.target")
+//!     .exec_start(&["/usr/bin/app", "--port", "8080"])
+//!     .restart(Restart::OnFailure)
+//!     .restart_sec(Duration::from_secs(5))
+//!     .user("app")
+//!     .wanted_by("multi-user.target");
+//! let conn = Connection::new().unwrap();
+//! conn.install_unit("app.service", &service, UnitLocation::System).unwrap();
+//! ```
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+use unitfile::{escape, Document, UnitFileError};
+use {Connection, SystemdError, SystemdResult};
+
+/// When a service is restarted after its process exits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Restart {
+    /// Never restart
+    No,
+    /// Restart after a clean exit
+    OnSuccess,
+    /// Restart after an unclean exit, signal, timeout or watchdog
+    OnFailure,
+    /// Restart after a signal, timeout or watchdog
+    OnAbnormal,
+    /// Restart after a watchdog timeout
+    OnWatchdog,
+    /// Restart after an uncaught signal
+    OnAbort,
+    /// Always restart
+    Always
+}
+
+impl Restart {
+    /// The string form used in unit files.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Restart::No => "no",
+            Restart::OnSuccess => "on-success",
+            Restart::OnFailure => "on-failure",
+            Restart::OnAbnormal => "on-abnormal",
+            Restart::OnWatchdog => "on-watchdog",
+            Restart::OnAbort => "on-abort",
+            Restart::Always => "always"
+        }
+    }
+}
+
+/// How systemd decides that a service has started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceType {
+    /// Started as soon as the process is forked
+    Simple,
+    /// Started once the binary has been executed
+    Exec,
+    /// Started once the process forks and the parent exits
+    Forking,
+    /// Started once the process exits
+    Oneshot,
+    /// Started once the process takes its BusName
+    Dbus,
+    /// Started once the process sends READY=1
+    Notify,
+    /// Like Simple, but run once other jobs are dispatched
+    Idle
+}
+
+impl ServiceType {
+    /// The string form used in unit files.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ServiceType::Simple => "simple",
+            ServiceType::Exec => "exec",
+            ServiceType::Forking => "forking",
+            ServiceType::Oneshot => "oneshot",
+            ServiceType::Dbus => "dbus",
+            ServiceType::Notify => "notify",
+            ServiceType::Idle => "idle"
+        }
+    }
+}
+
+/// Where `install_unit` writes a unit file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnitLocation {
+    /// /etc/systemd/system, for the system manager
+    System,
+    /// The user's ~/.config/systemd/user, for the user manager
+    User,
+    /// Any other directory
+    Directory(PathBuf)
+}
+
+impl UnitLocation {
+    /// The directory unit files are written to.
+    pub fn directory(&self) -> SystemdResult<PathBuf> {
+        match self {
+            &UnitLocation::System => Ok(PathBuf::from("/etc/systemd/system")),
+            &UnitLocation::User => {
+                let config = match env::var("XDG_CONFIG_HOME") {
+                    Ok(dir) => PathBuf::from(dir),
+                    Err(_) => match env::var("HOME") {
+                        Ok(home) => Path::new(&home).join(".config"),
+                        Err(_) => return Err(SystemdError::UnspecifiedError(
+                            "neither XDG_CONFIG_HOME nor HOME is set"
+                                .to_string()))
+                    }
+                };
+                Ok(config.join("systemd").join("user"))
+            },
+            UnitLocation::Directory(dir) => Ok(dir.clone())
+        }
+    }
+}
+
+/// A unit that can be rendered to a unit file.
+pub trait UnitDefinition {
+    /// The unit name suffix for this type of unit, e.g. ".service".
+    fn suffix(&self) -> &'static str;
+
+    /// Render the unit file.
+    fn to_document(&self) -> Result<Document, UnitFileError>;
+}
+
+/// The directives of one unit, as (section, key, value).
+type Entries = Vec<(&'static str, &'static str, String)>;
+
+/// Render `entries` with the sections in `order`.
+fn render(entries: &Entries, order: &[&str]) -> Result<Document, UnitFileError> {
+    let mut doc = Document::new();
+    for name in order.iter() {
+        if !entries.iter().any(|e| e.0 == *name) {
+            continue
+        }
+        let section = doc.add_section(name);
+        for &(_, key, ref value) in entries.iter().filter(|e| e.0 == *name) {
+            section.add(key, value)?;
+        }
+    }
+    Ok(doc)
+}
+
+/// Escape `%`, which introduces a specifier in most values.
+fn literal(s: &str) -> String {
+    s.replace("%", "%%")
+}
+
+fn boolean(b: bool) -> String {
+    (if b { "yes" } else { "no" }).to_string()
+}
+
+/// Format `d` as a systemd time span, e.g. "90s" or "250ms".
+fn timespan(d: Duration) -> String {
+    let nanos = d.subsec_nanos();
+    if nanos == 0 {
+        format!("{}s", d.as_secs())
+    } else if nanos % 1000000 == 0 {
+        format!("{}ms", d.as_secs() * 1000 + (nanos / 1000000) as u64)
+    } else {
+        format!("{}us", d.as_secs() * 1000000 + (nanos / 1000) as u64)
+    }
+}
+
+/// Format a command line, quoting each argument so that it is passed
+/// to the process unchanged.
+fn command(args: &[&str]) -> String {
+    let quoted: Vec<String> = args.iter().map(|arg| {
+        let arg = literal(arg).replace("$", "$$");
+        if arg.is_empty() ||
+            arg.chars().any(|c| c.is_whitespace() || c == '"' ||
+                            c == '\'' || c == '\\' || c == ';') {
+            format!("\"{}\"", escape(&arg))
+        } else {
+            arg
+        }
+    }).collect();
+    quoted.join(" ")
+}
+
+/// Define the [Unit] and [Install] directives, shared by all builders.
+macro_rules! unit_directives {
+    ($t:ident) => (
+        impl $t {
+            /// Set the human readable description, Description=.
+            pub fn description(mut self, s: &str) -> $t {
+                self.entries.push(("Unit", "Description", literal(s)));
+                self
+            }
+
+            /// Add a documentation URI, Documentation=.
+            pub fn documentation(mut self, uri: &str) -> $t {
+                self.entries.push(("Unit", "Documentation", literal(uri)));
+                self
+            }
+
+            /// Order after `unit`, After=.
+            pub fn after(mut self, unit: &str) -> $t {
+                self.entries.push(("Unit", "After", unit.to_string()));
+                self
+            }
+
+            /// Order before `unit`, Before=.
+            pub fn before(mut self, unit: &str) -> $t {
+                self.entries.push(("Unit", "Before", unit.to_string()));
+                self
+            }
+
+            /// Require `unit`, Requires=.
+            pub fn requires(mut self, unit: &str) -> $t {
+                self.entries.push(("Unit", "Requires", unit.to_string()));
+                self
+            }
+
+            /// Want `unit`, Wants=.
+            pub fn wants(mut self, unit: &str) -> $t {
+                self.entries.push(("Unit", "Wants", unit.to_string()));
+                self
+            }
+
+            /// Conflict with `unit`, Conflicts=.
+            pub fn conflicts(mut self, unit: &str) -> $t {
+                self.entries.push(("Unit", "Conflicts", unit.to_string()));
+                self
+            }
+
+            /// Be wanted by `unit` when enabled, WantedBy=.
+            pub fn wanted_by(mut self, unit: &str) -> $t {
+                self.entries.push(("Install", "WantedBy", unit.to_string()));
+                self
+            }
+
+            /// Be required by `unit` when enabled, RequiredBy=.
+            pub fn required_by(mut self, unit: &str) -> $t {
+                self.entries.push(("Install", "RequiredBy", unit.to_string()));
+                self
+            }
+
+            /// Add an alias created when enabled, Alias=.
+            pub fn alias(mut self, name: &str) -> $t {
+                self.entries.push(("Install", "Alias", name.to_string()));
+                self
+            }
+        })
+}
+
+/// A .service unit.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceUnit {
+    entries: Entries
+}
+
+unit_directives!(ServiceUnit);
+
+impl ServiceUnit {
+    /// A service with no directives.
+    pub fn new() -> ServiceUnit {
+        ServiceUnit::default()
+    }
+
+    /// Set the start up type, Type=.
+    pub fn service_type(mut self, t: ServiceType) -> ServiceUnit {
+        self.entries.push(("Service", "Type", t.as_str().to_string()));
+        self
+    }
+
+    /// Set the command to run, ExecStart=.  Each argument is passed
+    /// as given, without variable or specifier expansion.
+    pub fn exec_start(mut self, args: &[&str]) -> ServiceUnit {
+        self.entries.push(("Service", "ExecStart", command(args)));
+        self
+    }
+
+    /// Add a command to run before ExecStart, ExecStartPre=.
+    pub fn exec_start_pre(mut self, args: &[&str]) -> ServiceUnit {
+        self.entries.push(("Service", "ExecStartPre", command(args)));
+        self
+    }
+
+    /// Add a command to run after ExecStart, ExecStartPost=.
+    pub fn exec_start_post(mut self, args: &[&str]) -> ServiceUnit {
+        self.entries.push(("Service", "ExecStartPost", command(args)));
+        self
+    }
+
+    /// Add a command that reloads the service, ExecReload=.
+    pub fn exec_reload(mut self, args: &[&str]) -> ServiceUnit {
+        self.entries.push(("Service", "ExecReload", command(args)));
+        self
+    }
+
+    /// Add a command that stops the service, ExecStop=.
+    pub fn exec_stop(mut self, args: &[&str]) -> ServiceUnit {
+        self.entries.push(("Service", "ExecStop", command(args)));
+        self
+    }
+
+    /// Set when the service is restarted, Restart=.
+    pub fn restart(mut self, restart: Restart) -> ServiceUnit {
+        self.entries.push(("Service", "Restart", restart.as_str().to_string()));
+        self
+    }
+
+    /// Set the delay before a restart, RestartSec=.
+    pub fn restart_sec(mut self, d: Duration) -> ServiceUnit {
+        self.entries.push(("Service", "RestartSec", timespan(d)));
+        self
+    }
+
+    /// Set the start up timeout, TimeoutStartSec=.
+    pub fn timeout_start_sec(mut self, d: Duration) -> ServiceUnit {
+        self.entries.push(("Service", "TimeoutStartSec", timespan(d)));
+        self
+    }
+
+    /// Set the stop timeout, TimeoutStopSec=.
+    pub fn timeout_stop_sec(mut self, d: Duration) -> ServiceUnit {
+        self.entries.push(("Service", "TimeoutStopSec", timespan(d)));
+        self
+    }
+
+    /// Keep the service active after its process exits, RemainAfterExit=.
+    pub fn remain_after_exit(mut self, b: bool) -> ServiceUnit {
+        self.entries.push(("Service", "RemainAfterExit", boolean(b)));
+        self
+    }
+
+    /// Run as `user`, User=.
+    pub fn user(mut self, user: &str) -> ServiceUnit {
+        self.entries.push(("Service", "User", literal(user)));
+        self
+    }
+
+    /// Run as `group`, Group=.
+    pub fn group(mut self, group: &str) -> ServiceUnit {
+        self.entries.push(("Service", "Group", literal(group)));
+        self
+    }
+
+    /// Run in `dir`, WorkingDirectory=.
+    pub fn working_directory(mut self, dir: &str) -> ServiceUnit {
+        self.entries.push(("Service", "WorkingDirectory", literal(dir)));
+        self
+    }
+
+    /// Set an environment variable, Environment=.
+    pub fn environment(mut self, name: &str, value: &str) -> ServiceUnit {
+        let assignment = format!("{}={}", name, value);
+        self.entries.push(("Service", "Environment",
+                           format!("\"{}\"", escape(&literal(&assignment)))));
+        self
+    }
+
+    /// Read environment variables from `path`, EnvironmentFile=.
+    pub fn environment_file(mut self, path: &str) -> ServiceUnit {
+        self.entries.push(("Service", "EnvironmentFile", literal(path)));
+        self
+    }
+
+    /// Limit the number of open files, LimitNOFILE=.
+    pub fn limit_nofile(mut self, n: u64) -> ServiceUnit {
+        self.entries.push(("Service", "LimitNOFILE", n.to_string()));
+        self
+    }
+}
+
+impl UnitDefinition for ServiceUnit {
+    fn suffix(&self) -> &'static str {
+        ".service"
+    }
+
+    fn to_document(&self) -> Result<Document, UnitFileError> {
+        render(&self.entries, &["Unit", "Service", "Install"])
+    }
+}
+
+/// A .timer unit.
+#[derive(Clone, Debug, Default)]
+pub struct TimerUnit {
+    entries: Entries
+}
+
+unit_directives!(TimerUnit);
+
+impl TimerUnit {
+    /// A timer with no directives.
+    pub fn new() -> TimerUnit {
+        TimerUnit::default()
+    }
+
+    /// Elapse at calendar times, e.g. "daily", OnCalendar=.
+    pub fn on_calendar(mut self, spec: &str) -> TimerUnit {
+        self.entries.push(("Timer", "OnCalendar", literal(spec)));
+        self
+    }
+
+    /// Elapse `d` after boot, OnBootSec=.
+    pub fn on_boot_sec(mut self, d: Duration) -> TimerUnit {
+        self.entries.push(("Timer", "OnBootSec", timespan(d)));
+        self
+    }
+
+    /// Elapse `d` after the timer is activated, OnActiveSec=.
+    pub fn on_active_sec(mut self, d: Duration) -> TimerUnit {
+        self.entries.push(("Timer", "OnActiveSec", timespan(d)));
+        self
+    }
+
+    /// Elapse `d` after the timed unit was last activated,
+    /// OnUnitActiveSec=.
+    pub fn on_unit_active_sec(mut self, d: Duration) -> TimerUnit {
+        self.entries.push(("Timer", "OnUnitActiveSec", timespan(d)));
+        self
+    }
+
+    /// Set how accurately the timer elapses, AccuracySec=.
+    pub fn accuracy_sec(mut self, d: Duration) -> TimerUnit {
+        self.entries.push(("Timer", "AccuracySec", timespan(d)));
+        self
+    }
+
+    /// Delay elapsing by a random time up to `d`, RandomizedDelaySec=.
+    pub fn randomized_delay_sec(mut self, d: Duration) -> TimerUnit {
+        self.entries.push(("Timer", "RandomizedDelaySec", timespan(d)));
+        self
+    }
+
+    /// Catch up on calendar times missed while the system was off,
+    /// Persistent=.
+    pub fn persistent(mut self, b: bool) -> TimerUnit {
+        self.entries.push(("Timer", "Persistent", boolean(b)));
+        self
+    }
+
+    /// Activate `unit` rather than the service of the same name, Unit=.
+    pub fn unit(mut self, unit: &str) -> TimerUnit {
+        self.entries.push(("Timer", "Unit", unit.to_string()));
+        self
+    }
+}
+
+impl UnitDefinition for TimerUnit {
+    fn suffix(&self) -> &'static str {
+        ".timer"
+    }
+
+    fn to_document(&self) -> Result<Document, UnitFileError> {
+        render(&self.entries, &["Unit", "Timer", "Install"])
+    }
+}
+
+/// A .socket unit.
+#[derive(Clone, Debug, Default)]
+pub struct SocketUnit {
+    entries: Entries
+}
+
+unit_directives!(SocketUnit);
+
+impl SocketUnit {
+    /// A socket with no directives.
+    pub fn new() -> SocketUnit {
+        SocketUnit::default()
+    }
+
+    /// Listen on a stream socket, e.g. "8080" or "/run/app.sock",
+    /// ListenStream=.
+    pub fn listen_stream(mut self, address: &str) -> SocketUnit {
+        self.entries.push(("Socket", "ListenStream", literal(address)));
+        self
+    }
+
+    /// Listen on a datagram socket, ListenDatagram=.
+    pub fn listen_datagram(mut self, address: &str) -> SocketUnit {
+        self.entries.push(("Socket", "ListenDatagram", literal(address)));
+        self
+    }
+
+    /// Listen on a FIFO, ListenFIFO=.
+    pub fn listen_fifo(mut self, path: &str) -> SocketUnit {
+        self.entries.push(("Socket", "ListenFIFO", literal(path)));
+        self
+    }
+
+    /// Start a service instance per connection, Accept=.
+    pub fn accept(mut self, b: bool) -> SocketUnit {
+        self.entries.push(("Socket", "Accept", boolean(b)));
+        self
+    }
+
+    /// Set the owner of a file system socket, SocketUser=.
+    pub fn socket_user(mut self, user: &str) -> SocketUnit {
+        self.entries.push(("Socket", "SocketUser", literal(user)));
+        self
+    }
+
+    /// Set the access mode of a file system socket, SocketMode=.
+    pub fn socket_mode(mut self, mode: u32) -> SocketUnit {
+        self.entries.push(("Socket", "SocketMode", format!("{:04o}", mode)));
+        self
+    }
+
+    /// Activate `unit` rather than the service of the same name,
+    /// Service=.
+    pub fn service(mut self, unit: &str) -> SocketUnit {
+        self.entries.push(("Socket", "Service", unit.to_string()));
+        self
+    }
+}
+
+impl UnitDefinition for SocketUnit {
+    fn suffix(&self) -> &'static str {
+        ".socket"
+    }
+
+    fn to_document(&self) -> Result<Document, UnitFileError> {
+        render(&self.entries, &["Unit", "Socket", "Install"])
+    }
+}
+
+/// Write `contents` to `dir/name`, replacing any existing file
+/// atomically: readers see either the old or the new file.
+pub fn write_atomic(dir: &Path, name: &str, contents: &str)
+                    -> SystemdResult<PathBuf> {
+    write_via_tmp(dir, name, contents, |tmp, path| fs::rename(tmp, path))
+}
+
+/// Write `contents` to `dir/name`, failing if it exists.  The file
+/// appears complete, and an existing file is never replaced, even if
+/// another process creates it at the same time.
+pub fn write_new(dir: &Path, name: &str, contents: &str)
+                 -> SystemdResult<PathBuf> {
+    write_via_tmp(dir, name, contents, |tmp, path| {
+        fs::hard_link(tmp, path)?;
+        fs::remove_file(tmp)
+    })
+}
+
+/// Write `contents` to a temporary file in `dir`, and `install` it as
+/// `dir/name`.
+fn write_via_tmp<F>(dir: &Path, name: &str, contents: &str, install: F)
+                    -> SystemdResult<PathBuf>
+    where F: FnOnce(&Path, &Path) -> io::Result<()>
+{
+    let path = dir.join(name);
+    let io_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::AlreadyExists => SystemdError::InvalidArg(
+            format!("{} already exists", path.display())),
+        _ => SystemdError::UnspecifiedError(format!("writing {}: {}", name, e))
+    };
+    fs::create_dir_all(dir).map_err(&io_error)?;
+    let tmp = dir.join(format!(".{}.{}.tmp", name, process::id()));
+    let written = File::create(&tmp).and_then(|mut f| {
+        f.write_all(contents.as_bytes())?;
+        f.sync_all()
+    }).and_then(|_| install(&tmp, &path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(io_error(e))
+    }
+    Ok(path)
+}
+
+impl Connection {
+    /// Write unit `name` to `location` and reload systemd, returning
+    /// the path of the unit file.  Use a session bus connection for
+    /// `UnitLocation::User`.
+    pub fn install_unit<U: UnitDefinition>(&self, name: &str, unit: &U,
+                                           location: UnitLocation)
+                                           -> SystemdResult<PathBuf> {
+        if !name.ends_with(unit.suffix()) || name.len() == unit.suffix().len() ||
+            name.contains('/') {
+            return Err(SystemdError::InvalidArg(
+                format!("{} is not a valid {} unit name", name, unit.suffix())))
+        }
+        let doc = unit.to_document()?;
+        let dir = location.directory()?;
+        let path = write_atomic(&dir, name, &doc.to_string())?;
+        self.reload()?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::time::Duration;
+    use mock::MockSystemd;
+    use Connection;
+
+    #[test]
+    fn service_unit() {
+        let service = ServiceUnit::new()
+            .description("App at 100%")
+            .after("network.target")
+            .service_type(ServiceType::Notify)
+            .exec_start(&["/usr/bin/app", "--name", "my app", "$HOME"])
+            .restart(Restart::OnFailure)
+            .restart_sec(Duration::from_millis(1500))
+            .environment("GREETING", "hello \"world\"")
+            .user("app")
+            .wanted_by("multi-user.target");
+        assert_eq!("[Unit]\n\
+                    Description=App at 100%%\n\
+                    After=network.target\n\
+                    \n\
+                    [Service]\n\
+                    Type=notify\n\
+                    ExecStart=/usr/bin/app --name \"my app\" $$HOME\n\
+                    Restart=on-failure\n\
+                    RestartSec=1500ms\n\
+                    Environment=\"GREETING=hello \\\"world\\\"\"\n\
+                    User=app\n\
+                    \n\
+                    [Install]\n\
+                    WantedBy=multi-user.target\n",
+                   service.to_document().unwrap().to_string());
+    }
+
+    #[test]
+    fn timer_and_socket_units() {
+        let timer = TimerUnit::new()
+            .on_calendar("daily")
+            .persistent(true)
+            .wanted_by("timers.target");
+        assert_eq!("[Timer]\nOnCalendar=daily\nPersistent=yes\n\n\
+                    [Install]\nWantedBy=timers.target\n",
+                   timer.to_document().unwrap().to_string());
+        let socket = SocketUnit::new().listen_stream("8080").socket_mode(0o660);
+        assert_eq!("[Socket]\nListenStream=8080\nSocketMode=0660\n",
+                   socket.to_document().unwrap().to_string());
+    }
+
+    #[test]
+    fn install_unit() {
+        let dir = env::temp_dir().join(format!("systemd-dbus-units-{}",
+                                               ::std::process::id()));
+        let mock = MockSystemd::start().unwrap();
+        let conn = Connection::open(mock.bus()).unwrap();
+        let service = ServiceUnit::new().exec_start(&["/bin/true"]);
+        assert!(conn.install_unit("app.timer", &service,
+                                  UnitLocation::Directory(dir.clone())).is_err());
+        let path = conn.install_unit("app.service", &service,
+                                     UnitLocation::Directory(dir.clone()))
+            .unwrap();
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        assert_eq!("[Service]\nExecStart=/bin/true\n", text);
+        assert_eq!(vec!["Reload"], mock.calls().iter()
+                   .map(|c| &c.method[..]).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+// Copyright 2015 Hugo Duncan
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Watch a single unit for property changes.
+//!
+//! `Connection::watch_unit` returns a `UnitWatcher`, which turns the
+//! unit's `PropertiesChanged` signals into typed `UnitChange` events.
+//! Properties that systemd reports as invalidated, rather than
+//! sending their new value, are fetched before the event is returned.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use systemd_dbus::Connection;
+//! use systemd_dbus::watch::{ActiveState, UnitChange};
+//!
+//! let conn = Connection::new().unwrap();
+//! let mut watcher = conn.watch_unit("app.service").unwrap();
+//! while let Some(change) = watcher.next_change(Duration::from_secs(30)).unwrap() {
+//!     match change {
+//!         UnitChange::ActiveState{to: ActiveState::Active, ..} => break,
+//!         UnitChange::ActiveState{to: ActiveState::Failed, ..} =>
+//!             panic!("app.service failed"),
+//!         _ => ()
+//!     }
+//! }
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use dbus::arg::messageitem::MessageItem;
+
+use match_rule::MatchRule;
+use {property_dict, Connection, Signal, SystemdError, SystemdResult};
+
+static UNIT_INTERFACE : &str = "org.freedesktop.systemd1.Unit";
+static SERVICE_INTERFACE : &str = "org.freedesktop.systemd1.Service";
+
+/// The active state of a unit.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ActiveState {
+    /// The unit is active
+    Active,
+    /// The unit is active and reloading its configuration
+    Reloading,
+    /// The unit is inactive
+    Inactive,
+    /// The unit is inactive after a failure
+    Failed,
+    /// The unit is changing from inactive to active
+    Activating,
+    /// The unit is changing from active to inactive
+    Deactivating,
+    /// A state not known to this crate
+    Other(String)
+}
+
+impl<'a> From<&'a str> for ActiveState {
+    /// Parse the string form used by systemd.
+    fn from(s: &'a str) -> ActiveState {
+        match s {
+            "active" => ActiveState::Active,
+            "reloading" => ActiveState::Reloading,
+            "inactive" => ActiveState::Inactive,
+            "failed" => ActiveState::Failed,
+            "activating" => ActiveState::Activating,
+            "deactivating" => ActiveState::Deactivating,
+            other => ActiveState::Other(other.to_string())
+        }
+    }
+}
+
+impl FromStr for ActiveState {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<ActiveState, Infallible> {
+        Ok(ActiveState::from(s))
+    }
+}
+
+/// A change to one property of a watched unit.  `from` is None when
+/// the previous value was not known.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnitChange {
+    /// ActiveState changed
+    ActiveState { from: Option<ActiveState>, to: ActiveState },
+    /// SubState changed, e.g. from "start" to "running"
+    SubState { from: Option<String>, to: String },
+    /// LoadState changed
+    LoadState { from: Option<String>, to: String },
+    /// The main process of a service changed.  0 means no process.
+    MainPID { from: Option<u32>, to: u32 },
+    /// Any other property changed
+    Other { interface: String, name: String, value: MessageItem }
+}
+
+/// Typed events for the property changes of one unit.
+///
+/// Reads signals from the connection's signal bus; signals that do not
+/// concern the watched unit are held for `Connection::next_signal`.
+/// The match rule is removed when the watcher is dropped, and
+/// the connection unsubscribed unless it was subscribed already.
+pub struct UnitWatcher<'c> {
+    conn: &'c Connection,
+    name: String,
+    path: String,
+    rule: MatchRule,
+    subscribed: bool,
+    values: HashMap<(String, String), MessageItem>,
+    pending: VecDeque<UnitChange>
+}
+
+impl Connection {
+    /// Watch unit `name` for property changes.  The unit is loaded if
+    /// it is not already.
+    pub fn watch_unit(&self, name: &str) -> SystemdResult<UnitWatcher<'_>> {
+        let path = self.unit(name)?.path().to_string();
+        let rule = MatchRule::signal()
+            .interface("org.freedesktop.DBus.Properties")
+            .member("PropertiesChanged")
+            .path(&path);
+        self.add_match_rule(&rule)?;
+        let subscribed = self.subscribed.get();
+        if !subscribed {
+            if let Err(e) = self.subscribe() {
+                let _ = self.remove_match_rule(&rule);
+                return Err(e)
+            }
+        }
+        let mut watcher = UnitWatcher{
+            conn: self,
+            name: name.to_string(),
+            path,
+            rule,
+            subscribed,
+            values: HashMap::new(),
+            pending: VecDeque::new()
+        };
+        watcher.refresh()?;
+        Ok(watcher)
+    }
+}
+
+impl<'c> UnitWatcher<'c> {
+    /// The name of the watched unit.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The object path of the watched unit.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The last known active state of the unit.
+    pub fn active_state(&self) -> Option<ActiveState> {
+        self.str_value(UNIT_INTERFACE, "ActiveState")
+            .map(|s| ActiveState::from(s.as_str()))
+    }
+
+    /// The last known sub state of the unit.
+    pub fn sub_state(&self) -> Option<String> {
+        self.str_value(UNIT_INTERFACE, "SubState")
+    }
+
+    /// The last known main PID of the unit, for services.
+    pub fn main_pid(&self) -> Option<u32> {
+        self.values.get(&(SERVICE_INTERFACE.to_string(), "MainPID".to_string()))
+            .and_then(as_u32)
+    }
+
+    /// Wait up to `timeout` for the next change to the unit.
+    pub fn next_change(&mut self, timeout: Duration)
+                       -> SystemdResult<Option<UnitChange>> {
+        let start = Instant::now();
+        while self.pending.is_empty() {
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(None)
+            }
+            let conn = self.conn;
+            let rule = &self.rule;
+            match conn.take_signal(timeout - elapsed, |s| rule.matches(s))? {
+                Some(signal) => self.handle(signal)?,
+                None => return Ok(None)
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    /// Wait up to `timeout` for the unit to become active.  Fails with
+    /// `UnitFailed` if the unit fails first, and `Timeout` if it does
+    /// not become active in time.
+    pub fn wait_active(&mut self, timeout: Duration) -> SystemdResult<()> {
+        let start = Instant::now();
+        loop {
+            match self.active_state() {
+                Some(ActiveState::Active) => return Ok(()),
+                Some(ActiveState::Failed) =>
+                    return Err(SystemdError::UnitFailed(self.name.clone())),
+                _ => ()
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout ||
+                self.next_change(timeout - elapsed)?.is_none() {
+                return Err(SystemdError::Timeout)
+            }
+        }
+    }
+
+    /// Fetch the current value of the watched properties.
+    fn refresh(&mut self) -> SystemdResult<()> {
+        let mut interfaces = vec![UNIT_INTERFACE];
+        if self.name.ends_with(".service") {
+            interfaces.push(SERVICE_INTERFACE);
+        }
+        for interface in interfaces.into_iter() {
+            let properties = self.conn.get_all_properties(&self.path,
+                                                          interface)?;
+            for (name, value) in properties.into_iter() {
+                self.values.insert((interface.to_string(), name), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue the changes reported by a PropertiesChanged signal.
+    fn handle(&mut self, signal: Signal) -> SystemdResult<()> {
+        if !self.rule.matches(&signal) {
+            return Ok(())
+        }
+        let mut items = signal.items.into_iter();
+        let (interface, changed, invalidated) =
+            match (items.next(), items.next(), items.next()) {
+                (Some(MessageItem::Str(i)), Some(c),
+                 Some(MessageItem::Array(inv))) => (i, c, inv.into_vec()),
+                _ => return Ok(())
+            };
+        let mut properties = property_dict(changed)?;
+        for item in invalidated.into_iter() {
+            if let MessageItem::Str(name) = item {
+                let value = self.conn.get_property(&self.path, &interface,
+                                                   &name)?;
+                properties.push((name, value));
+            }
+        }
+        for (name, value) in properties.into_iter() {
+            let key = (interface.clone(), name);
+            let previous = self.values.get(&key).cloned();
+            if previous.as_ref() == Some(&value) {
+                continue
+            }
+            if let Some(change) = change(&key.0, &key.1, previous.as_ref(),
+                                         &value) {
+                self.pending.push_back(change);
+            }
+            self.values.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn str_value(&self, interface: &str, name: &str) -> Option<String> {
+        self.values.get(&(interface.to_string(), name.to_string()))
+            .and_then(as_str)
+    }
+}
+
+impl<'c> Drop for UnitWatcher<'c> {
+    fn drop(&mut self) {
+        let _ = self.conn.remove_match_rule(&self.rule);
+        if !self.subscribed {
+            let _ = self.conn.unsubscribe();
+        }
+    }
+}
+
+fn as_str(item: &MessageItem) -> Option<String> {
+    match item {
+        MessageItem::Str(s) => Some(s.clone()),
+        _ => None
+    }
+}
+
+fn as_u32(item: &MessageItem) -> Option<u32> {
+    match item {
+        &MessageItem::UInt32(n) => Some(n),
+        _ => None
+    }
+}
+
+/// Return the typed change of property `name` from `previous` to `value`.
+fn change(interface: &str, name: &str, previous: Option<&MessageItem>,
+          value: &MessageItem) -> Option<UnitChange> {
+    let typed = match (interface, name) {
+        (i, "ActiveState") if i == UNIT_INTERFACE => as_str(value).map(|to| UnitChange::ActiveState{
+            from: previous.and_then(as_str).map(|s| ActiveState::from(s.as_str())),
+            to: ActiveState::from(to.as_str())
+        }),
+        (i, "SubState") if i == UNIT_INTERFACE => as_str(value).map(|to| UnitChange::SubState{
+            from: previous.and_then(as_str),
+            to
+        }),
+        (i, "LoadState") if i == UNIT_INTERFACE => as_str(value).map(|to| UnitChange::LoadState{
+            from: previous.and_then(as_str),
+            to
+        }),
+        (i, "MainPID") if i == SERVICE_INTERFACE => as_u32(value).map(|to| UnitChange::MainPID{
+            from: previous.and_then(as_u32),
+            to
+        }),
+        _ => None
+    };
+    typed.or_else(|| Some(UnitChange::Other{
+        interface: interface.to_string(),
+        name: name.to_string(),
+        value: value.clone()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::change;
+    use dbus::arg::messageitem::MessageItem;
+
+    #[test]
+    fn active_state_change() {
+        let from = MessageItem::Str("activating".to_string());
+        let to = MessageItem::Str("active".to_string());
+        assert_eq!(Some(UnitChange::ActiveState{
+            from: Some(ActiveState::Activating),
+            to: ActiveState::Active
+        }), change("org.freedesktop.systemd1.Unit", "ActiveState",
+                   Some(&from), &to));
+    }
+
+    #[test]
+    fn main_pid_change() {
+        assert_eq!(Some(UnitChange::MainPID{ from: None, to: 42 }),
+                   change("org.freedesktop.systemd1.Service", "MainPID",
+                          None, &MessageItem::UInt32(42)));
+    }
+
+    #[test]
+    fn main_pid_of_other_interface() {
+        let value = MessageItem::UInt32(42);
+        assert_eq!(Some(UnitChange::Other{
+            interface: "org.freedesktop.systemd1.Socket".to_string(),
+            name: "MainPID".to_string(),
+            value: value.clone()
+        }), change("org.freedesktop.systemd1.Socket", "MainPID", None, &value));
+    }
+
+    #[test]
+    fn watch_unit_test() {
+        use std::time::Duration;
+        use mock::MockSystemd;
+        use Connection;
+
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("app.service", "App", "activating", "start");
+        let conn = Connection::open(mock.bus()).unwrap();
+        let mut watcher = conn.watch_unit("app.service").unwrap();
+        assert_eq!(Some(ActiveState::Activating), watcher.active_state());
+
+        mock.emit_active_state("app.service", "active", "running");
+        assert_eq!(Some(UnitChange::ActiveState{
+            from: Some(ActiveState::Activating),
+            to: ActiveState::Active
+        }), watcher.next_change(Duration::from_secs(5)).unwrap());
+        assert_eq!(Some(UnitChange::SubState{
+            from: Some("start".to_string()),
+            to: "running".to_string()
+        }), watcher.next_change(Duration::from_secs(5)).unwrap());
+        watcher.wait_active(Duration::from_secs(5)).unwrap();
+
+        mock.emit_active_state("app.service", "failed", "failed");
+        let mut watcher = conn.watch_unit("app.service").unwrap();
+        match watcher.wait_active(Duration::from_secs(5)) {
+            Err(SystemdError::UnitFailed(name)) => assert_eq!("app.service", name),
+            other => panic!("expected UnitFailed, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn watcher_holds_other_signals() {
+        use std::time::Duration;
+        use mock::MockSystemd;
+        use Connection;
+
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("app.service", "App", "activating", "start");
+        let conn = Connection::open(mock.bus()).unwrap();
+        conn.systemd_signals().unwrap();
+        let mut watcher = conn.watch_unit("app.service").unwrap();
+        mock.emit_unit_new("other.service");
+        mock.emit_active_state("app.service", "active", "running");
+        assert!(watcher.next_change(Duration::from_secs(5)).unwrap().is_some());
+        let signal = conn.next_signal(Duration::from_secs(5)).unwrap().unwrap();
+        assert_eq!(Some("UnitNew".to_string()), signal.member);
+    }
+
+    #[test]
+    fn watcher_restores_subscription() {
+        use std::time::Duration;
+        use mock::MockSystemd;
+        use Connection;
+
+        let mock = MockSystemd::start().unwrap();
+        mock.add_unit("app.service", "App", "active", "running");
+        let mut conn = Connection::open(mock.bus()).unwrap();
+        drop(conn.watch_unit("app.service").unwrap());
+        assert!(!conn.subscribed.get());
+        assert!(conn.rules.borrow().is_empty());
+
+        conn.subscribe().unwrap();
+        drop(conn.watch_unit("app.service").unwrap());
+        assert!(conn.subscribed.get());
+        conn.unsubscribe().unwrap();
+
+        // The match rule is removed when subscribing fails
+        mock.withhold_replies("Subscribe");
+        conn.set_timeout(Duration::from_millis(100));
+        assert!(conn.watch_unit("app.service").is_err());
+        assert!(conn.rules.borrow().is_empty());
+    }
+}